@@ -0,0 +1,57 @@
+// explicit returns are the style of this crate.
+#![allow(clippy::needless_return)]
+
+use std::rc::Rc;
+use std::sync::Arc;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use n1::graph::concurrent::{ConcurrentComputeGraph, ConcurrentGraphNode};
+use n1::graph::easy_functions;
+use n1::graph::io_graph::IoConcurrentComputeGraph;
+use n1::graph::serial::{ComputeGraph, GraphNode};
+
+// the fan out of `main.rs::fan_out_benchmark`: a source read by 32 branches, joined by the sink.
+const BRANCHES: usize = 32;
+
+fn datum() -> Vec<f64> {
+    return (0..100_000).map(|x| x as f64).collect();
+}
+
+fn concurrent_source() -> Arc<ConcurrentGraphNode<f64>> {
+    let sink = Arc::new(ConcurrentGraphNode::new(easy_functions::square, String::from("sink"), vec![]));
+    let branches = (0..BRANCHES).map(|i| {
+        Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, format!("branch {}", i), vec![Arc::clone(&sink)]))
+    }).collect();
+    return Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("source"), branches));
+}
+
+fn serial_source() -> Rc<GraphNode<f64>> {
+    let sink = Rc::new(GraphNode::new(easy_functions::square, String::from("sink"), vec![]));
+    let branches = (0..BRANCHES).map(|i| {
+        Rc::new(GraphNode::new(easy_functions::add_one, format!("branch {}", i), vec![Rc::clone(&sink)]))
+    }).collect();
+    return Rc::new(GraphNode::new(easy_functions::add_one, String::from("source"), branches));
+}
+
+// the datum is copied outside of the timed part, every graph takes it by value.
+fn fan_out(c: &mut Criterion) {
+    let datum = datum();
+    let mut group = c.benchmark_group("fan out");
+
+    let serial = ComputeGraph::new(serial_source()).unwrap();
+    group.bench_function("serial", |b| b.iter_batched(|| datum.clone(), |datum| serial.apply(datum), BatchSize::LargeInput));
+
+    let concurrent = ConcurrentComputeGraph::new(concurrent_source()).unwrap();
+    group.bench_function("concurrent", |b| b.iter_batched(|| datum.clone(), |datum| concurrent.apply(datum), BatchSize::LargeInput));
+
+    let io = IoConcurrentComputeGraph::new(concurrent_source()).unwrap();
+    group.bench_function("io", |b| b.iter_batched(|| datum.clone(), |datum| io.apply(datum), BatchSize::LargeInput));
+
+    let mut pooled = IoConcurrentComputeGraph::new(concurrent_source()).unwrap();
+    pooled.set_workers(4);
+    group.bench_function("io, 4 workers", |b| b.iter_batched(|| datum.clone(), |datum| pooled.apply(datum), BatchSize::LargeInput));
+    group.finish();
+}
+
+criterion_group!(benches, fan_out);
+criterion_main!(benches);
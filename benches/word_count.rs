@@ -0,0 +1,47 @@
+// explicit returns are the style of this crate.
+#![allow(clippy::needless_return)]
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use n1::pipeline::map_reduce::MapReduce;
+use n1::pipeline::word_count::{word_count_emitter, word_count_reducer};
+
+// word count over `data/data.txt` repeated 100 times, so that every split has some work to do.
+fn text() -> Vec<u8> {
+    let text = std::fs::read("data/data.txt").unwrap();
+    let mut repeated = Vec::with_capacity(101 * text.len());
+    for _ in 0..100 {
+        repeated.extend_from_slice(&text);
+        repeated.push(b' ');
+    }
+    return repeated;
+}
+
+fn word_count(c: &mut Criterion) {
+    let text = text();
+    let mut group = c.benchmark_group("word count");
+
+    let single = MapReduce::new(word_count_emitter, word_count_reducer);
+    group.bench_function("1 split", |b| b.iter_batched(|| text.clone(), |text| single.apply(text), BatchSize::LargeInput));
+
+    let mut splits = MapReduce::new(word_count_emitter, word_count_reducer);
+    splits.set_splits(4, b' ');
+    splits.set_partitions(4);
+    group.bench_function("4 splits", |b| b.iter_batched(|| text.clone(), |text| splits.apply(text), BatchSize::LargeInput));
+
+    let mut combined = MapReduce::new(word_count_emitter, word_count_reducer);
+    combined.set_splits(4, b' ');
+    combined.set_partitions(4);
+    combined.set_combiner(word_count_reducer);
+    group.bench_function("4 splits, combined", |b| b.iter_batched(|| text.clone(), |text| combined.apply(text), BatchSize::LargeInput));
+
+    let mut spilled = MapReduce::new(word_count_emitter, word_count_reducer);
+    spilled.set_splits(4, b' ');
+    spilled.set_partitions(4);
+    spilled.set_spill_threshold(10_000);
+    group.bench_function("4 splits, spilled", |b| b.iter_batched(|| text.clone(), |text| spilled.apply(text), BatchSize::LargeInput));
+    group.finish();
+}
+
+criterion_group!(benches, word_count);
+criterion_main!(benches);
@@ -0,0 +1,45 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+// system allocator that keeps track of the large allocations, only installed for tests.
+// used to measure how many times a datum gets copied while running a graph.
+// allocations are counted per thread, so that the tests running alongside don't skew the counts:
+// `measure` only sees the allocations of the thread calling it.
+pub struct CountingAllocator;
+
+// allocations of at least this many bytes are counted.
+pub const LARGE: usize = 1024 * 1024;
+
+thread_local! {
+    // (allocations, bytes) made by this thread.
+    static ALLOCATED: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() >= LARGE {
+            // the thread local is gone while the thread exits, its allocations are not counted.
+            let _ = ALLOCATED.try_with(|allocated| {
+                let (allocations, bytes) = allocated.get();
+                allocated.set((allocations + 1, bytes + layout.size()));
+            });
+        }
+        return System.alloc(layout);
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// runs `f`, along with the number of large allocations it made on the calling thread and their size in bytes.
+// what `f` allocates on other threads (e.g. the segments of an IO graph) is not counted.
+pub fn measure<R>(f: impl FnOnce() -> R) -> (R, usize, usize) {
+    let (allocations_before, bytes_before) = ALLOCATED.with(Cell::get);
+    let result = f();
+    let (allocations_after, bytes_after) = ALLOCATED.with(Cell::get);
+    return (result, allocations_after - allocations_before, bytes_after - bytes_before);
+}
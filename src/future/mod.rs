@@ -1,11 +1,7 @@
-use std::task::Poll;
 use std::thread::{spawn, sleep};
 use std::time::Duration;
 use std::sync::{Arc, Mutex};
-use atomic_refcell::AtomicRefCell;
-use std::ops::{DerefMut, Deref};
-use serde::export::fmt::Debug;
-use std::borrow::BorrowMut;
+use std::fmt::Debug;
 
 
 pub struct Future<T> {
@@ -33,10 +29,9 @@ impl <T> PollableFuture<T> for Future<T> where T: Debug {
     fn busy_poll(&self) -> Option<T> {
 
         loop {
-            if let r = self.get() {
-                if r.is_some() {
-                    return r;
-                }
+            let r = self.get();
+            if r.is_some() {
+                return r;
             }
             sleep(Duration::from_millis(100));
         }
@@ -0,0 +1,234 @@
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::{Serialize, Deserialize};
+
+// *******************************
+// cross call result cache.
+// A cache is attached to a single node and remembers its last outputs, keyed by a hash of what the node reads.
+// Entries keep the input they were computed from, a hit compares it with what the node reads so that
+// colliding hashes never return the output of another input. Functions are expected to be pure.
+// The cache is shared by every thread calling `apply` on the graph, so that they all benefit from each other's hits.
+// *******************************
+pub type InputHasher<T> = fn(xs: &[T]) -> u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheScope {
+    // keyed by the input of the node, its ancestors are still computed.
+    Node,
+    // keyed by the datum fed to the graph, a hit skips every ancestor of the node.
+    Subgraph,
+}
+
+pub struct CacheConfig<T> {
+    pub capacity: usize,
+    pub scope: CacheScope,
+    pub hasher: InputHasher<T>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub len: usize,
+}
+
+type CachedOutput<T> = Option<Arc<Vec<T>>>;
+
+pub struct NodeCache<T> {
+    pub (in crate::graph) scope: CacheScope,
+    hasher: InputHasher<T>,
+    // compares the input of an entry with what the node reads.
+    eq: fn(&[T], &[T]) -> bool,
+    entries: Mutex<Lru<T>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl <T> NodeCache<T> where T: Clone {
+
+    pub fn new(config: CacheConfig<T>) -> NodeCache<T> where T: PartialEq {
+        return NodeCache {
+            scope: config.scope,
+            hasher: config.hasher,
+            eq: |xs, ys| xs == ys,
+            entries: Mutex::new(Lru::new(config.capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        };
+    }
+
+    // the lock is not held while computing, concurrent misses on the same key may compute twice.
+    // a subgraph that did not fire (see `routing`) is cached as None.
+    pub (in crate::graph) fn get_or_compute<F>(&self, xs: &[T], compute: F) -> CachedOutput<T> where F: FnOnce() -> CachedOutput<T> {
+        let hasher = self.hasher;
+        let key = hasher(xs);
+        if let Some(result) = self.entries.lock().unwrap().get(key, xs, self.eq) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return result;
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let result = compute();
+        self.entries.lock().unwrap().insert(key, xs.to_vec(), result.clone(), self.eq);
+        return result;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        return CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            len: self.entries.lock().unwrap().len(),
+        };
+    }
+}
+
+// an input and the output computed from it.
+struct Entry<T> {
+    input: Vec<T>,
+    output: CachedOutput<T>,
+    last_used: u64,
+}
+
+// bounded map evicting the least recently used entry.
+// inputs with the same hash share a bucket.
+struct Lru<T> {
+    capacity: usize,
+    tick: u64,
+    buckets: HashMap<u64, Vec<Entry<T>>>,
+    // last time an entry was used -> the hash of its input.
+    recency: BTreeMap<u64, u64>,
+}
+
+impl <T> Lru<T> {
+
+    fn new(capacity: usize) -> Lru<T> {
+        return Lru {
+            capacity,
+            tick: 0,
+            buckets: HashMap::new(),
+            recency: BTreeMap::new(),
+        };
+    }
+
+    fn len(&self) -> usize {
+        return self.recency.len();
+    }
+
+    fn get(&mut self, key: u64, input: &[T], eq: fn(&[T], &[T]) -> bool) -> Option<CachedOutput<T>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.buckets.get_mut(&key)?.iter_mut().find(|entry| eq(&entry.input, input))?;
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(tick, key);
+        entry.last_used = tick;
+        return Some(entry.output.clone());
+    }
+
+    fn insert(&mut self, key: u64, input: Vec<T>, output: CachedOutput<T>, eq: fn(&[T], &[T]) -> bool) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some(bucket) = self.buckets.get_mut(&key) {
+            if let Some(position) = bucket.iter().position(|entry| eq(&entry.input, &input)) {
+                let entry = bucket.remove(position);
+                self.recency.remove(&entry.last_used);
+            }
+        }
+        if self.len() >= self.capacity {
+            let (&oldest, &oldest_key) = self.recency.iter().next().unwrap();
+            self.recency.remove(&oldest);
+            let bucket = self.buckets.get_mut(&oldest_key).unwrap();
+            bucket.retain(|entry| entry.last_used != oldest);
+            if bucket.is_empty() {
+                self.buckets.remove(&oldest_key);
+            }
+        }
+        self.recency.insert(self.tick, key);
+        self.buckets.entry(key).or_default().push(Entry { input, output, last_used: self.tick });
+    }
+}
+
+// *******************************
+// hashers
+// *******************************
+// floats are not `Hash`, their bit pattern is used instead.
+pub fn hash_f64s(xs: &[f64]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for x in xs {
+        x.to_bits().hash(&mut hasher);
+    }
+    return hasher.finish();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::concurrent::{ConcurrentComputeGraph, ConcurrentGraphNode};
+    use crate::graph::easy_functions;
+
+    // every input collides.
+    fn constant_hash(_: &[f64]) -> u64 {
+        return 0;
+    }
+
+    fn cache(capacity: usize, hasher: InputHasher<f64>) -> NodeCache<f64> {
+        return NodeCache::new(CacheConfig { capacity, scope: CacheScope::Node, hasher });
+    }
+
+    fn output(xs: Vec<f64>) -> CachedOutput<f64> {
+        return Some(Arc::new(xs));
+    }
+
+    #[test]
+    fn hit_returns_the_cached_output() {
+        let cache = cache(4, hash_f64s);
+        assert_eq!(cache.get_or_compute(&[1.0], || output(vec![2.0])), output(vec![2.0]));
+        assert_eq!(cache.get_or_compute(&[1.0], || panic!("should hit")), output(vec![2.0]));
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.len), (1, 1, 1));
+    }
+
+    #[test]
+    fn colliding_inputs_are_told_apart() {
+        let cache = cache(4, constant_hash);
+        cache.get_or_compute(&[1.0], || output(vec![2.0]));
+        assert_eq!(cache.get_or_compute(&[5.0], || output(vec![6.0])), output(vec![6.0]));
+        assert_eq!(cache.get_or_compute(&[1.0], || panic!("should hit")), output(vec![2.0]));
+        assert_eq!(cache.get_or_compute(&[5.0], || panic!("should hit")), output(vec![6.0]));
+        assert_eq!(cache.stats().len, 2);
+    }
+
+    #[test]
+    fn least_recently_used_entry_is_evicted() {
+        for hasher in [hash_f64s, constant_hash] {
+            let cache = cache(2, hasher);
+            cache.get_or_compute(&[1.0], || output(vec![1.0]));
+            cache.get_or_compute(&[2.0], || output(vec![2.0]));
+            // 1.0 is now more recent than 2.0.
+            cache.get_or_compute(&[1.0], || panic!("should hit"));
+            cache.get_or_compute(&[3.0], || output(vec![3.0]));
+
+            assert_eq!(cache.stats().len, 2);
+            assert_eq!(cache.get_or_compute(&[1.0], || panic!("should hit")), output(vec![1.0]));
+            assert_eq!(cache.get_or_compute(&[2.0], || output(vec![-2.0])), output(vec![-2.0]));
+        }
+    }
+
+    #[test]
+    fn batches_go_through_node_caches() {
+        let sink = Arc::new(ConcurrentGraphNode::new(easy_functions::square, String::from("sink"), vec![]));
+        let source = Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("source"), vec![sink]));
+        let mut graph = ConcurrentComputeGraph::new(source).unwrap();
+        graph.enable_cache("sink", CacheConfig { capacity: 4, scope: CacheScope::Node, hasher: hash_f64s });
+
+        assert_eq!(graph.apply_batch(vec![vec![1.0], vec![2.0], vec![1.0]]), vec![vec![4.0], vec![9.0], vec![4.0]]);
+        assert_eq!(graph.apply(vec![2.0]), vec![9.0]);
+
+        let stats = &graph.cache_stats()["sink"];
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+    }
+}
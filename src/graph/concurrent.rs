@@ -5,28 +5,140 @@ use std::sync::Arc;
 use atomic_refcell;
 
 pub use crate::graph::GraphLikeFunc;
+use crate::graph::GraphError;
+use crate::graph::optimizer::{self, OptimizationReport, OptimizerNode};
+use crate::graph::identity::{self, IdentityNode};
+use crate::graph::introspection::GraphView;
+use crate::graph::routing::Route;
+use crate::graph::hybrid::NodeKind;
+use crate::graph::cache::{NodeCache, CacheConfig, CacheScope, CacheStats};
+use crate::graph::trace::{self, NodeRecord, NodeRecords, Trace};
+use crate::graph::metrics::MetricsRegistry;
+use std::time::Instant;
 
 // exposed graph structure
 pub struct ConcurrentComputeGraph<T> where T: Clone {
-    pub root: Arc<ConcurrentGraphNode<T>>,
+    // the user graph, copied by `then` and `subgraph`.
+    root: Arc<ConcurrentGraphNode<T>>,
     internal_root: Arc<ConcurrentInternalGraphNode<T>>,
+    caches: NodeCaches<T>,
+    metrics: Arc<MetricsRegistry>,
+}
+
+type NodeCaches<T> = HashMap<Uuid, NodeCache<T>>;
+
+// state of a single call to apply.
+struct Run<'a, T> where T: Clone {
+    graph: &'a ConcurrentComputeGraph<T>,
+    started: Instant,
+    // None for the nodes that did not fire.
+    memo: HashMap<Uuid, Option<Arc<Vec<T>>>>,
+    // only kept for traced runs.
+    records: Option<NodeRecords<T>>,
+}
+
+impl <'a, T> Run<'a, T> where T: Clone {
+    fn new(graph: &'a ConcurrentComputeGraph<T>, traced: bool) -> Run<'a, T> {
+        return Run {
+            graph,
+            started: Instant::now(),
+            memo: HashMap::new(),
+            records: if traced { Some(NodeRecords::new()) } else { None },
+        };
+    }
 }
 
 impl <T> ConcurrentComputeGraph<T> where T: Clone {
 
-    pub fn new(root: Arc<ConcurrentGraphNode<T>>) -> ConcurrentComputeGraph<T> {
+    // fails when the graph is not valid (see `GraphError`).
+    pub fn new(root: Arc<ConcurrentGraphNode<T>>) -> Result<ConcurrentComputeGraph<T>, GraphError> {
+        let internal_root = ConcurrentInternalGraphNode::from(ConcurrentTmpInternalGraphNode::to_internal_graph_node(Arc::clone(&root))?);
+        return Ok(ConcurrentComputeGraph::from_internal(root, internal_root));
+    }
+
+    // same as `new`, but drops the nodes the sink does not read and merges duplicated computations.
+    pub fn optimized(root: Arc<ConcurrentGraphNode<T>>) -> Result<(ConcurrentComputeGraph<T>, OptimizationReport), GraphError> {
+        let tmp_sink = ConcurrentTmpInternalGraphNode::to_internal_graph_node(Arc::clone(&root))?;
+        let report = tmp_sink.optimize(&root)?;
+        let graph = ConcurrentComputeGraph::from_internal(root, ConcurrentInternalGraphNode::from(tmp_sink));
+        return Ok((graph, report));
+    }
+
+    fn from_internal(root: Arc<ConcurrentGraphNode<T>>, internal_root: Arc<ConcurrentInternalGraphNode<T>>) -> ConcurrentComputeGraph<T> {
+        let nodes = ConcurrentInternalGraphNode::index_by_id(&internal_root).values()
+            .map(|node| (node.id, node.name.clone()))
+            .collect();
         return ConcurrentComputeGraph {
-            root: Arc::clone(&root),
-            internal_root: ConcurrentInternalGraphNode::from(ConcurrentTmpInternalGraphNode::to_internal_graph_node(Arc::clone(&root))),
-        }
+            root,
+            internal_root,
+            caches: HashMap::new(),
+            metrics: Arc::new(MetricsRegistry::new(nodes)),
+        };
+    }
+
+    // calls, errors and latencies of the graph and of every node, shared with the callers of `apply`.
+    pub fn metrics(&self) -> Arc<MetricsRegistry> {
+        return Arc::clone(&self.metrics);
+    }
+
+    // opt-in: remembers the outputs of the given node across calls to `apply`.
+    // panics if no node the sink reads from has this name.
+    // node caches are also used by `apply_batch`, subgraph caches are not: they are keyed by a whole datum.
+    pub fn enable_cache(&mut self, node_name: &str, config: CacheConfig<T>) where T: PartialEq {
+        let id_to_node = ConcurrentInternalGraphNode::index_by_id(&self.internal_root);
+        let node = id_to_node.values().find(|node| node.name == node_name)
+            .unwrap_or_else(|| panic!("no node named {} in the graph", node_name));
+        self.caches.insert(node.id, NodeCache::new(config));
+    }
+
+    // hits and misses of every cached node, by node name.
+    pub fn cache_stats(&self) -> HashMap<String, CacheStats> {
+        let id_to_node = ConcurrentInternalGraphNode::index_by_id(&self.internal_root);
+        return self.caches.iter()
+            .map(|(id, cache)| (id_to_node.get(id).unwrap().name.clone(), cache.stats()))
+            .collect();
     }
 
     pub fn apply(&self, datum: Vec<T>) -> Vec<T> {
-        return self.internal_root.apply(datum);
+        return self.metrics.graph.measure(|| self.internal_root.apply(datum, self));
+    }
+
+    // same as `apply`, along with the input, output and duration of every node.
+    // nodes skipped thanks to a subgraph cache are not recorded.
+    pub fn apply_traced(&self, datum: Vec<T>) -> Trace<T> {
+        return self.metrics.graph.measure(|| self.internal_root.apply_traced(datum, self));
     }
 
     pub fn apply_batch(&self, data: Vec<Vec<T>>) -> Vec<Vec<T>> {
-        return self.internal_root.apply_batch(data);
+        return self.metrics.graph.measure(|| self.internal_root.apply_batch(data, self));
+    }
+
+    // output of the given node, only its ancestors are computed (empty if the node does not fire).
+    // returns None if the sink does not read from a node with this name (names are unique within a graph).
+    pub fn evaluate(&self, node_name: &str, datum: Vec<T>) -> Option<Vec<T>> {
+        let id_to_node = ConcurrentInternalGraphNode::index_by_id(&self.internal_root);
+        let node = id_to_node.values().find(|node| node.name == node_name)?;
+        return Some(node.apply(datum, self));
+    }
+
+    // a graph where the sink of this graph feeds the source of `next`.
+    // node names are kept as they are, use `ConcurrentGraphNode::subgraph` to namespace them: fails when both graphs share a name.
+    // caches are not carried over.
+    pub fn then(&self, next: &ConcurrentComputeGraph<T>) -> Result<ConcurrentComputeGraph<T>, GraphError> {
+        let next_root = ConcurrentGraphNode::copy(&next.root, None, &[]);
+        return ConcurrentComputeGraph::new(ConcurrentGraphNode::copy(&self.root, None, &[next_root]));
+    }
+
+    // id of every node the sink reads from, by node name. ids are the same from one run to the next.
+    pub fn node_ids(&self) -> HashMap<String, Uuid> {
+        return ConcurrentInternalGraphNode::index_by_id(&self.internal_root).values()
+            .map(|node| (node.name.clone(), node.id))
+            .collect();
+    }
+
+    // read-only structure of the graph, as it is executed.
+    pub fn view(&self) -> GraphView {
+        return ConcurrentInternalGraphNode::view(&self.internal_root);
     }
 }
 
@@ -37,7 +149,13 @@ pub struct ConcurrentGraphNode<T> where T: Clone {
     pub f: GraphLikeFunc<T>,
     pub name: String,
     pub children: Vec<Arc<ConcurrentGraphNode<T>>>,
-    pub (in crate::graph) id: Uuid,
+    pub route: Route<T>,
+    // only read by the hybrid executor.
+    pub kind: NodeKind,
+    // when not set, the id is derived from the name, the operator and the parents of the node (see `identity`).
+    pub (in crate::graph) id: Option<Uuid>,
+    // what the node computes, nodes are only merged by the optimizer when they share it (see `with_operator`).
+    pub (in crate::graph) operator: Option<String>,
 }
 
 impl <T> ConcurrentGraphNode<T> where T: Clone {
@@ -47,17 +165,151 @@ impl <T> ConcurrentGraphNode<T> where T: Clone {
             f,
             name: m,
             children,
-            id: Uuid::new_v4(),
+            route: Route::All,
+            kind: NodeKind::Cpu,
+            id: None,
+            operator: None,
+        };
+    }
+    // a node waiting on I/O, run on the pool of the hybrid executor (see `HybridComputeGraph`).
+    pub fn io(f: GraphLikeFunc<T>, m: String, children: Vec<Arc<ConcurrentGraphNode<T>>>) -> Self {
+        return ConcurrentGraphNode {
+            f,
+            name: m,
+            children,
+            route: Route::All,
+            kind: NodeKind::Io,
+            id: None,
+            operator: None,
+        };
+    }
+    pub fn with_id(f: GraphLikeFunc<T>, m: String, children: Vec<Arc<ConcurrentGraphNode<T>>>, id: Uuid) -> Self {
+        return ConcurrentGraphNode {
+            f,
+            name: m,
+            children,
+            route: Route::All,
+            kind: NodeKind::Cpu,
+            id: Some(id),
+            operator: None,
+        };
+    }
+    // sends its output to a single child, the one at the index returned by `selector` (see `routing`).
+    pub fn switch(f: GraphLikeFunc<T>, m: String, children: Vec<Arc<ConcurrentGraphNode<T>>>, selector: fn(&[T]) -> usize) -> Self {
+        return ConcurrentGraphNode {
+            f,
+            name: m,
+            children,
+            route: Route::Switch(selector),
+            kind: NodeKind::Cpu,
+            id: None,
+            operator: None,
+        };
+    }
+    // sends its output to its children only when `predicate` holds, drops it otherwise (see `routing`).
+    pub fn filter(f: GraphLikeFunc<T>, m: String, children: Vec<Arc<ConcurrentGraphNode<T>>>, predicate: fn(&[T]) -> bool) -> Self {
+        return ConcurrentGraphNode {
+            f,
+            name: m,
+            children,
+            route: Route::Filter(predicate),
+            kind: NodeKind::Cpu,
+            id: None,
+            operator: None,
         };
     }
     pub fn empty(f: GraphLikeFunc<T>, m: String) -> Self {
         return ConcurrentGraphNode {
             f,
             name: m,
-            id: Uuid::new_v4(),
+            route: Route::All,
+            kind: NodeKind::Cpu,
+            id: None,
+            operator: None,
             children: vec![],
         };
     }
+
+    // names what the node computes (e.g. "add one"). Nodes given the same operator must apply the same function
+    // and route the same way: the optimizer merges them when they read the same parents.
+    // function addresses can't tell it, the same function may have several addresses and two functions the same one.
+    // the operator is also part of the id of the node (see `identity`).
+    pub fn with_operator(mut self, operator: &str) -> Self {
+        self.operator = Some(String::from(operator));
+        return self;
+    }
+
+    // embeds a copy of `graph` as a single node of another graph: the returned node reads the datum
+    // the subgraph would read, and the sink of the subgraph feeds `children`.
+    // names (and explicit ids) of the copied nodes are prefixed with `namespace/`, so that they don't collide.
+    pub fn subgraph(graph: &ConcurrentComputeGraph<T>, namespace: &str, children: Vec<Arc<ConcurrentGraphNode<T>>>) -> Arc<ConcurrentGraphNode<T>> {
+        return ConcurrentGraphNode::copy(&graph.root, Some(namespace), &children);
+    }
+
+    // copies the nodes reachable from the root, the sinks of the copy get `sink_children` as children.
+    fn copy(root: &Arc<ConcurrentGraphNode<T>>,
+            namespace: Option<&str>,
+            sink_children: &[Arc<ConcurrentGraphNode<T>>]) -> Arc<ConcurrentGraphNode<T>> {
+        return ConcurrentGraphNode::copy_memo(root, namespace, sink_children, &mut HashMap::new());
+    }
+
+    // a node read by several children is only copied once.
+    fn copy_memo(node: &Arc<ConcurrentGraphNode<T>>,
+                 namespace: Option<&str>,
+                 sink_children: &[Arc<ConcurrentGraphNode<T>>],
+                 copies: &mut HashMap<usize, Arc<ConcurrentGraphNode<T>>>) -> Arc<ConcurrentGraphNode<T>> {
+        if let Some(copy) = copies.get(&ConcurrentGraphNode::key(node)) {
+            return Arc::clone(copy);
+        }
+        let children = if node.children.is_empty() {
+            sink_children.to_vec()
+        } else {
+            node.children.iter().map(|child| ConcurrentGraphNode::copy_memo(child, namespace, sink_children, copies)).collect()
+        };
+        let (name, id) = match namespace {
+            Some(namespace) => identity::namespaced(namespace, &node.name, node.id),
+            None => (node.name.clone(), node.id),
+        };
+        let copy = Arc::new(ConcurrentGraphNode { f: node.f, name, children, route: node.route, kind: node.kind, id, operator: node.operator.clone() });
+        copies.insert(ConcurrentGraphNode::key(node), Arc::clone(&copy));
+        return copy;
+    }
+
+    // until ids are assigned, nodes are identified by their address.
+    pub (in crate::graph) fn key(node: &Arc<ConcurrentGraphNode<T>>) -> usize {
+        return Arc::as_ptr(node) as usize;
+    }
+
+    // id of every node reachable from the root, by address.
+    pub (in crate::graph) fn assign_ids(root: &Arc<ConcurrentGraphNode<T>>) -> Result<HashMap<usize, Uuid>, GraphError> {
+        let nodes = ConcurrentGraphNode::all_nodes(root).iter().map(|node| IdentityNode {
+            key: ConcurrentGraphNode::key(node),
+            name: node.name.clone(),
+            operator: node.operator.clone(),
+            explicit_id: node.id,
+            children: node.children.iter().map(ConcurrentGraphNode::key).collect(),
+        }).collect();
+        return identity::assign_ids(nodes);
+    }
+
+    // every node reachable from this one, in bfs order.
+    pub (in crate::graph) fn all_nodes(root: &Arc<ConcurrentGraphNode<T>>) -> Vec<Arc<ConcurrentGraphNode<T>>> {
+        let mut nodes = vec![];
+        let mut visited = HashSet::new();
+        let mut bfs_q = VecDeque::new();
+        bfs_q.push_back(Arc::clone(root));
+
+        while let Some(node) = bfs_q.pop_front() {
+            if !visited.insert(ConcurrentGraphNode::key(&node)) {
+                continue;
+            }
+            for child in node.children.iter() {
+                bfs_q.push_back(Arc::clone(child));
+            }
+            nodes.push(node);
+        }
+        return nodes;
+    }
 }
 
 // *******************************
@@ -75,64 +327,196 @@ type ConcurrentParentRefs<T> = Vec<Arc<ConcurrentInternalGraphNode<T>>>;
 pub struct ConcurrentInternalGraphNode<T> {
     pub (in crate::graph) f: GraphLikeFunc<T>,
     pub (in crate::graph) name: String,
+    pub (in crate::graph) route: Route<T>,
+    pub (in crate::graph) kind: NodeKind,
     pub (in crate::graph) parents: ConcurrentParentRefs<T>,
+    // for every parent, the index of the node among the children of that parent (what a switch selects).
+    pub (in crate::graph) branches: Vec<usize>,
     pub (in crate::graph) id: Uuid,
 }
 
 impl <T> ConcurrentInternalGraphNode <T> where T: Clone {
 
     pub (in crate::graph)  fn from(sink_node: Arc<ConcurrentTmpInternalGraphNode<T>>) -> Arc<ConcurrentInternalGraphNode<T>> {
-        if sink_node.parents.borrow().len() == 0 {
-            return Arc::new(ConcurrentInternalGraphNode {
-                f: sink_node.f,
-                name: sink_node.name.clone(),
-                id: sink_node.id,
-                parents: vec![],
-            });
+        return ConcurrentInternalGraphNode::from_memo(sink_node, &mut HashMap::new());
+    }
+
+    // a node read by several children is only built once, so that the immutable graph keeps the shape of the dag.
+    fn from_memo(sink_node: Arc<ConcurrentTmpInternalGraphNode<T>>,
+                 built: &mut HashMap<Uuid, Arc<ConcurrentInternalGraphNode<T>>>) -> Arc<ConcurrentInternalGraphNode<T>> {
+        if let Some(node) = built.get(&sink_node.id) {
+            return Arc::clone(node);
         }
 
         let mut parents = Vec::new();
         for parent in sink_node.parents.borrow().iter() {
-            let n = ConcurrentInternalGraphNode::from(Arc::clone(parent));
+            let n = ConcurrentInternalGraphNode::from_memo(Arc::clone(parent), built);
             parents.push(n);
         }
 
-        return Arc::new(ConcurrentInternalGraphNode {
+        let node = Arc::new(ConcurrentInternalGraphNode {
             f: sink_node.f,
             name: sink_node.name.clone(),
+            route: sink_node.route,
+            kind: sink_node.kind,
             id: sink_node.id,
             parents,
+            branches: sink_node.branches.borrow().clone(),
         });
+        built.insert(node.id, Arc::clone(&node));
+        return node;
+    }
+
+    pub (in crate::graph) fn view(sink: &Arc<ConcurrentInternalGraphNode<T>>) -> GraphView {
+        return GraphView::new(ConcurrentInternalGraphNode::index_by_id(sink).values()
+            .map(|node| (node.id, node.name.clone(), node.parents.iter().map(|parent| parent.id).collect()))
+            .collect());
+    }
+
+    // every node the sink reads from, sink included.
+    pub (in crate::graph) fn index_by_id(sink: &Arc<ConcurrentInternalGraphNode<T>>) -> HashMap<Uuid, Arc<ConcurrentInternalGraphNode<T>>> {
+        let mut id_to_node = HashMap::new();
+        let mut bfs_q = VecDeque::new();
+        bfs_q.push_back(Arc::clone(sink));
+
+        while let Some(node) = bfs_q.pop_front() {
+            if id_to_node.contains_key(&node.id) {
+                continue;
+            }
+            for parent in node.parents.iter() {
+                bfs_q.push_back(Arc::clone(parent));
+            }
+            id_to_node.insert(node.id, node);
+        }
+        return id_to_node;
     }
 
     // given the tap node, apply starting from sink node up to the tap.
     // to be used for concurrent application.
-    fn apply(&self, datum: Vec<T>) -> Vec<T> {
-        let f = self.f;
-        let mut data = vec![];
-        if self.parents.len() == 0 {
-            return f(datum);
+    fn apply(&self, datum: Vec<T>, graph: &ConcurrentComputeGraph<T>) -> Vec<T> {
+        // the run (and its memo) is dropped here, so that the output is not shared anymore and is not copied.
+        let result = self.apply_memo(&datum, &mut Run::new(graph, false));
+        return match result {
+            Some(result) => Arc::try_unwrap(result).unwrap_or_else(|shared| (*shared).clone()),
+            None => vec![],
+        };
+    }
+
+    fn apply_traced(&self, datum: Vec<T>, graph: &ConcurrentComputeGraph<T>) -> Trace<T> {
+        let mut run = Run::new(graph, true);
+        let result = self.apply_memo(&datum, &mut run);
+        return Trace {
+            output: result.map_or_else(Vec::new, |result| (*result).clone()),
+            nodes: run.records.unwrap(),
+            segments: vec![],
+        };
+    }
+
+    // each node is computed once per call, even when several children read from it.
+    fn apply_memo(&self, datum: &[T], run: &mut Run<T>) -> Option<Arc<Vec<T>>> {
+        if let Some(result) = run.memo.get(&self.id) {
+            return result.clone();
         }
-        for parent in &self.parents{
-            let result = parent.apply(datum.clone());
-            data.extend(result);
+        let graph = run.graph;
+        let result = match graph.caches.get(&self.id) {
+            Some(cache) if cache.scope == CacheScope::Subgraph => {
+                cache.get_or_compute(datum, || self.compute(datum, run))
+            }
+            _ => self.compute(datum, run),
+        };
+        run.memo.insert(self.id, result.clone());
+        return result;
+    }
+
+    // outputs are shared by reference, the only copies made are when a node has to concatenate its parents.
+    fn compute(&self, datum: &[T], run: &mut Run<T>) -> Option<Arc<Vec<T>>> {
+        if self.parents.is_empty() {
+            return Some(self.call(datum, run));
         }
-        return f(data);
+        let results = self.fired_inputs(datum, run);
+        return match results.len() {
+            0 => None,
+            1 => Some(self.call(&results[0], run)),
+            _ => {
+                let mut data = Vec::with_capacity(results.iter().map(|result| result.len()).sum());
+                for result in results.iter() {
+                    data.extend_from_slice(result);
+                }
+                Some(self.call(&data, run))
+            }
+        };
+    }
+
+    // outputs of the parents that fired towards this node, in order.
+    fn fired_inputs(&self, datum: &[T], run: &mut Run<T>) -> Vec<Arc<Vec<T>>> {
+        let mut results = vec![];
+        for (parent, branch) in self.parents.iter().zip(self.branches.iter()) {
+            if let Some(result) = parent.apply_memo(datum, run) {
+                if parent.route.fires(&result, *branch) {
+                    results.push(result);
+                }
+            }
+        }
+        return results;
+    }
+
+    fn call(&self, xs: &[T], run: &mut Run<T>) -> Arc<Vec<T>> {
+        let start = Instant::now();
+        let result = match run.graph.caches.get(&self.id) {
+            Some(cache) if cache.scope == CacheScope::Node => cache.get_or_compute(xs, || Some(Arc::new(self.invoke(xs, run.graph)))).unwrap(),
+            _ => Arc::new(self.invoke(xs, run.graph)),
+        };
+        if let Some(records) = run.records.as_mut() {
+            trace::record(records, &self.name, NodeRecord {
+                input: xs.to_vec(),
+                output: (*result).clone(),
+                start: start.duration_since(run.started),
+                duration: start.elapsed(),
+                thread: trace::current_thread_id(),
+            });
+        }
+        return result;
+    }
+
+    // the function of the node, accounted in the metrics of the graph.
+    fn invoke(&self, xs: &[T], graph: &ConcurrentComputeGraph<T>) -> Vec<T> {
+        let f = self.f;
+        return graph.metrics.node(&self.id).measure(|| f(xs));
+    }
+
+    // same as `invoke`, through the node cache when there is one.
+    fn invoke_cached(&self, xs: &[T], graph: &ConcurrentComputeGraph<T>) -> Vec<T> {
+        return match graph.caches.get(&self.id) {
+            Some(cache) if cache.scope == CacheScope::Node => {
+                let result = cache.get_or_compute(xs, || Some(Arc::new(self.invoke(xs, graph)))).unwrap();
+                Arc::try_unwrap(result).unwrap_or_else(|shared| (*shared).clone())
+            }
+            _ => self.invoke(xs, graph),
+        };
     }
 
     // given the tap node, apply starting from sink node up to the tap.
     // to be used for concurrent application.
-    fn apply_batch(&self, batch: Vec<Vec<T>>) -> Vec<Vec<T>> {
-        let f = self.f;
+    fn apply_batch(&self, batch: Vec<Vec<T>>, graph: &ConcurrentComputeGraph<T>) -> Vec<Vec<T>> {
+        let result = self.apply_batch_memo(&batch, &mut HashMap::new(), graph);
+        return Arc::try_unwrap(result).unwrap_or_else(|shared| (*shared).clone());
+    }
+
+    fn apply_batch_memo(&self, batch: &[Vec<T>], memo: &mut HashMap<Uuid, Arc<Vec<Vec<T>>>>, graph: &ConcurrentComputeGraph<T>) -> Arc<Vec<Vec<T>>> {
+        if let Some(result) = memo.get(&self.id) {
+            return Arc::clone(result);
+        }
         let mut data: Vec<Vec<T>> = Vec::new();
-        if self.parents.len() == 0 {
-            return batch.iter().map(|xs| f(xs.to_vec())).collect();
+        if self.parents.is_empty() {
+            data.extend(batch.iter().map(|xs| self.invoke_cached(xs, graph)));
         }
-        for parent in &self.parents {
-            let result = parent.apply_batch(batch.clone());
-            data.extend(result);
+        for (parent, branch) in self.parents.iter().zip(self.branches.iter()) {
+            let result = parent.apply_batch_memo(batch, memo, graph);
+            data.extend(result.iter().filter(|xs| parent.route.fires(xs, *branch)).map(|xs| self.invoke_cached(xs, graph)));
         }
-        return data.iter().map(|xs| f(xs.to_vec())).collect();
+        let result = Arc::new(data);
+        memo.insert(self.id, Arc::clone(&result));
+        return result;
     }
 }
 
@@ -141,12 +525,17 @@ impl <T> ConcurrentInternalGraphNode <T> where T: Clone {
 // internal temporary concurrent graph repr, on which we allow mutation.
 // *******************************
 type ConcurrentParentMutablRefs<T> = atomic_refcell::AtomicRefCell<Vec<Arc<ConcurrentTmpInternalGraphNode<T>>>>;
+type MutableBranches = atomic_refcell::AtomicRefCell<Vec<usize>>;
 // for the internal structure each node points to its parents.
 // because of the non-natural way to express such a graph, we keep this representation private.
 pub struct ConcurrentTmpInternalGraphNode<T> {
     f: GraphLikeFunc<T>,
     name: String,
+    operator: Option<String>,
+    route: Route<T>,
+    kind: NodeKind,
     parents: ConcurrentParentMutablRefs<T>,
+    branches: MutableBranches,
     id: Uuid,
 }
 
@@ -158,61 +547,69 @@ impl <T> fmt::Display for ConcurrentTmpInternalGraphNode<T> where T: Clone {
 
 impl <T> ConcurrentTmpInternalGraphNode<T> where T: Clone {
 
-    fn empty(f: GraphLikeFunc<T>, m: String, id: Uuid) -> ConcurrentTmpInternalGraphNode<T> {
+    fn empty(node: &ConcurrentGraphNode<T>, id: Uuid) -> ConcurrentTmpInternalGraphNode<T> {
         return ConcurrentTmpInternalGraphNode {
-            f,
-            name: m,
+            f: node.f,
+            name: node.name.clone(),
+            operator: node.operator.clone(),
+            route: node.route,
+            kind: node.kind,
             id,
-            parents: atomic_refcell::AtomicRefCell::new(vec![])
+            parents: atomic_refcell::AtomicRefCell::new(vec![]),
+            branches: atomic_refcell::AtomicRefCell::new(vec![]),
         };
     }
     // we start from the root node, and build a transpose of the given graph.
     // ref: https://en.wikipedia.org/wiki/Transpose_graph
-    pub (in crate::graph) fn to_internal_graph_node(node: Arc<ConcurrentGraphNode<T>>) -> Arc<ConcurrentTmpInternalGraphNode<T>> {
+    pub (in crate::graph) fn to_internal_graph_node(node: Arc<ConcurrentGraphNode<T>>) -> Result<Arc<ConcurrentTmpInternalGraphNode<T>>, GraphError> {
 
         // 1. declare all necessary structures
+        let ids = ConcurrentGraphNode::assign_ids(&node)?;
         let mut bfs_q = VecDeque::new();
         let mut internal_nodes = VecDeque::new();
         let mut id_to_internal_node = HashMap::new();
         let mut id_to_parent_ids: HashMap<Uuid, HashSet<Uuid>> = HashMap::new(); // avoid counting a node multiple times.
 
-        let internal = ConcurrentTmpInternalGraphNode::empty(node.f, node.name.clone(), node.id);
+        let root_id = ids[&ConcurrentGraphNode::key(&node)];
+        let internal = ConcurrentTmpInternalGraphNode::empty(&node, root_id);
         let mut internal_arc = Arc::new(internal);
 
         // 2. initiate structure
-        id_to_internal_node.insert(node.id, Arc::clone(&internal_arc));
-        id_to_parent_ids.insert(node.id, HashSet::new());
+        id_to_internal_node.insert(root_id, Arc::clone(&internal_arc));
+        id_to_parent_ids.insert(root_id, HashSet::new());
         bfs_q.push_back(node);
         internal_nodes.push_back(Arc::clone(&internal_arc));
 
         // 3. iterate over graph bfs style.
-        while bfs_q.len() != 0 {
+        while !bfs_q.is_empty() {
             let node = bfs_q.pop_front().unwrap();
             let internal_node = internal_nodes.pop_front().unwrap();
 
 
-            for child in node.children.iter() {
+            for (branch, child) in node.children.iter().enumerate() {
 
                 // either get back already built node if it exists or create a new one.
+                let child_id = ids[&ConcurrentGraphNode::key(child)];
                 let new_internal: Arc<ConcurrentTmpInternalGraphNode<T>> = {
-                    if id_to_internal_node.contains_key(&child.id) {
-                        Arc::clone(&id_to_internal_node.get(&child.id).unwrap())
+                    if id_to_internal_node.contains_key(&child_id) {
+                        Arc::clone(id_to_internal_node.get(&child_id).unwrap())
                     } else {
-                        Arc::new(ConcurrentTmpInternalGraphNode::empty(child.f, child.name.clone(), child.id))
+                        Arc::new(ConcurrentTmpInternalGraphNode::empty(child, child_id))
                     }
                 };
 
-                let parent_ids_opt = id_to_parent_ids.get(&child.id);
+                let parent_ids_opt = id_to_parent_ids.get(&child_id);
                 if parent_ids_opt.is_none() || !parent_ids_opt.unwrap().contains(&internal_node.id) {
                     new_internal.parents.borrow_mut().push(Arc::clone(&internal_node));
+                    new_internal.branches.borrow_mut().push(branch);
                 }
                 if parent_ids_opt.is_some() {
-                    let ids = id_to_parent_ids.get_mut(&child.id).unwrap();
-                    ids.insert(internal_node.id);
+                    let parent_ids = id_to_parent_ids.get_mut(&child_id).unwrap();
+                    parent_ids.insert(internal_node.id);
                 } else {
-                    let mut ids = HashSet::new();
-                    ids.insert(internal_node.id);
-                    id_to_parent_ids.insert(new_internal.id, ids);
+                    let mut parent_ids = HashSet::new();
+                    parent_ids.insert(internal_node.id);
+                    id_to_parent_ids.insert(new_internal.id, parent_ids);
                 }
 
                 id_to_internal_node.insert(new_internal.id, Arc::clone(&new_internal));
@@ -223,6 +620,181 @@ impl <T> ConcurrentTmpInternalGraphNode<T> where T: Clone {
             }
         }
 
-        return internal_arc;
+        return Ok(internal_arc);
     }
-}
\ No newline at end of file
+
+    // every node the sink reads from, sink included.
+    pub (in crate::graph) fn index_by_id(sink: &Arc<ConcurrentTmpInternalGraphNode<T>>) -> HashMap<Uuid, Arc<ConcurrentTmpInternalGraphNode<T>>> {
+        let mut id_to_node = HashMap::new();
+        let mut bfs_q = VecDeque::new();
+        bfs_q.push_back(Arc::clone(sink));
+
+        while let Some(node) = bfs_q.pop_front() {
+            if id_to_node.contains_key(&node.id) {
+                continue;
+            }
+            for parent in node.parents.borrow().iter() {
+                bfs_q.push_back(Arc::clone(parent));
+            }
+            id_to_node.insert(node.id, node);
+        }
+        return id_to_node;
+    }
+
+    // rewires the parents of every node so that duplicated computations point to a single node.
+    // must run before the graph is frozen into its immutable repr.
+    pub (in crate::graph) fn optimize(self: &Arc<Self>, root: &Arc<ConcurrentGraphNode<T>>) -> Result<OptimizationReport, GraphError> {
+        let id_to_node = ConcurrentTmpInternalGraphNode::index_by_id(self);
+        let ids = ConcurrentGraphNode::assign_ids(root)?;
+        let all_nodes = ConcurrentGraphNode::all_nodes(root).iter().map(|node| (ids[&ConcurrentGraphNode::key(node)], node.name.clone())).collect();
+        let reachable = id_to_node.values().map(|node| OptimizerNode {
+            id: node.id,
+            name: node.name.clone(),
+            operator: node.operator.clone(),
+            route: node.route.kind(),
+            parents: node.parents.borrow().iter().map(|parent| parent.id).collect(),
+            branches: node.parents.borrow().iter().zip(node.branches.borrow().iter())
+                .map(|(parent, branch)| parent.route.branch_key(*branch))
+                .collect(),
+        }).collect();
+
+        let (replaced_by, report) = optimizer::plan(all_nodes, self.id, reachable);
+        for node in id_to_node.values() {
+            let parents = node.parents.borrow().iter()
+                .map(|parent| Arc::clone(id_to_node.get(replaced_by.get(&parent.id).unwrap()).unwrap()))
+                .collect();
+            *node.parents.borrow_mut() = parents;
+        }
+        return Ok(report);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc_counter::{self, LARGE};
+    use crate::graph::easy_functions;
+
+    // large enough for every copy of the datum to be counted.
+    const DATUM_LEN: usize = LARGE;
+
+    fn head(xs: &[f64]) -> Vec<f64> {
+        return xs.iter().take(1).cloned().collect();
+    }
+
+    // add_one (source) -> 32 x head -> head (sink)
+    fn fan_out() -> Arc<ConcurrentGraphNode<f64>> {
+        let sink = Arc::new(ConcurrentGraphNode::new(head, String::from("sink"), vec![]));
+        let branches = (0..32).map(|i| {
+            Arc::new(ConcurrentGraphNode::new(head, format!("branch {}", i), vec![Arc::clone(&sink)]))
+        }).collect();
+        return Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("source"), branches));
+    }
+
+    // start (add_one) -> left (add_one) / right (add_five) -> last (square)
+    fn diamond(right_name: &str) -> Result<ConcurrentComputeGraph<f64>, GraphError> {
+        let last = Arc::new(ConcurrentGraphNode::new(easy_functions::square, String::from("last"), vec![]));
+        let left = Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("left"), vec![Arc::clone(&last)]));
+        let right = Arc::new(ConcurrentGraphNode::new(easy_functions::add_five, String::from(right_name), vec![last]));
+        return ConcurrentComputeGraph::new(Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("start"), vec![left, right])));
+    }
+
+    #[test]
+    fn evaluate_returns_the_output_of_any_node() {
+        let graph = diamond("right").unwrap();
+        assert_eq!(graph.evaluate("left", vec![1.0]), Some(vec![3.0]));
+        assert_eq!(graph.evaluate("right", vec![1.0]), Some(vec![7.0]));
+        assert_eq!(graph.evaluate("missing", vec![1.0]), None);
+    }
+
+    #[test]
+    fn caches_are_enabled_on_the_named_node() {
+        let mut graph = diamond("right").unwrap();
+        graph.enable_cache("right", CacheConfig { capacity: 4, scope: CacheScope::Node, hasher: crate::graph::cache::hash_f64s });
+        graph.apply(vec![1.0]);
+        graph.apply(vec![1.0]);
+
+        let stats = graph.cache_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!((stats["right"].hits, stats["right"].misses), (1, 1));
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        assert_eq!(diamond("left").err(), Some(GraphError::DuplicateName(String::from("left"))));
+    }
+
+    #[test]
+    fn subgraph_namespaces_the_embedded_nodes() {
+        let inner = diamond("right").unwrap();
+        let sink = Arc::new(ConcurrentGraphNode::new(easy_functions::add_five, String::from("sink"), vec![]));
+        let embedded = ConcurrentGraphNode::subgraph(&inner, "inner", vec![sink]);
+        let outer = ConcurrentComputeGraph::new(Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("start"), vec![embedded]))).unwrap();
+
+        let mut names: Vec<String> = outer.node_ids().into_keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["inner/last", "inner/left", "inner/right", "inner/start", "sink", "start"]);
+        let expected: Vec<f64> = inner.apply(vec![2.0]).iter().map(|x| x + 5.0).collect();
+        assert_eq!(outer.apply(vec![1.0]), expected);
+        // the embedded graph is copied, it still runs on its own.
+        assert_eq!(inner.apply(vec![1.0]), diamond("right").unwrap().apply(vec![1.0]));
+    }
+
+    #[test]
+    fn then_feeds_the_sink_into_the_next_graph() {
+        let first = diamond("right").unwrap();
+        let sink = Arc::new(ConcurrentGraphNode::new(easy_functions::square, String::from("sink"), vec![]));
+        let next = ConcurrentComputeGraph::new(Arc::new(ConcurrentGraphNode::new(easy_functions::add_five, String::from("next"), vec![sink]))).unwrap();
+
+        let chained = first.then(&next).unwrap();
+        assert_eq!(chained.node_ids().len(), 6);
+        assert_eq!(chained.apply(vec![1.0]), next.apply(first.apply(vec![1.0])));
+    }
+
+    #[test]
+    fn then_rejects_shared_names() {
+        let graph = diamond("right").unwrap();
+        assert!(matches!(graph.then(&graph), Err(GraphError::DuplicateName(_))));
+    }
+
+    #[test]
+    fn fan_out_does_not_copy_the_source_output() {
+        let graph = ConcurrentComputeGraph::new(fan_out()).unwrap();
+        let datum = vec![1.0; DATUM_LEN];
+        let datum_bytes = DATUM_LEN * std::mem::size_of::<f64>();
+
+        // before: every edge copied the output of its parent.
+        let (_, _, copied_bytes) = alloc_counter::measure(|| (0..32).map(|_| datum.clone()).collect::<Vec<_>>());
+        // after: only the output of the source is allocated.
+        let (output, allocations, bytes) = alloc_counter::measure(|| graph.apply(datum));
+
+        assert_eq!(output, vec![2.0]);
+        assert_eq!(allocations, 1);
+        assert!(bytes < 2 * datum_bytes);
+        assert!(copied_bytes >= 32 * datum_bytes);
+    }
+
+    #[test]
+    fn batch_does_not_copy_the_data() {
+        let graph = ConcurrentComputeGraph::new(fan_out()).unwrap();
+        let data = vec![vec![1.0; DATUM_LEN], vec![2.0; DATUM_LEN]];
+
+        // one source output per datum, the sink reads the batch of every branch.
+        let (outputs, allocations, _) = alloc_counter::measure(|| graph.apply_batch(data));
+        assert_eq!(outputs.len(), 64);
+        assert_eq!(outputs[..2], [vec![2.0], vec![3.0]]);
+        assert_eq!(allocations, 2);
+    }
+
+    #[test]
+    fn sink_output_is_not_copied() {
+        let sink = Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("sink"), vec![]));
+        let graph = ConcurrentComputeGraph::new(Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("source"), vec![sink]))).unwrap();
+        let datum = vec![1.0; DATUM_LEN];
+
+        // one output per node.
+        let (output, allocations, _) = alloc_counter::measure(|| graph.apply(datum));
+        assert_eq!(output[0], 3.0);
+        assert_eq!(allocations, 2);
+    }
+}
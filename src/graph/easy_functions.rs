@@ -1,11 +1,26 @@
-pub fn add_one(xs: Vec<f64>) -> Vec<f64> {
+pub fn add_one(xs: &[f64]) -> Vec<f64> {
     return xs.iter().map(|x| {x + 1.}).collect();
 }
 
-pub fn add_five(xs: Vec<f64>) -> Vec<f64> {
+pub fn add_five(xs: &[f64]) -> Vec<f64> {
     return xs.iter().map(|x| {x + 5.}).collect();
 }
 
-pub fn square(xs: Vec<f64>) -> Vec<f64> {
+pub fn square(xs: &[f64]) -> Vec<f64> {
     return xs.iter().map(|x| {x.powf(2.)}).collect();
 }
+
+// routing: the first child when any value is negative, the second one otherwise.
+pub fn by_sign(xs: &[f64]) -> usize {
+    return if xs.iter().any(|x| *x < 0.) { 0 } else { 1 };
+}
+
+pub fn below_hundred(xs: &[f64]) -> bool {
+    return xs.iter().all(|x| *x < 100.);
+}
+
+// stands for a call to a remote service.
+pub fn slow_add_one(xs: &[f64]) -> Vec<f64> {
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    return add_one(xs);
+}
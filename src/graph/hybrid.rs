@@ -0,0 +1,373 @@
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::Instant;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::graph::GraphError;
+use crate::graph::concurrent::{ConcurrentGraphNode, ConcurrentInternalGraphNode, ConcurrentTmpInternalGraphNode};
+use crate::graph::optimizer::OptimizationReport;
+use crate::graph::introspection::GraphView;
+use crate::graph::metrics::MetricsRegistry;
+use crate::graph::trace::{self, NodeRecord, NodeRecords, SegmentRecord, Trace};
+
+// *******************************
+// hybrid executor.
+// Nodes are tagged `Cpu` (the default) or `Io` (see `ConcurrentGraphNode::io`).
+// Cpu nodes run inline, on the thread calling `apply`. Chains of Io nodes (segments) are sent to a pool,
+// so that cpu nodes keep running while they wait. A node runs as soon as all its parents are done,
+// whether they ran inline or on the pool, which is how joins across both kinds resolve.
+// *******************************
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    Cpu,
+    Io,
+}
+
+pub struct HybridComputeGraph<T> where T: Clone {
+    // in topological order, nodes are referred to by their index.
+    nodes: Vec<Arc<ConcurrentInternalGraphNode<T>>>,
+    // (parent, branch) of every node.
+    parents: Vec<Vec<(usize, usize)>>,
+    children: Vec<Vec<usize>>,
+    // by first node, the nodes of every segment sent to the pool.
+    segments: HashMap<usize, Vec<usize>>,
+    sink: usize,
+    pool: ThreadPool,
+    metrics: Arc<MetricsRegistry>,
+}
+
+// what a segment produced on the pool, along with its records when traced.
+struct SegmentOutput<T> {
+    last: usize,
+    // None when the segment did not fire (see `routing`).
+    result: Option<Vec<T>>,
+    records: Vec<(String, NodeRecord<T>)>,
+    segment: Option<SegmentRecord>,
+}
+
+// a node of a segment, along with the branch the next node of the segment reads.
+type SegmentPlan<T> = Vec<(Arc<ConcurrentInternalGraphNode<T>>, usize)>;
+
+// state of a single call to apply.
+struct Run<T> {
+    started: Instant,
+    // for every node: None until it is done, then None if it did not fire.
+    outputs: Vec<Option<Option<Arc<Vec<T>>>>>,
+    // parents every node still waits for.
+    missing: Vec<usize>,
+    ready: VecDeque<usize>,
+    // only kept for traced runs.
+    records: Option<NodeRecords<T>>,
+    segments: Vec<SegmentRecord>,
+}
+
+impl <T> HybridComputeGraph<T> where T: Clone + Send + Sync + 'static {
+
+    // io segments run on a pool of `io_threads` threads, owned by the graph.
+    // fails when the graph is not valid (see `GraphError`).
+    pub fn new(root: Arc<ConcurrentGraphNode<T>>, io_threads: usize) -> Result<HybridComputeGraph<T>, GraphError> {
+        let sink = ConcurrentInternalGraphNode::from(ConcurrentTmpInternalGraphNode::to_internal_graph_node(root)?);
+        return Ok(HybridComputeGraph::from_internal(sink, io_threads));
+    }
+
+    // same as `new`, but drops the nodes the sink does not read and merges duplicated computations.
+    pub fn optimized(root: Arc<ConcurrentGraphNode<T>>, io_threads: usize) -> Result<(HybridComputeGraph<T>, OptimizationReport), GraphError> {
+        let tmp_sink = ConcurrentTmpInternalGraphNode::to_internal_graph_node(Arc::clone(&root))?;
+        let report = tmp_sink.optimize(&root)?;
+        let graph = HybridComputeGraph::from_internal(ConcurrentInternalGraphNode::from(tmp_sink), io_threads);
+        return Ok((graph, report));
+    }
+
+    fn from_internal(sink: Arc<ConcurrentInternalGraphNode<T>>, io_threads: usize) -> HybridComputeGraph<T> {
+        let id_to_node = ConcurrentInternalGraphNode::index_by_id(&sink);
+        let view = ConcurrentInternalGraphNode::view(&sink);
+        let nodes: Vec<Arc<ConcurrentInternalGraphNode<T>>> = view.nodes().iter()
+            .map(|node| Arc::clone(&id_to_node[&node.id]))
+            .collect();
+        let id_to_index: HashMap<_, _> = nodes.iter().enumerate().map(|(i, node)| (node.id, i)).collect();
+
+        // 1. index the edges.
+        let parents: Vec<Vec<(usize, usize)>> = nodes.iter()
+            .map(|node| node.parents.iter().zip(node.branches.iter()).map(|(parent, branch)| (id_to_index[&parent.id], *branch)).collect())
+            .collect();
+        let mut children = vec![vec![]; nodes.len()];
+        for (i, node_parents) in parents.iter().enumerate() {
+            for (parent, _) in node_parents {
+                children[*parent].push(i);
+            }
+        }
+
+        // 2. an io node reading from a single io node, its only child, runs in the same segment.
+        let continues_segment = |i: usize| {
+            nodes[i].kind == NodeKind::Io && parents[i].len() == 1
+                && nodes[parents[i][0].0].kind == NodeKind::Io && children[parents[i][0].0].len() == 1
+        };
+        let mut segments = HashMap::new();
+        for (i, node) in nodes.iter().enumerate() {
+            if node.kind != NodeKind::Io || continues_segment(i) {
+                continue;
+            }
+            let mut segment = vec![i];
+            let mut last = i;
+            while children[last].len() == 1 && continues_segment(children[last][0]) {
+                last = children[last][0];
+                segment.push(last);
+            }
+            segments.insert(i, segment);
+        }
+
+        let metrics = nodes.iter().map(|node| (node.id, node.name.clone())).collect();
+        return HybridComputeGraph {
+            sink: id_to_index[&sink.id],
+            nodes,
+            parents,
+            children,
+            segments,
+            pool: ThreadPoolBuilder::new().num_threads(io_threads).build().unwrap(),
+            metrics: Arc::new(MetricsRegistry::new(metrics)),
+        };
+    }
+
+    // calls, errors and latencies of the graph and of every node, and the time segments wait for the pool.
+    pub fn metrics(&self) -> Arc<MetricsRegistry> {
+        return Arc::clone(&self.metrics);
+    }
+
+    // read-only structure of the graph, as it is executed.
+    pub fn view(&self) -> GraphView {
+        return ConcurrentInternalGraphNode::view(&self.nodes[self.sink]);
+    }
+
+    pub fn apply(&self, datum: Vec<T>) -> Vec<T> {
+        return self.metrics.graph.measure(|| self.run(datum, false).output);
+    }
+
+    // same as `apply`, along with the input, output and duration of every node, and the timeline of every segment.
+    pub fn apply_traced(&self, datum: Vec<T>) -> Trace<T> {
+        return self.metrics.graph.measure(|| self.run(datum, true));
+    }
+
+    fn run(&self, datum: Vec<T>, traced: bool) -> Trace<T> {
+        let datum = Arc::new(datum);
+        let (sender, receiver) = channel();
+        let mut run = Run {
+            started: Instant::now(),
+            outputs: vec![None; self.nodes.len()],
+            missing: self.parents.iter().map(|parents| parents.len()).collect(),
+            ready: (0..self.nodes.len()).filter(|i| self.parents[*i].is_empty()).collect(),
+            records: if traced { Some(NodeRecords::new()) } else { None },
+            segments: vec![],
+        };
+
+        let mut in_flight = 0;
+        loop {
+            // 1. run every ready cpu node inline, and send ready io segments to the pool.
+            while let Some(i) = run.ready.pop_front() {
+                let input = self.input(i, &datum, &run);
+                match (self.nodes[i].kind, input) {
+                    (NodeKind::Io, Some(xs)) => {
+                        self.submit(i, xs, traced, run.started, &sender);
+                        in_flight += 1;
+                    }
+                    (NodeKind::Io, None) => {
+                        let last = *self.segments[&i].last().unwrap();
+                        self.complete(last, None, &mut run);
+                    }
+                    (NodeKind::Cpu, input) => {
+                        let output = input.map(|xs| self.call(i, &xs, &mut run));
+                        self.complete(i, output, &mut run);
+                    }
+                }
+            }
+
+            // 2. nothing left to run inline, wait for a segment.
+            if in_flight == 0 {
+                break;
+            }
+            let output = receiver.recv().unwrap().unwrap_or_else(|err| panic::resume_unwind(err));
+            in_flight -= 1;
+            if let Some(records) = run.records.as_mut() {
+                for (name, record) in output.records {
+                    trace::record(records, &name, record);
+                }
+            }
+            run.segments.extend(output.segment);
+            self.complete(output.last, output.result.map(Arc::new), &mut run);
+        }
+
+        let output = run.outputs[self.sink].clone().unwrap();
+        return Trace {
+            output: output.map_or_else(Vec::new, |output| (*output).clone()),
+            nodes: run.records.unwrap_or_default(),
+            segments: run.segments,
+        };
+    }
+
+    // the outputs of the parents that fired towards the node, sources read the datum.
+    fn input(&self, i: usize, datum: &Arc<Vec<T>>, run: &Run<T>) -> Option<Arc<Vec<T>>> {
+        if self.parents[i].is_empty() {
+            return Some(Arc::clone(datum));
+        }
+        let results: Vec<&Arc<Vec<T>>> = self.parents[i].iter()
+            .filter_map(|(parent, branch)| {
+                run.outputs[*parent].as_ref().unwrap().as_ref().filter(|xs| self.nodes[*parent].route.fires(xs, *branch))
+            })
+            .collect();
+        return match results.len() {
+            0 => None,
+            1 => Some(Arc::clone(results[0])),
+            _ => {
+                let mut data = Vec::with_capacity(results.iter().map(|result| result.len()).sum());
+                for result in results.iter() {
+                    data.extend_from_slice(result);
+                }
+                Some(Arc::new(data))
+            }
+        };
+    }
+
+    fn call(&self, i: usize, xs: &[T], run: &mut Run<T>) -> Arc<Vec<T>> {
+        let node = &self.nodes[i];
+        let f = node.f;
+        let start = Instant::now();
+        let output = self.metrics.node(&node.id).measure(|| f(xs));
+        if let Some(records) = run.records.as_mut() {
+            trace::record(records, &node.name, NodeRecord {
+                input: xs.to_vec(),
+                output: output.clone(),
+                start: start.duration_since(run.started),
+                duration: start.elapsed(),
+                thread: trace::current_thread_id(),
+            });
+        }
+        return Arc::new(output);
+    }
+
+    fn complete(&self, i: usize, output: Option<Arc<Vec<T>>>, run: &mut Run<T>) {
+        run.outputs[i] = Some(output);
+        for child in self.children[i].iter() {
+            run.missing[*child] -= 1;
+            if run.missing[*child] == 0 {
+                run.ready.push_back(*child);
+            }
+        }
+    }
+
+    // a panic on the pool is sent back, and propagated by the thread calling `apply`.
+    fn submit(&self, first: usize, datum: Arc<Vec<T>>, traced: bool, started: Instant, sender: &Sender<thread::Result<SegmentOutput<T>>>) {
+        let segment = &self.segments[&first];
+        let plan: SegmentPlan<T> = segment.iter().enumerate()
+            .map(|(k, i)| (Arc::clone(&self.nodes[*i]), segment.get(k + 1).map_or(0, |next| self.parents[*next][0].1)))
+            .collect();
+        let last = *segment.last().unwrap();
+        let metrics = Arc::clone(&self.metrics);
+        let sender = sender.clone();
+        let forked = Instant::now();
+        self.pool.spawn(move || {
+            metrics.queue_wait.observe(forked.elapsed());
+            let output = panic::catch_unwind(AssertUnwindSafe(|| run_segment(last, plan, datum, traced, started, &metrics)));
+            // the receiver is gone when another node panicked.
+            let _ = sender.send(output);
+        });
+    }
+}
+
+fn run_segment<T>(last: usize,
+                  plan: SegmentPlan<T>,
+                  datum: Arc<Vec<T>>,
+                  traced: bool,
+                  started: Instant,
+                  metrics: &MetricsRegistry) -> SegmentOutput<T> where T: Clone {
+    let segment_start = Instant::now();
+    let mut result: Option<Vec<T>> = None;
+    let mut records = vec![];
+    for (k, (node, next_branch)) in plan.iter().enumerate() {
+        let xs: &[T] = match &result {
+            None => &datum,
+            Some(xs) => xs,
+        };
+        let f = node.f;
+        let start = Instant::now();
+        let output = metrics.node(&node.id).measure(|| f(xs));
+        if traced {
+            records.push((node.name.clone(), NodeRecord {
+                input: xs.to_vec(),
+                output: output.clone(),
+                start: start.duration_since(started),
+                duration: start.elapsed(),
+                thread: trace::current_thread_id(),
+            }));
+        }
+        let fired = k + 1 == plan.len() || node.route.fires(&output, *next_branch);
+        result = Some(output);
+        if !fired {
+            result = None;
+            break;
+        }
+    }
+    let segment = if traced {
+        Some(SegmentRecord {
+            nodes: plan.iter().map(|(node, _)| node.name.clone()).collect(),
+            start: segment_start.duration_since(started),
+            duration: segment_start.elapsed(),
+            thread: trace::current_thread_id(),
+        })
+    } else {
+        None
+    };
+    return SegmentOutput { last, result, records, segment };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::concurrent::ConcurrentComputeGraph;
+    use crate::graph::easy_functions;
+
+    //                 / fetch a (io) -> fetch a again (io) \
+    // start (add_one) - fetch b (io) ----------------------- join (add_five)
+    //                 \ square (cpu) ----------------------/
+    fn fetches() -> Arc<ConcurrentGraphNode<f64>> {
+        let join = Arc::new(ConcurrentGraphNode::new(easy_functions::add_five, String::from("join"), vec![]));
+        let fetch_a_again = Arc::new(ConcurrentGraphNode::io(easy_functions::add_one, String::from("fetch a again"), vec![Arc::clone(&join)]));
+        let fetch_a = Arc::new(ConcurrentGraphNode::io(easy_functions::add_one, String::from("fetch a"), vec![fetch_a_again]).with_operator("fetch"));
+        let fetch_b = Arc::new(ConcurrentGraphNode::io(easy_functions::add_one, String::from("fetch b"), vec![Arc::clone(&join)]).with_operator("fetch"));
+        let square = Arc::new(ConcurrentGraphNode::new(easy_functions::square, String::from("square"), vec![join]));
+        return Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("start"), vec![fetch_a, fetch_b, square]));
+    }
+
+    #[test]
+    fn apply_matches_the_concurrent_executor() {
+        let root = fetches();
+        let expected = ConcurrentComputeGraph::new(Arc::clone(&root)).unwrap().apply(vec![1.0, 2.0]);
+        let graph = HybridComputeGraph::new(root, 2).unwrap();
+        assert_eq!(graph.apply(vec![1.0, 2.0]), expected);
+        assert_eq!(graph.metrics().snapshot().graph.calls, 1);
+    }
+
+    #[test]
+    fn io_chains_run_as_segments() {
+        let trace = HybridComputeGraph::new(fetches(), 2).unwrap().apply_traced(vec![1.0]);
+        let mut segments: Vec<Vec<String>> = trace.segments.iter().map(|segment| segment.nodes.clone()).collect();
+        segments.sort();
+        assert_eq!(segments, vec![vec![String::from("fetch a"), String::from("fetch a again")], vec![String::from("fetch b")]]);
+    }
+
+    #[test]
+    fn optimized_merges_and_keeps_the_output() {
+        let root = fetches();
+        let expected = HybridComputeGraph::new(Arc::clone(&root), 2).unwrap().apply(vec![1.0, 2.0]);
+        let (graph, report) = HybridComputeGraph::optimized(root, 2).unwrap();
+        assert_eq!(report.merged, vec![(String::from("fetch a"), String::from("fetch b"))]);
+        assert_eq!(graph.apply(vec![1.0, 2.0]), expected);
+
+        let view = graph.view();
+        assert_eq!(view.nodes().len(), 5);
+        assert!(view.node("fetch a").is_none());
+        assert_eq!(view.node("fetch b").unwrap().fan_out(), 2);
+        assert_eq!(view.topological_order().first().map(String::as_str), Some("start"));
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use uuid::Uuid;
+
+use crate::graph::GraphError;
+
+// *******************************
+// deterministic node ids.
+// A node id is derived from its name, its operator (see `GraphNode::with_operator`) and the ids of its parents
+// (what it reads from), so that the same graph gets the same ids from one run, process or build to the next.
+// Functions are not part of the id: their address changes from one build to the next. Changing the function of a node
+// without renaming it, or changing its operator, keeps its id. A node can also be given an explicit id.
+// Ids are assigned when a graph is built, and must be unique within the graph, so must names.
+// *******************************
+
+// flat view of a user node, as seen when assigning ids.
+// nodes are identified by their address until they get an id.
+pub (in crate::graph) struct IdentityNode {
+    pub (in crate::graph) key: usize,
+    pub (in crate::graph) name: String,
+    pub (in crate::graph) operator: Option<String>,
+    pub (in crate::graph) explicit_id: Option<Uuid>,
+    pub (in crate::graph) children: Vec<usize>,
+}
+
+// id of every node, by address. fails if two nodes share a name or an id, or if the graph has a cycle.
+pub (in crate::graph) fn assign_ids(nodes: Vec<IdentityNode>) -> Result<HashMap<usize, Uuid>, GraphError> {
+    let key_to_node: HashMap<usize, &IdentityNode> = nodes.iter().map(|node| (node.key, node)).collect();
+    let mut names = HashSet::new();
+    for node in nodes.iter() {
+        if !names.insert(&node.name) {
+            return Err(GraphError::DuplicateName(node.name.clone()));
+        }
+    }
+
+    // 1. invert the children links.
+    let mut key_to_parents: HashMap<usize, Vec<usize>> = nodes.iter().map(|node| (node.key, vec![])).collect();
+    for node in nodes.iter() {
+        for child in node.children.iter() {
+            key_to_parents.get_mut(child).unwrap().push(node.key);
+        }
+    }
+
+    // 2. walk the graph parents first (kahn's algorithm).
+    let mut n_missing_parents: HashMap<usize, usize> = key_to_parents.iter().map(|(key, parents)| (*key, parents.len())).collect();
+    let mut ready: VecDeque<usize> = nodes.iter().filter(|node| key_to_parents[&node.key].is_empty()).map(|node| node.key).collect();
+    let mut ids: HashMap<usize, Uuid> = HashMap::new();
+    let mut id_to_name: HashMap<Uuid, &str> = HashMap::new();
+
+    while let Some(key) = ready.pop_front() {
+        let node = key_to_node[&key];
+        let id = node.explicit_id.unwrap_or_else(|| {
+            let parent_ids: Vec<Uuid> = key_to_parents[&key].iter().map(|parent| ids[parent]).collect();
+            content_id(&node.name, node.operator.as_deref(), &parent_ids)
+        });
+        if let Some(other) = id_to_name.insert(id, &node.name) {
+            return Err(GraphError::DuplicateId(id, String::from(other), node.name.clone()));
+        }
+        ids.insert(key, id);
+
+        for child in node.children.iter() {
+            let n_missing = n_missing_parents.get_mut(child).unwrap();
+            *n_missing -= 1;
+            if *n_missing == 0 {
+                ready.push_back(*child);
+            }
+        }
+    }
+    // nodes of a cycle never get all their parents.
+    if let Some(node) = nodes.iter().find(|node| !ids.contains_key(&node.key)) {
+        return Err(GraphError::Cycle(node.name.clone()));
+    }
+    return Ok(ids);
+}
+
+// name and id of a node copied into another graph under a namespace (see `subgraph`).
+pub (in crate::graph) fn namespaced(namespace: &str, name: &str, explicit_id: Option<Uuid>) -> (String, Option<Uuid>) {
+    let id = explicit_id.map(|id| Uuid::new_v5(&id, namespace.as_bytes()));
+    return (format!("{}/{}", namespace, name), id);
+}
+
+// name based uuid (v5), parents are taken in order since a node reads them in order.
+fn content_id(name: &str, operator: Option<&str>, parent_ids: &[Uuid]) -> Uuid {
+    let mut bytes = vec![];
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.push(0);
+    if let Some(operator) = operator {
+        bytes.extend_from_slice(operator.as_bytes());
+    }
+    bytes.push(0);
+    for parent_id in parent_ids {
+        bytes.extend_from_slice(parent_id.as_bytes());
+    }
+    return Uuid::new_v5(&Uuid::NAMESPACE_OID, &bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(key: usize, name: &str, operator: Option<&str>, children: Vec<usize>) -> IdentityNode {
+        return IdentityNode { key, name: String::from(name), operator: operator.map(String::from), explicit_id: None, children };
+    }
+
+    // a -> b -> c, a -> c
+    fn chain(b_operator: Option<&str>) -> Vec<IdentityNode> {
+        return vec![
+            node(1, "a", Some("add one"), vec![2, 3]),
+            node(2, "b", b_operator, vec![3]),
+            node(3, "c", None, vec![]),
+        ];
+    }
+
+    #[test]
+    fn ids_do_not_depend_on_the_build() {
+        // pinned: the same name, operator and parents give the same id whatever the binary.
+        let ids = assign_ids(chain(Some("square"))).unwrap();
+        assert_eq!(ids[&1], content_id("a", Some("add one"), &[]));
+        assert_eq!(ids[&1], Uuid::parse_str("ae69fa86-7e9f-5f78-ac5f-97029a3579fd").unwrap());
+        assert_eq!(ids[&3], content_id("c", None, &[ids[&1], ids[&2]]));
+    }
+
+    #[test]
+    fn operator_and_parents_change_the_id() {
+        let ids = assign_ids(chain(Some("square"))).unwrap();
+        let other = assign_ids(chain(Some("add five"))).unwrap();
+        assert_eq!(ids[&1], other[&1]);
+        // b and every node downstream of it.
+        assert_ne!(ids[&2], other[&2]);
+        assert_ne!(ids[&3], other[&3]);
+    }
+
+    #[test]
+    fn explicit_ids_are_kept() {
+        let mut nodes = chain(None);
+        nodes[1].explicit_id = Some(Uuid::from_u128(42));
+        let ids = assign_ids(nodes).unwrap();
+        assert_eq!(ids[&2], Uuid::from_u128(42));
+    }
+
+    #[test]
+    fn duplicate_ids_are_rejected() {
+        let mut nodes = chain(None);
+        nodes[0].explicit_id = Some(Uuid::from_u128(42));
+        nodes[2].explicit_id = Some(Uuid::from_u128(42));
+        assert_eq!(assign_ids(nodes).err(), Some(GraphError::DuplicateId(Uuid::from_u128(42), String::from("a"), String::from("c"))));
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        let mut nodes = chain(None);
+        nodes[2].name = String::from("a");
+        assert_eq!(assign_ids(nodes).err(), Some(GraphError::DuplicateName(String::from("a"))));
+    }
+
+    #[test]
+    fn cycles_are_rejected() {
+        // a -> b -> c -> b
+        let nodes = vec![
+            node(1, "a", None, vec![2]),
+            node(2, "b", None, vec![3]),
+            node(3, "c", None, vec![2]),
+        ];
+        assert_eq!(assign_ids(nodes).err(), Some(GraphError::Cycle(String::from("b"))));
+    }
+
+    #[test]
+    fn namespaced_ids_are_derived_from_the_explicit_id() {
+        assert_eq!(namespaced("ns", "a", None), (String::from("ns/a"), None));
+        let (name, id) = namespaced("ns", "a", Some(Uuid::from_u128(42)));
+        assert_eq!(name, "ns/a");
+        assert_ne!(id, Some(Uuid::from_u128(42)));
+        assert_eq!(id, namespaced("ns", "b", Some(Uuid::from_u128(42))).1);
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+use serde::Serialize;
+
+// *******************************
+// graph introspection.
+// A read-only copy of the structure of a graph, as built by its executor (i.e. after optimization,
+// and only with the nodes the sink reads from). The view does not hold on the graph, so it can be
+// serialized, diffed or checked by tooling.
+// *******************************
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeView {
+    pub id: Uuid,
+    pub name: String,
+    // a node reading twice from the same parent lists it twice.
+    pub parents: Vec<Uuid>,
+    pub children: Vec<Uuid>,
+    // number of nodes between this node and the furthest source (0 for a source).
+    pub depth: usize,
+    // only set by the io graph.
+    pub io: Option<IoNodeView>,
+}
+
+impl NodeView {
+    pub fn fan_in(&self) -> usize {
+        return self.parents.len();
+    }
+
+    pub fn fan_out(&self) -> usize {
+        return self.children.len();
+    }
+}
+
+// fork/join metadata of a node of the io graph.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct IoNodeView {
+    // the node has several children, each of them starts a new segment.
+    pub forkable: bool,
+    // a node with several parents joins their segments.
+    pub n_parents: i32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GraphView {
+    // in topological order, parents first.
+    nodes: Vec<NodeView>,
+}
+
+impl GraphView {
+    // (id, name, parent ids) of every node. nodes are ordered by id before sorting them topologically,
+    // ids being deterministic so is the view.
+    pub (in crate::graph) fn new(mut nodes: Vec<(Uuid, String, Vec<Uuid>)>) -> GraphView {
+        nodes.sort_by_key(|(id, _, _)| *id);
+
+        let mut id_to_children: HashMap<Uuid, Vec<Uuid>> = nodes.iter().map(|(id, _, _)| (*id, vec![])).collect();
+        for (id, _, parents) in nodes.iter() {
+            for parent in parents {
+                id_to_children.get_mut(parent).unwrap().push(*id);
+            }
+        }
+
+        // kahn's algorithm, the depth of a node is settled once all its parents are.
+        let mut n_missing_parents: HashMap<Uuid, usize> = nodes.iter().map(|(id, _, parents)| (*id, parents.len())).collect();
+        let mut depths: HashMap<Uuid, usize> = HashMap::new();
+        let mut ready: VecDeque<Uuid> = nodes.iter().filter(|(_, _, parents)| parents.is_empty()).map(|(id, _, _)| *id).collect();
+        let mut order = vec![];
+        while let Some(id) = ready.pop_front() {
+            order.push(id);
+            let depth = *depths.entry(id).or_insert(0);
+            for child in id_to_children[&id].iter() {
+                let child_depth = depths.entry(*child).or_insert(0);
+                *child_depth = (*child_depth).max(depth + 1);
+                let n_missing = n_missing_parents.get_mut(child).unwrap();
+                *n_missing -= 1;
+                if *n_missing == 0 {
+                    ready.push_back(*child);
+                }
+            }
+        }
+
+        let mut id_to_node: HashMap<Uuid, (String, Vec<Uuid>)> = nodes.into_iter().map(|(id, name, parents)| (id, (name, parents))).collect();
+        let nodes = order.iter().map(|id| {
+            let (name, parents) = id_to_node.remove(id).unwrap();
+            NodeView {
+                id: *id,
+                name,
+                parents,
+                children: id_to_children.remove(id).unwrap(),
+                depth: depths[id],
+                io: None,
+            }
+        }).collect();
+        return GraphView { nodes };
+    }
+
+    pub (in crate::graph) fn set_io(&mut self, id: Uuid, io: IoNodeView) {
+        if let Some(node) = self.nodes.iter_mut().find(|node| node.id == id) {
+            node.io = Some(io);
+        }
+    }
+
+    // every node, in topological order.
+    pub fn nodes(&self) -> &[NodeView] {
+        return &self.nodes;
+    }
+
+    pub fn node(&self, name: &str) -> Option<&NodeView> {
+        return self.nodes.iter().find(|node| node.name == name);
+    }
+
+    pub fn node_by_id(&self, id: &Uuid) -> Option<&NodeView> {
+        return self.nodes.iter().find(|node| node.id == *id);
+    }
+
+    // every edge, as (parent, child).
+    pub fn edges(&self) -> Vec<(Uuid, Uuid)> {
+        return self.nodes.iter()
+            .flat_map(|node| node.parents.iter().map(move |parent| (*parent, node.id)))
+            .collect();
+    }
+
+    // node names, parents first.
+    pub fn topological_order(&self) -> Vec<String> {
+        return self.nodes.iter().map(|node| node.name.clone()).collect();
+    }
+
+    pub fn sources(&self) -> Vec<&NodeView> {
+        return self.nodes.iter().filter(|node| node.parents.is_empty()).collect();
+    }
+
+    pub fn sinks(&self) -> Vec<&NodeView> {
+        return self.nodes.iter().filter(|node| node.children.is_empty()).collect();
+    }
+
+    // number of nodes on the longest path.
+    pub fn depth(&self) -> usize {
+        return self.nodes.iter().map(|node| node.depth + 1).max().unwrap_or(0);
+    }
+
+    // the longest path from a source to a sink, by node name (the critical path when every node costs the same).
+    pub fn longest_path(&self) -> Vec<String> {
+        let mut path = vec![];
+        let mut current = self.nodes.iter().max_by_key(|node| node.depth);
+        while let Some(node) = current {
+            path.push(node.name.clone());
+            current = node.parents.iter()
+                .filter_map(|parent| self.node_by_id(parent))
+                .find(|parent| parent.depth + 1 == node.depth);
+        }
+        path.reverse();
+        return path;
+    }
+
+    pub fn to_json(&self) -> String {
+        return serde_json::to_string(self).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use crate::graph::easy_functions;
+    use crate::graph::serial::{ComputeGraph, GraphNode};
+    use crate::graph::concurrent::ConcurrentGraphNode;
+    use crate::graph::io_graph::IoConcurrentComputeGraph;
+
+    // start -> left -> left 2 -> last
+    //       \-> right ---------/
+    fn graph() -> ComputeGraph<f64> {
+        let last = Rc::new(GraphNode::new(easy_functions::square, String::from("last"), vec![]));
+        let left2 = Rc::new(GraphNode::new(easy_functions::add_one, String::from("left 2"), vec![Rc::clone(&last)]));
+        let left = Rc::new(GraphNode::new(easy_functions::add_one, String::from("left"), vec![left2]));
+        let right = Rc::new(GraphNode::new(easy_functions::add_five, String::from("right"), vec![last]));
+        return ComputeGraph::new(Rc::new(GraphNode::new(easy_functions::add_one, String::from("start"), vec![left, right]))).unwrap();
+    }
+
+    fn names(nodes: Vec<&NodeView>) -> Vec<&str> {
+        return nodes.iter().map(|node| node.name.as_str()).collect();
+    }
+
+    #[test]
+    fn view_follows_the_graph() {
+        let view = graph().view();
+        assert_eq!(view.nodes().len(), 5);
+        assert_eq!(view.edges().len(), 5);
+        assert_eq!(names(view.sources()), vec!["start"]);
+        assert_eq!(names(view.sinks()), vec!["last"]);
+        assert_eq!(view.depth(), 4);
+        assert_eq!(view.longest_path(), vec!["start", "left", "left 2", "last"]);
+
+        let start = view.node("start").unwrap();
+        let last = view.node("last").unwrap();
+        assert_eq!((start.fan_in(), start.fan_out(), start.depth), (0, 2, 0));
+        assert_eq!((last.fan_in(), last.fan_out(), last.depth), (2, 0, 3));
+        assert_eq!(view.node_by_id(&last.id).unwrap().name, "last");
+        assert!(view.node("missing").is_none());
+    }
+
+    #[test]
+    fn topological_order_puts_parents_first() {
+        let view = graph().view();
+        let order = view.topological_order();
+        let position = |name: &str| order.iter().position(|node| node == name).unwrap();
+        for (parent, child) in view.edges() {
+            assert!(position(&view.node_by_id(&parent).unwrap().name) < position(&view.node_by_id(&child).unwrap().name));
+        }
+        // ids are deterministic, so is the view.
+        assert_eq!(order, graph().view().topological_order());
+        assert_eq!(view.to_json(), graph().view().to_json());
+    }
+
+    #[test]
+    fn io_view_tells_forks_and_joins() {
+        let last = Arc::new(ConcurrentGraphNode::new(easy_functions::square, String::from("last"), vec![]));
+        let left = Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("left"), vec![Arc::clone(&last)]));
+        let right = Arc::new(ConcurrentGraphNode::new(easy_functions::add_five, String::from("right"), vec![last]));
+        let start = Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("start"), vec![left, right]));
+        let view = IoConcurrentComputeGraph::new(start).unwrap().view();
+
+        let io = |name: &str| view.node(name).unwrap().io.as_ref().map(|io| (io.forkable, io.n_parents));
+        assert_eq!(io("start"), Some((true, 0)));
+        assert_eq!(io("left"), Some((false, 1)));
+        assert_eq!(io("last"), Some((false, 2)));
+
+        let json: serde_json::Value = serde_json::from_str(&view.to_json()).unwrap();
+        assert_eq!(json["nodes"].as_array().unwrap().len(), 4);
+    }
+}
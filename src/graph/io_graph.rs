@@ -1,33 +1,165 @@
 use std::collections::{VecDeque, HashMap, HashSet};
 use std::fmt;
 use uuid::Uuid;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use atomic_refcell;
-use std::thread::{spawn, JoinHandle};
+use std::thread::{self, spawn, JoinHandle};
+use std::panic;
+use std::sync::mpsc::Receiver;
+use log::debug;
 
 pub use crate::graph::GraphLikeFunc;
+use crate::graph::GraphError;
 use crate::graph::concurrent::{ConcurrentGraphNode, ConcurrentInternalGraphNode, ConcurrentTmpInternalGraphNode};
+use crate::graph::optimizer::OptimizationReport;
+use crate::graph::trace::{self, NodeRecord, NodeRecords, SegmentRecord, Trace};
+use crate::graph::metrics::MetricsRegistry;
+use crate::graph::introspection::{GraphView, IoNodeView};
+use crate::graph::routing::Route;
+use crate::graph::limits::{Limiter, NodeLimits};
+use crate::graph::priority_pool::{Batch, PriorityPool};
+use std::time::{Duration, Instant};
 
 
 // exposed graph structure
 pub struct IoConcurrentComputeGraph<T> where T: Clone {
-    pub root: Arc<ConcurrentGraphNode<T>>,
     internal_root: Arc<IoInternalGraphNode<T>>,
+    metrics: Arc<MetricsRegistry>,
+    view: GraphView,
+    // shared with the segments of every call to apply, which may still run when a limit is set.
+    limits: Arc<RwLock<Limits>>,
+    // segments run on a thread each, unless the graph is given a number of workers.
+    pool: Option<PriorityPool>,
+    // declared cost of the nodes, overriding their mean latency when ranking segments.
+    costs: HashMap<Uuid, Duration>,
 }
 
+type Limits = HashMap<Uuid, Arc<Limiter>>;
+
 impl <T> IoConcurrentComputeGraph<T> where T: Clone + Send + Sync + Copy + fmt::Display + fmt::Debug + 'static  {
-    pub fn new(root: Arc<ConcurrentGraphNode<T>>) -> IoConcurrentComputeGraph<T> {
-        return IoConcurrentComputeGraph {
-            root: Arc::clone(&root),
-            internal_root: IoInternalGraphNode::from(Arc::clone(&root)),
+    // fails when the graph is not valid (see `GraphError`).
+    pub fn new(root: Arc<ConcurrentGraphNode<T>>) -> Result<IoConcurrentComputeGraph<T>, GraphError> {
+        let concurrent_node = ConcurrentInternalGraphNode::from(ConcurrentTmpInternalGraphNode::to_internal_graph_node(Arc::clone(&root))?);
+        return IoConcurrentComputeGraph::from_internal(root, concurrent_node);
+    }
+
+    // same as `new`, but merges duplicated computations, so that they are only forked once.
+    pub fn optimized(root: Arc<ConcurrentGraphNode<T>>) -> Result<(IoConcurrentComputeGraph<T>, OptimizationReport), GraphError> {
+        let tmp_sink = ConcurrentTmpInternalGraphNode::to_internal_graph_node(Arc::clone(&root))?;
+        let report = tmp_sink.optimize(&root)?;
+        let graph = IoConcurrentComputeGraph::from_internal(root, ConcurrentInternalGraphNode::from(tmp_sink))?;
+        return Ok((graph, report));
+    }
+
+    fn from_internal(root: Arc<ConcurrentGraphNode<T>>, concurrent_node: Arc<ConcurrentInternalGraphNode<T>>) -> Result<IoConcurrentComputeGraph<T>, GraphError> {
+        let nodes = ConcurrentInternalGraphNode::index_by_id(&concurrent_node).values()
+            .map(|node| (node.id, node.name.clone()))
+            .collect();
+        let internal_root = IoInternalGraphNode::from(root, Arc::clone(&concurrent_node))?;
+        let mut view = ConcurrentInternalGraphNode::view(&concurrent_node);
+        for node in IoInternalGraphNode::all_nodes(&internal_root) {
+            view.set_io(node.id, IoNodeView { forkable: node.forkable, n_parents: node.n_parents });
+        }
+        return Ok(IoConcurrentComputeGraph {
+            internal_root,
+            metrics: Arc::new(MetricsRegistry::new(nodes)),
+            view,
+            limits: Arc::new(RwLock::new(HashMap::new())),
+            pool: None,
+            costs: HashMap::new(),
+        });
+    }
+
+    // caps the calls in flight and the rate of the given node, across every concurrent call to `apply`.
+    // panics if no node the sink reads from has this name, or if the rate is not positive and finite.
+    pub fn limit(&mut self, node_name: &str, limits: NodeLimits) {
+        let node = self.view.node(node_name)
+            .unwrap_or_else(|| panic!("no node named {} in the graph", node_name));
+        let limiter = Arc::new(Limiter::new(limits));
+        self.limits.write().unwrap().insert(node.id, limiter);
+    }
+
+    // runs the segments on `workers` threads instead of a thread each. When more segments are ready than there
+    // are workers, the segment with the longest remaining critical path (to the sink) runs first.
+    pub fn set_workers(&mut self, workers: usize) {
+        self.pool = Some(PriorityPool::new(workers));
+    }
+
+    // the cost of a node is its mean latency so far, unless declared here.
+    // panics if no node the sink reads from has this name.
+    pub fn set_cost(&mut self, node_name: &str, cost: Duration) {
+        let node = self.view.node(node_name)
+            .unwrap_or_else(|| panic!("no node named {} in the graph", node_name));
+        self.costs.insert(node.id, cost);
+    }
+
+    // for every node, the cost of the longest path from it to the sink, in micro seconds.
+    // a node that was never called and has no declared cost costs 1 micro second.
+    fn priorities(&self) -> HashMap<Uuid, u64> {
+        let cost = |id: &Uuid| -> u64 {
+            let cost = self.costs.get(id).cloned().or_else(|| self.metrics.node(id).mean_latency());
+            return cost.map_or(1, |cost| (cost.as_micros() as u64).max(1));
+        };
+        // nodes come after their parents in the view, walk it backwards.
+        let mut remaining: HashMap<Uuid, u64> = HashMap::new();
+        for node in self.view.nodes().iter().rev() {
+            let longest_child = node.children.iter().filter_map(|child| remaining.get(child)).max().cloned().unwrap_or(0);
+            remaining.insert(node.id, cost(&node.id) + longest_child);
         }
+        return remaining;
+    }
+
+    // read-only structure of the graph, along with where it forks and joins.
+    pub fn view(&self) -> GraphView {
+        return self.view.clone();
+    }
+
+    // calls, errors and latencies of the graph and of every node, and the time segments wait for a thread.
+    pub fn metrics(&self) -> Arc<MetricsRegistry> {
+        return Arc::clone(&self.metrics);
     }
 
     pub fn apply(&self, datum: Vec<T>) -> Vec<T> {
-        return IoInternalGraphNode::schedule_bfs(Arc::clone(&self.internal_root), datum);
+        return self.metrics.graph.measure(|| {
+            IoInternalGraphNode::schedule_bfs(self, datum, false).output
+        });
+    }
+
+    // same as `apply`, along with the input, output and duration of every node, and the timeline of every segment.
+    pub fn apply_traced(&self, datum: Vec<T>) -> Trace<T> {
+        return self.metrics.graph.measure(|| {
+            IoInternalGraphNode::schedule_bfs(self, datum, true)
+        });
     }
 }
 
+// what a segment produced, along with its records when traced.
+struct SegmentOutput<T> {
+    // None when the segment did not fire (see `routing`).
+    result: Option<Vec<T>>,
+    records: Vec<(String, NodeRecord<T>)>,
+    segment: Option<SegmentRecord>,
+}
+
+// a forked segment, running on its own thread or queued on the pool.
+enum SegmentHandle<T> {
+    Thread(JoinHandle<SegmentOutput<T>>),
+    Pooled(Receiver<thread::Result<SegmentOutput<T>>>),
+}
+
+impl <T> SegmentHandle<T> {
+    // a panic in the segment is propagated to the caller of apply.
+    fn join(self) -> SegmentOutput<T> {
+        return match self {
+            SegmentHandle::Thread(handle) => handle.join().unwrap(),
+            SegmentHandle::Pooled(receiver) => receiver.recv().unwrap().unwrap_or_else(|err| panic::resume_unwind(err)),
+        };
+    }
+}
+
+// the chain of nodes a segment runs on its own thread.
+type ExecPlan<T> = Vec<Arc<IoInternalGraphNode<T>>>;
+
 type ConcurrentParentRefs<T> = atomic_refcell::AtomicRefCell<Vec<Arc<IoInternalGraphNode<T>>>>;
 
 // for the internal structure each node points to its parents.
@@ -36,7 +168,10 @@ type ConcurrentParentRefs<T> = atomic_refcell::AtomicRefCell<Vec<Arc<IoInternalG
 struct IoInternalGraphNode<T> {
     f: GraphLikeFunc<T>,
     name: String,
+    route: Route<T>,
     children: ConcurrentParentRefs<T>,
+    // for every child, its index among the children of the user node (what a switch selects).
+    branches: atomic_refcell::AtomicRefCell<Vec<usize>>,
     id: Uuid,
     n_parents: i32,
     forkable: bool,
@@ -53,31 +188,54 @@ impl <T> IoInternalGraphNode<T> where T: Clone + Send + Sync + Copy + fmt::Displ
     // ********************
     // Scheduling Region
     // ********************
-    fn schedule_bfs(root: Arc<IoInternalGraphNode<T>>, datum: Vec<T>) -> Vec<T> {
+    // records are only kept when traced, the trace is otherwise empty.
+    fn schedule_bfs(graph: &IoConcurrentComputeGraph<T>, datum: Vec<T>, traced: bool) -> Trace<T> {
+        let root = Arc::clone(&graph.internal_root);
+        let started = Instant::now();
+        let priorities = graph.priorities();
+        let mut records = NodeRecords::new();
+        let mut segments = vec![];
         let mut nodes = HashMap::new();
         let mut bfs_q = VecDeque::new();
-        let mut uuid_to_handles: HashMap<Uuid, Vec<JoinHandle<Vec<T>>>> = HashMap::new();
+        let mut uuid_to_handles: HashMap<Uuid, Vec<SegmentHandle<T>>> = HashMap::new();
         let mut scheduled_nodes = HashSet::new();
 
         nodes.insert(root.id, Arc::clone(&root));
         bfs_q.push_back(Arc::clone(&root));
 
-        let mut results = datum.clone();
-        while bfs_q.len() != 0 {
-            let node = bfs_q.pop_front().unwrap();
+        // the datum flowing out of the last join, shared by every segment forked from it.
+        // None when no segment reaching the join fired.
+        let mut results = Some(Arc::new(datum));
+        while let Some(node) = bfs_q.pop_front() {
 
             // 1. if node is of type join, wait until all computation is done.
-            if node.n_parents > 1 || node.forkable {
+            // the sink is always joined, so that its segment ends up in the result.
+            // a sink with several parents is joined like any other join, once every segment reaching it is scheduled.
+            let is_sink = node.children.borrow().is_empty();
+            if node.n_parents > 1 || node.forkable || is_sink {
                 let handle_opt = uuid_to_handles.get(&node.id);
 
                 // check if all computation upstream has been scheduled
-                if handle_opt.is_some() && (node.forkable || (node.n_parents > 1 && handle_opt.unwrap().len() as i32 == node.n_parents)) {
-                    results.clear();
+                let single_parent_sink = is_sink && node.n_parents <= 1;
+                if handle_opt.is_some() && (node.forkable || single_parent_sink || (node.n_parents > 1 && handle_opt.unwrap().len() as i32 == node.n_parents)) {
+                    let mut joined: Option<Vec<T>> = None;
                     let remove = uuid_to_handles.remove(&node.id).unwrap();
                     for handle in remove {
-                        let result = handle.join().unwrap();
-                        results.extend(result);
+                        let output = handle.join();
+                        // the first segment that fired is taken as is, not copied.
+                        joined = match (joined, output.result) {
+                            (Some(mut xs), Some(result)) => {
+                                xs.extend(result);
+                                Some(xs)
+                            }
+                            (xs, result) => xs.or(result),
+                        };
+                        for (name, record) in output.records {
+                            trace::record(&mut records, &name, record);
+                        }
+                        segments.extend(output.segment);
                     }
+                    results = joined.map(Arc::new);
                 }
             }
 
@@ -89,49 +247,145 @@ impl <T> IoInternalGraphNode<T> where T: Clone + Send + Sync + Copy + fmt::Displ
             }
 
             // 2. fork if possible and propagate the result downstream.
-            for child in node.children.borrow().iter() {
-                let (last_node, exec_plan) = IoInternalGraphNode::to_exec_plan(Arc::clone(&child));
-                let handle = IoInternalGraphNode::async_exec(exec_plan, results.clone());
-                let removed = uuid_to_handles.remove(&last_node.id);
-                if removed.is_none() {
-                    uuid_to_handles.insert(last_node.id, vec![handle]);
-                } else {
-                    let mut handles = removed.unwrap();
-                    handles.push(handle);
-                    uuid_to_handles.insert(last_node.id, handles);
-                }
+            // branches that do not fire are still forked, so that the joins downstream know not to wait for them.
+            // on a pool, the segments are queued together so that they are picked by priority.
+            let mut batch = graph.pool.as_ref().map(PriorityPool::batch);
+            for (child, branch) in node.children.borrow().iter().zip(node.branches.borrow().iter()) {
+                let (last_node, exec_plan) = IoInternalGraphNode::to_exec_plan(Arc::clone(child));
+                let input = results.as_ref().filter(|xs| node.route.fires(xs, *branch)).map(Arc::clone);
+                let handle = IoInternalGraphNode::async_exec(exec_plan, input, traced, started, graph, batch.as_mut(), priorities[&child.id]);
+                uuid_to_handles.entry(last_node.id).or_default().push(handle);
                 bfs_q.push_back(Arc::clone(&last_node));
             }
 
         }
-        return results;
+        return Trace {
+            output: results.map_or_else(Vec::new, |results| Arc::try_unwrap(results).unwrap_or_else(|shared| (*shared).clone())),
+            nodes: records,
+            segments,
+        };
     }
 
-    fn async_exec(name_and_funcs: Vec<(String, GraphLikeFunc<T>)>, datum: Vec<T>) -> JoinHandle<Vec<T>> {
-        let datum_clone = datum.clone();
-        return spawn(move || {
-            let mut result = datum_clone.clone();
-            let names = name_and_funcs.clone();
-            for (name, f) in name_and_funcs {
-                result = f(result.clone());
+    // the segment runs on its own thread, or is queued on the pool of the graph with the given priority.
+    fn async_exec(exec_plan: ExecPlan<T>,
+                  datum: Option<Arc<Vec<T>>>,
+                  traced: bool,
+                  started: Instant,
+                  graph: &IoConcurrentComputeGraph<T>,
+                  batch: Option<&mut Batch>,
+                  priority: u64) -> SegmentHandle<T> {
+        let metrics = Arc::clone(&graph.metrics);
+        let limits = Arc::clone(&graph.limits);
+        let forked = Instant::now();
+        let run = move || IoInternalGraphNode::run_segment(exec_plan, datum, traced, started, forked, &metrics, &limits);
+        return match batch {
+            Some(batch) => SegmentHandle::Pooled(batch.spawn(priority, run)),
+            None => SegmentHandle::Thread(spawn(run)),
+        };
+    }
+
+    // the segment borrows the datum it was forked with, only the outputs of its nodes are allocated.
+    // a segment forked without a datum, or whose nodes stop routing it, outputs None.
+    fn run_segment(exec_plan: ExecPlan<T>,
+                   datum: Option<Arc<Vec<T>>>,
+                   traced: bool,
+                   started: Instant,
+                   forked: Instant,
+                   metrics: &MetricsRegistry,
+                   limits: &RwLock<Limits>) -> SegmentOutput<T> {
+        metrics.queue_wait.observe(forked.elapsed());
+        let segment_start = Instant::now();
+        let mut result: Option<Vec<T>> = None;
+        let mut fired = datum.is_some();
+        let mut records = vec![];
+        for (i, node) in exec_plan.iter().enumerate() {
+            if !fired {
+                break;
             }
-            println!("exec plan {:?}, before{:?}, after{:?}", names, datum_clone, result);
-            return result;
-        });
+            let xs: &[T] = match (&result, &datum) {
+                (Some(xs), _) => xs,
+                (None, Some(datum)) => datum,
+                (None, None) => unreachable!(),
+            };
+            let f = node.f;
+            let node_metrics = metrics.node(&node.id);
+            // the lock is not held while waiting for the limiter.
+            let limiter = limits.read().unwrap().get(&node.id).cloned();
+            let permit = limiter.as_ref().map(|limiter| {
+                let waited = Instant::now();
+                let permit = limiter.acquire();
+                node_metrics.limit_wait.observe(waited.elapsed());
+                permit
+            });
+            let start = Instant::now();
+            let output = node_metrics.measure(|| f(xs));
+            drop(permit);
+            if traced {
+                records.push((node.name.clone(), NodeRecord {
+                    input: xs.to_vec(),
+                    output: output.clone(),
+                    start: start.duration_since(started),
+                    duration: start.elapsed(),
+                    thread: trace::current_thread_id(),
+                }));
+            }
+            // within a segment, a node has a single child.
+            if i + 1 < exec_plan.len() {
+                fired = node.route.fires(&output, node.branches.borrow()[0]);
+            }
+            result = Some(output);
+        }
+        let names: Vec<String> = exec_plan.iter().map(|node| node.name.clone()).collect();
+        let result = if fired {
+            result.or_else(|| datum.as_ref().map(|datum| datum.to_vec()))
+        } else {
+            None
+        };
+        debug!("exec plan {:?}, before{:?}, after{:?}", names, datum, result);
+
+        let segment = if traced {
+            Some(SegmentRecord {
+                nodes: names,
+                start: segment_start.duration_since(started),
+                duration: segment_start.elapsed(),
+                thread: trace::current_thread_id(),
+            })
+        } else {
+            None
+        };
+        return SegmentOutput { result, records, segment };
+    }
+
+    // every node reachable from the root.
+    fn all_nodes(root: &Arc<IoInternalGraphNode<T>>) -> Vec<Arc<IoInternalGraphNode<T>>> {
+        let mut nodes = vec![];
+        let mut visited = HashSet::new();
+        let mut bfs_q = VecDeque::new();
+        bfs_q.push_back(Arc::clone(root));
+        while let Some(node) = bfs_q.pop_front() {
+            if !visited.insert(node.id) {
+                continue;
+            }
+            for child in node.children.borrow().iter() {
+                bfs_q.push_back(Arc::clone(child));
+            }
+            nodes.push(node);
+        }
+        return nodes;
     }
 
-    fn to_exec_plan(node: Arc<IoInternalGraphNode<T>>) -> (Arc<IoInternalGraphNode<T>>, Vec<(String, GraphLikeFunc<T>)>) {
+    fn to_exec_plan(node: Arc<IoInternalGraphNode<T>>) -> (Arc<IoInternalGraphNode<T>>, ExecPlan<T>) {
 
-        let mut name_and_funcs: Vec<(String, GraphLikeFunc<T>)> = vec![];
+        let mut exec_plan: ExecPlan<T> = vec![];
         let mut last_node = Arc::clone(&node);
         let mut nodes = VecDeque::new();
 
         nodes.push_back(Arc::clone(&node));
 
-        while nodes.len() != 0 {
+        while !nodes.is_empty() {
             let node = nodes.pop_front().unwrap();
 
-            name_and_funcs.push((node.name.clone(), node.f));
+            exec_plan.push(Arc::clone(&node));
             last_node = Arc::clone(&node);
 
             if node.forkable || node.n_parents > 1 {
@@ -142,89 +396,193 @@ impl <T> IoInternalGraphNode<T> where T: Clone + Send + Sync + Copy + fmt::Displ
             }
         }
 
-        return (last_node , name_and_funcs)
+        return (last_node , exec_plan)
     }
 
     // *******************************
     // Building Internal Struct Region
     // *******************************
-    fn from(root: Arc<ConcurrentGraphNode<T>>) -> Arc<IoInternalGraphNode<T>> {
-
-        let new_root = IoInternalGraphNode {
-            f: root.f,
-            name: root.name.clone(),
-            id: root.id,
-            n_parents: 0,
-            forkable: root.children.len() > 1,
-            children: atomic_refcell::AtomicRefCell::new(vec![]),
-        };
-        let arced_new_root = Arc::new(new_root );
-
-        let concurrent_node = ConcurrentInternalGraphNode::from(ConcurrentTmpInternalGraphNode::to_internal_graph_node(Arc::clone(&root)));
-        let id_to_concurrent_node = IoInternalGraphNode::index_by_id(Arc::clone(&concurrent_node));
+    // the io graph is built from the internal graph (parents pointing), so that nodes the sink
+    // does not read from are never scheduled.
+    fn from(root: Arc<ConcurrentGraphNode<T>>, sink: Arc<ConcurrentInternalGraphNode<T>>) -> Result<Arc<IoInternalGraphNode<T>>, GraphError> {
+        let id_to_concurrent_node = ConcurrentInternalGraphNode::index_by_id(&sink);
+        let ids = ConcurrentGraphNode::assign_ids(&root)?;
+
+        // 1. invert the parent links, children are listed in the order the user graph is traversed.
+        let mut id_to_children_ids: HashMap<Uuid, Vec<(Uuid, usize)>> = HashMap::new();
+        for node in ConcurrentGraphNode::all_nodes(&root) {
+            let id = ids[&ConcurrentGraphNode::key(&node)];
+            if let Some(concurrent_node) = id_to_concurrent_node.get(&id) {
+                for (parent, branch) in concurrent_node.parents.iter().zip(concurrent_node.branches.iter()) {
+                    id_to_children_ids.entry(parent.id).or_default().push((id, *branch));
+                }
+            }
+        }
 
-        let mut bfs_q = VecDeque::new();
-        let mut io_nodes_bfs_q = VecDeque::new();
+        // 2. create the io nodes.
         let mut id_to_io_node: HashMap<Uuid, Arc<IoInternalGraphNode<T>>> = HashMap::new();
-        let mut visited_nodes: HashSet<Uuid> = HashSet::new();
-
-        bfs_q.push_back(Arc::clone(&root));
-        io_nodes_bfs_q.push_back(Arc::clone(&arced_new_root));
-        id_to_io_node.insert(arced_new_root.id, Arc::clone(&arced_new_root));
-
-        while bfs_q.len() != 0 {
-            let node = bfs_q.pop_front().unwrap();
-            let io_node = io_nodes_bfs_q.pop_front().unwrap();
-
-            for child in node.children.iter() {
-
-                // 1. update number of parents.
-                let n_parents = id_to_concurrent_node.get(&child.id).unwrap().parents.len() as i32;
-
-                // 2. go fetch or create the io node
-                let new_io_child = {
-                    if !id_to_io_node.contains_key(&child.id) {
-                        Arc::new(IoInternalGraphNode {
-                            f: child.f,
-                            name: child.name.clone(),
-                            id: child.id,
-                            n_parents,
-                            forkable: child.children.len() > 1,
-                            children: atomic_refcell::AtomicRefCell::new(vec![]),
-                        })
-                    } else {
-                        Arc::clone(&id_to_io_node.get(&child.id).unwrap())
-                    }
-                };
-
-                id_to_io_node.insert(child.id, Arc::clone(&new_io_child));
+        for node in id_to_concurrent_node.values() {
+            let n_children = id_to_children_ids.get(&node.id).map_or(0, |ids| ids.len());
+            id_to_io_node.insert(node.id, Arc::new(IoInternalGraphNode {
+                f: node.f,
+                name: node.name.clone(),
+                route: node.route,
+                id: node.id,
+                n_parents: node.parents.len() as i32,
+                forkable: n_children > 1,
+                children: atomic_refcell::AtomicRefCell::new(vec![]),
+                branches: atomic_refcell::AtomicRefCell::new(vec![]),
+            }));
+        }
 
-                // 3. update refs
-                io_node.children.borrow_mut().push(Arc::clone(&new_io_child));
-                if !visited_nodes.contains(&child.id) {
-                    bfs_q.push_back(Arc::clone(child));
-                    io_nodes_bfs_q.push_back(Arc::clone(&new_io_child));
-                }
-                visited_nodes.insert(child.id);
+        // 3. update refs
+        for (id, children_ids) in id_to_children_ids.iter() {
+            let io_node = id_to_io_node.get(id).unwrap();
+            for (child_id, branch) in children_ids {
+                io_node.children.borrow_mut().push(Arc::clone(id_to_io_node.get(child_id).unwrap()));
+                io_node.branches.borrow_mut().push(*branch);
             }
         }
 
-        return Arc::clone(&arced_new_root);
+        return Ok(Arc::clone(id_to_io_node.get(&ids[&ConcurrentGraphNode::key(&root)]).unwrap()));
     }
+}
 
-    fn index_by_id(node: Arc<ConcurrentInternalGraphNode<T>>) -> HashMap<Uuid, Arc<ConcurrentInternalGraphNode<T>>> {
-        let mut id_to_node = HashMap::new();
-        let mut bfs_q = VecDeque::new();
-        bfs_q.push_back(Arc::clone(&node));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc_counter::{self, LARGE};
+    use crate::graph::easy_functions;
 
-        while bfs_q.len() != 0 {
-            let node = bfs_q.pop_front().unwrap();
-            id_to_node.insert(node.id, Arc::clone(&node));
+    // large enough for every copy of the datum to be counted.
+    const DATUM_LEN: usize = LARGE;
 
-            for child in node.parents.iter() {
-                bfs_q.push_back(Arc::clone(child));
-            }
+    fn head(xs: &[f64]) -> Vec<f64> {
+        return xs.iter().take(1).cloned().collect();
+    }
+
+    // figure 1.2 of the readme (`main.rs::io_graph_example`), every node adds one.
+    fn figure_1_2() -> IoConcurrentComputeGraph<f64> {
+        let node = |name: &str, children: Vec<Arc<ConcurrentGraphNode<f64>>>| {
+            Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from(name), children))
+        };
+        let node8 = node("node 8", vec![]);
+        let node7 = node("node 7", vec![Arc::clone(&node8)]);
+        let node3 = node("node 3", vec![node8]);
+        let node5 = node("node 5", vec![Arc::clone(&node7)]);
+        let node6 = node("node 6", vec![node7]);
+        let node4 = node("node 4", vec![node5, node6]);
+        let node2 = node("node 2", vec![node3]);
+        return IoConcurrentComputeGraph::new(node("node 1", vec![node2, node4])).unwrap();
+    }
+
+    // three short branches and a chain of three nodes, reading from the root and joined by the sink.
+    fn short_and_long() -> IoConcurrentComputeGraph<f64> {
+        let sink = Arc::new(ConcurrentGraphNode::new(easy_functions::add_five, String::from("sink"), vec![]));
+        let long3 = Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("long 3"), vec![Arc::clone(&sink)]));
+        let long2 = Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("long 2"), vec![long3]));
+        let long1 = Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("long 1"), vec![long2]));
+        let mut children: Vec<_> = (1..4).map(|i| Arc::new(ConcurrentGraphNode::new(
+            easy_functions::add_one, format!("short {}", i), vec![Arc::clone(&sink)]))).collect();
+        children.push(long1);
+        return IoConcurrentComputeGraph::new(Arc::new(ConcurrentGraphNode::new(crate::graph::passthrough, String::from("root"), children))).unwrap();
+    }
+
+    fn priority(graph: &IoConcurrentComputeGraph<f64>, name: &str) -> u64 {
+        return graph.priorities()[&graph.view.node(name).unwrap().id];
+    }
+
+    #[test]
+    fn priorities_follow_the_remaining_critical_path() {
+        let mut graph = short_and_long();
+        // never called, every node costs 1 micro second.
+        assert_eq!(priority(&graph, "long 1"), 4);
+        assert_eq!(priority(&graph, "short 1"), 2);
+        assert_eq!(priority(&graph, "root"), 5);
+
+        graph.set_cost("short 1", Duration::from_secs(1));
+        assert_eq!(priority(&graph, "short 1"), 1_000_001);
+        assert_eq!(priority(&graph, "short 2"), 2);
+        assert_eq!(priority(&graph, "root"), 1_000_002);
+    }
+
+    #[test]
+    fn workers_do_not_change_the_output() {
+        let expected = short_and_long().apply(vec![1.0]);
+        let mut graph = short_and_long();
+        graph.set_workers(2);
+        for _ in 0..3 {
+            assert_eq!(graph.apply(vec![1.0]), expected);
         }
-        return id_to_node;
+        let mut graph = figure_1_2();
+        graph.set_workers(1);
+        assert_eq!(graph.apply(vec![1.0]), figure_1_2().apply(vec![1.0]));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn multi_parent_sink_waits_for_every_segment() {
+        // node 8 runs in the segment coming from node 3 ([4, 5]), and in the one coming from node 7,
+        // which reads both of its parents ([5, 6, 5, 6]).
+        let graph = figure_1_2();
+        assert_eq!(graph.apply(vec![1.0, 2.0]), vec![4.0, 5.0, 5.0, 6.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn traced_run_records_every_node() {
+        let trace = figure_1_2().apply_traced(vec![1.0, 2.0]);
+        assert_eq!(trace.output, vec![4.0, 5.0, 5.0, 6.0, 5.0, 6.0]);
+
+        let mut names: Vec<&String> = trace.nodes.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["node 2", "node 3", "node 4", "node 5", "node 6", "node 7", "node 8"]);
+        assert_eq!(trace.nodes["node 2"][0].input, vec![1.0, 2.0]);
+        assert_eq!(trace.nodes["node 2"][0].output, vec![2.0, 3.0]);
+        assert_eq!(trace.nodes["node 8"].len(), 2);
+        assert!(!trace.segments.is_empty());
+    }
+
+    #[test]
+    fn fan_out_does_not_copy_the_datum() {
+        // source -> 32 x head -> head (sink), the source is not called and the sink runs in every segment
+        // (see `schedule_bfs`).
+        let sink = Arc::new(ConcurrentGraphNode::new(head, String::from("sink"), vec![]));
+        let branches = (0..32).map(|i| {
+            Arc::new(ConcurrentGraphNode::new(head, format!("branch {}", i), vec![Arc::clone(&sink)]))
+        }).collect();
+        let graph = IoConcurrentComputeGraph::new(Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("source"), branches))).unwrap();
+        let datum = vec![1.0; DATUM_LEN];
+        let datum_bytes = DATUM_LEN * std::mem::size_of::<f64>();
+
+        // before: every forked segment got its own copy of the datum.
+        let (_, _, copied_bytes) = alloc_counter::measure(|| (0..32).map(|_| datum.clone()).collect::<Vec<_>>());
+        let (output, allocations, _) = alloc_counter::measure(|| graph.apply(datum));
+
+        assert_eq!(output, vec![1.0; 32]);
+        assert_eq!(allocations, 0);
+        assert!(copied_bytes >= 32 * datum_bytes);
+    }
+
+    #[test]
+    fn sink_output_is_not_copied() {
+        let sink = Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("sink"), vec![]));
+        let graph = IoConcurrentComputeGraph::new(Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("source"), vec![sink]))).unwrap();
+        let datum = vec![1.0; DATUM_LEN];
+
+        // only the output of the sink is allocated, by the segment running it: the calling thread joins it without a copy.
+        let (output, allocations, _) = alloc_counter::measure(|| graph.apply(datum));
+        assert_eq!(output[0], 2.0);
+        assert_eq!(allocations, 0);
+    }
+
+    #[test]
+    fn chain_graphs_return_the_output_of_their_sink() {
+        // add one -> add five -> square: the sink has a single parent. it used to be left unjoined,
+        // and the graph returned the datum it was given ([1.0]) instead of the output of the sink.
+        let square = Arc::new(ConcurrentGraphNode::new(crate::graph::easy_functions::square, String::from("square"), vec![]));
+        let add_five = Arc::new(ConcurrentGraphNode::new(crate::graph::easy_functions::add_five, String::from("add five"), vec![square]));
+        let add_one = Arc::new(ConcurrentGraphNode::new(crate::graph::easy_functions::add_one, String::from("add one"), vec![add_five]));
+        let graph = IoConcurrentComputeGraph::new(add_one).unwrap();
+        // the io graph forwards the datum of its root as is.
+        assert_eq!(graph.apply(vec![1.0]), vec![36.0]);
+    }
+}
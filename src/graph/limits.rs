@@ -0,0 +1,202 @@
+use std::sync::{Condvar, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+// *******************************
+// per node limits.
+// A node calling a service that cannot take unlimited load can be given a maximum number of calls
+// in flight (a semaphore) and a rate (a token bucket). Limits are held by the graph, so that they
+// hold across every concurrent call to `apply`.
+// *******************************
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NodeLimits {
+    pub max_in_flight: Option<usize>,
+    pub rate: Option<Rate>,
+}
+
+// `per_second` calls on average (positive and finite), with bursts of up to `burst` calls (at least one).
+#[derive(Debug, Clone, Copy)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+pub (in crate::graph) struct Limiter {
+    in_flight: Option<Semaphore>,
+    bucket: Option<Mutex<TokenBucket>>,
+}
+
+// the slot taken by a call, released on drop.
+pub (in crate::graph) struct Permit<'a> {
+    semaphore: Option<&'a Semaphore>,
+}
+
+impl Limiter {
+
+    // panics if the rate is not positive and finite: the bucket would never refill.
+    pub (in crate::graph) fn new(limits: NodeLimits) -> Limiter {
+        if let Some(rate) = limits.rate {
+            assert!(rate.per_second > 0. && rate.per_second.is_finite(), "invalid rate of {} calls per second", rate.per_second);
+        }
+        return Limiter {
+            in_flight: limits.max_in_flight.map(Semaphore::new),
+            bucket: limits.rate.map(|rate| Mutex::new(TokenBucket::new(rate))),
+        };
+    }
+
+    // blocks until the call is allowed, a token is only taken once a slot is.
+    pub (in crate::graph) fn acquire(&self) -> Permit<'_> {
+        if let Some(semaphore) = self.in_flight.as_ref() {
+            semaphore.acquire();
+        }
+        if let Some(bucket) = self.bucket.as_ref() {
+            loop {
+                // the lock is not held while sleeping.
+                let wait = bucket.lock().unwrap().take();
+                match wait {
+                    None => break,
+                    Some(wait) => sleep(wait),
+                }
+            }
+        }
+        return Permit { semaphore: self.in_flight.as_ref() };
+    }
+}
+
+impl <'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        if let Some(semaphore) = self.semaphore {
+            semaphore.release();
+        }
+    }
+}
+
+struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+
+    fn new(permits: usize) -> Semaphore {
+        return Semaphore {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        };
+    }
+
+    fn acquire(&self) {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+    }
+
+    fn release(&self) {
+        *self.available.lock().unwrap() += 1;
+        self.released.notify_one();
+    }
+}
+
+struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+
+    // starts full.
+    fn new(rate: Rate) -> TokenBucket {
+        return TokenBucket {
+            rate,
+            tokens: rate.burst.max(1) as f64,
+            refilled: Instant::now(),
+        };
+    }
+
+    // takes a token, or returns how long to wait until one is available.
+    fn take(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * self.rate.per_second;
+        self.tokens = (self.tokens + refill).min(self.rate.burst.max(1) as f64);
+        self.refilled = now;
+        if self.tokens >= 1. {
+            self.tokens -= 1.;
+            return None;
+        }
+        return Some(Duration::from_secs_f64((1. - self.tokens) / self.rate.per_second));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn no_limits_never_wait() {
+        let limiter = Limiter::new(NodeLimits::default());
+        let started = Instant::now();
+        let permits: Vec<Permit> = (0..100).map(|_| limiter.acquire()).collect();
+        assert_eq!(permits.len(), 100);
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn in_flight_calls_are_capped() {
+        let limiter = Arc::new(Limiter::new(NodeLimits { max_in_flight: Some(2), rate: None }));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_seen = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..8).map(|_| {
+            let (limiter, in_flight, max_seen) = (Arc::clone(&limiter), Arc::clone(&in_flight), Arc::clone(&max_seen));
+            thread::spawn(move || {
+                let _permit = limiter.acquire();
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_seen.fetch_max(now, Ordering::SeqCst);
+                sleep(Duration::from_millis(10));
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(max_seen.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn dropping_a_permit_releases_its_slot() {
+        let limiter = Limiter::new(NodeLimits { max_in_flight: Some(1), rate: None });
+        drop(limiter.acquire());
+        // would block forever if the first slot was still taken.
+        let _permit = limiter.acquire();
+    }
+
+    #[test]
+    fn rate_allows_a_burst_then_waits() {
+        let limiter = Limiter::new(NodeLimits { max_in_flight: None, rate: Some(Rate { per_second: 20., burst: 2 }) });
+        let started = Instant::now();
+        drop(limiter.acquire());
+        drop(limiter.acquire());
+        assert!(started.elapsed() < Duration::from_millis(25));
+        drop(limiter.acquire());
+        assert!(started.elapsed() >= Duration::from_millis(45));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid rate of 0 calls per second")]
+    fn rate_must_be_positive() {
+        Limiter::new(NodeLimits { max_in_flight: None, rate: Some(Rate { per_second: 0., burst: 1 }) });
+    }
+
+    #[test]
+    fn empty_bucket_tells_how_long_to_wait() {
+        let mut bucket = TokenBucket::new(Rate { per_second: 10., burst: 0 });
+        assert_eq!(bucket.take(), None);
+        let wait = bucket.take().unwrap();
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100));
+    }
+}
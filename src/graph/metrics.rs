@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+// *******************************
+// metrics registry.
+// Every counter is an atomic, recording a call never takes a lock, so that the registry can be shared
+// by all the threads calling a graph. The set of nodes is fixed when the graph is built.
+// *******************************
+
+// upper bounds of the latency buckets, in micro seconds. (an extra +Inf bucket is implied)
+pub const LATENCY_BUCKETS_MICROS: [u64; 12] = [10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000, 5_000_000];
+
+pub struct Histogram {
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistogramSnapshot {
+    // (upper bound in micro seconds, number of observations in the bucket), the last bound is u64::MAX.
+    pub buckets: Vec<(u64, u64)>,
+    pub sum_micros: u64,
+    pub count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        return Histogram::new();
+    }
+}
+
+impl Histogram {
+
+    pub fn new() -> Histogram {
+        return Histogram {
+            buckets: (0..LATENCY_BUCKETS_MICROS.len() + 1).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        };
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        let bucket = LATENCY_BUCKETS_MICROS.iter().position(|bound| micros <= *bound).unwrap_or(LATENCY_BUCKETS_MICROS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let bounds = LATENCY_BUCKETS_MICROS.iter().cloned().chain(std::iter::once(u64::MAX));
+        return HistogramSnapshot {
+            buckets: bounds.zip(self.buckets.iter()).map(|(bound, n)| (bound, n.load(Ordering::Relaxed))).collect(),
+            sum_micros: self.sum_micros.load(Ordering::Relaxed),
+            count: self.count.load(Ordering::Relaxed),
+        };
+    }
+}
+
+pub struct Metrics {
+    calls: AtomicU64,
+    errors: AtomicU64,
+    latency: Histogram,
+    // io graph nodes only: time spent waiting for a slot or a token (see `limits`).
+    pub (in crate::graph) limit_wait: Histogram,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    pub calls: u64,
+    pub errors: u64,
+    pub latency: HistogramSnapshot,
+    pub limit_wait: HistogramSnapshot,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        return Metrics::new();
+    }
+}
+
+impl Metrics {
+
+    pub fn new() -> Metrics {
+        return Metrics {
+            calls: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            latency: Histogram::new(),
+            limit_wait: Histogram::new(),
+        };
+    }
+
+    // runs `f`, a panic is counted as an error before being propagated.
+    pub fn measure<R, F>(&self, f: F) -> R where F: FnOnce() -> R {
+        let start = Instant::now();
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.latency.observe(start.elapsed());
+        return match result {
+            Ok(r) => r,
+            Err(err) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                panic::resume_unwind(err)
+            }
+        };
+    }
+
+    // None until the first call.
+    pub (in crate::graph) fn mean_latency(&self) -> Option<Duration> {
+        let count = self.latency.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return None;
+        }
+        return Some(Duration::from_micros(self.latency.sum_micros.load(Ordering::Relaxed) / count));
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        return MetricsSnapshot {
+            calls: self.calls.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            latency: self.latency.snapshot(),
+            limit_wait: self.limit_wait.snapshot(),
+        };
+    }
+}
+
+pub struct MetricsRegistry {
+    pub graph: Metrics,
+    nodes: HashMap<Uuid, (String, Metrics)>,
+    // io graph only: time between a segment being forked and its thread starting.
+    pub queue_wait: Histogram,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistrySnapshot {
+    pub graph: MetricsSnapshot,
+    // by node name.
+    pub nodes: BTreeMap<String, MetricsSnapshot>,
+    pub queue_wait: HistogramSnapshot,
+}
+
+impl MetricsRegistry {
+
+    pub (in crate::graph) fn new(nodes: Vec<(Uuid, String)>) -> MetricsRegistry {
+        return MetricsRegistry {
+            graph: Metrics::new(),
+            nodes: nodes.into_iter().map(|(id, name)| (id, (name, Metrics::new()))).collect(),
+            queue_wait: Histogram::new(),
+        };
+    }
+
+    pub (in crate::graph) fn node(&self, id: &Uuid) -> &Metrics {
+        return &self.nodes.get(id).unwrap().1;
+    }
+
+    pub fn snapshot(&self) -> RegistrySnapshot {
+        return RegistrySnapshot {
+            graph: self.graph.snapshot(),
+            nodes: self.nodes.values().map(|(name, metrics)| (name.clone(), metrics.snapshot())).collect(),
+            queue_wait: self.queue_wait.snapshot(),
+        };
+    }
+}
+
+// *******************************
+// prometheus export
+// ref: https://prometheus.io/docs/instrumenting/exposition_formats/
+// *******************************
+impl RegistrySnapshot {
+
+    pub fn to_prometheus(&self, graph_name: &str) -> String {
+        let graph_labels = format!("graph=\"{}\"", escape(graph_name));
+        let mut out = String::new();
+
+        out.push_str("# TYPE graph_calls_total counter\n");
+        out.push_str(&format!("graph_calls_total{{{}}} {}\n", graph_labels, self.graph.calls));
+        out.push_str("# TYPE graph_errors_total counter\n");
+        out.push_str(&format!("graph_errors_total{{{}}} {}\n", graph_labels, self.graph.errors));
+        out.push_str("# TYPE graph_latency_seconds histogram\n");
+        push_histogram(&mut out, "graph_latency_seconds", &graph_labels, &self.graph.latency);
+
+        out.push_str("# TYPE node_calls_total counter\n");
+        for (name, metrics) in self.nodes.iter() {
+            out.push_str(&format!("node_calls_total{{{},node=\"{}\"}} {}\n", graph_labels, escape(name), metrics.calls));
+        }
+        out.push_str("# TYPE node_errors_total counter\n");
+        for (name, metrics) in self.nodes.iter() {
+            out.push_str(&format!("node_errors_total{{{},node=\"{}\"}} {}\n", graph_labels, escape(name), metrics.errors));
+        }
+        out.push_str("# TYPE node_latency_seconds histogram\n");
+        for (name, metrics) in self.nodes.iter() {
+            let labels = format!("{},node=\"{}\"", graph_labels, escape(name));
+            push_histogram(&mut out, "node_latency_seconds", &labels, &metrics.latency);
+        }
+
+        out.push_str("# TYPE node_limit_wait_seconds histogram\n");
+        for (name, metrics) in self.nodes.iter() {
+            let labels = format!("{},node=\"{}\"", graph_labels, escape(name));
+            push_histogram(&mut out, "node_limit_wait_seconds", &labels, &metrics.limit_wait);
+        }
+
+        out.push_str("# TYPE queue_wait_seconds histogram\n");
+        push_histogram(&mut out, "queue_wait_seconds", &graph_labels, &self.queue_wait);
+        return out;
+    }
+
+    pub fn write_prometheus(&self, graph_name: &str, path: &str) -> io::Result<()> {
+        return fs::write(path, self.to_prometheus(graph_name));
+    }
+}
+
+fn push_histogram(out: &mut String, metric: &str, labels: &str, histogram: &HistogramSnapshot) {
+    let mut cumulative = 0;
+    for (bound, n) in histogram.buckets.iter() {
+        cumulative += n;
+        let le = if *bound == u64::MAX { String::from("+Inf") } else { format!("{}", *bound as f64 / 1e6) };
+        out.push_str(&format!("{}_bucket{{{},le=\"{}\"}} {}\n", metric, labels, le, cumulative));
+    }
+    out.push_str(&format!("{}_sum{{{}}} {}\n", metric, labels, histogram.sum_micros as f64 / 1e6));
+    out.push_str(&format!("{}_count{{{}}} {}\n", metric, labels, histogram.count));
+}
+
+fn escape(label: &str) -> String {
+    return label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::graph::concurrent::{ConcurrentComputeGraph, ConcurrentGraphNode};
+    use crate::graph::easy_functions;
+
+    #[test]
+    fn histogram_counts_observations_in_their_bucket() {
+        let histogram = Histogram::new();
+        histogram.observe(Duration::from_micros(5));
+        histogram.observe(Duration::from_micros(700));
+        histogram.observe(Duration::from_secs(10));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 3);
+        assert_eq!(snapshot.sum_micros, 10_000_705);
+        assert_eq!(snapshot.buckets[0], (10, 1));
+        assert_eq!(snapshot.buckets[4], (1_000, 1));
+        assert_eq!(snapshot.buckets[LATENCY_BUCKETS_MICROS.len()], (u64::MAX, 1));
+        assert_eq!(snapshot.buckets.iter().map(|(_, n)| n).sum::<u64>(), 3);
+    }
+
+    #[test]
+    fn panics_are_counted_as_errors() {
+        let metrics = Metrics::new();
+        assert_eq!(metrics.measure(|| 1), 1);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| metrics.measure(|| panic!("boom"))));
+        assert!(result.is_err());
+
+        let snapshot = metrics.snapshot();
+        assert_eq!((snapshot.calls, snapshot.errors, snapshot.latency.count), (2, 1, 2));
+    }
+
+    #[test]
+    fn graph_calls_are_recorded_per_node() {
+        let sink = Arc::new(ConcurrentGraphNode::new(easy_functions::square, String::from("sink"), vec![]));
+        let graph = ConcurrentComputeGraph::new(Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("source"), vec![sink]))).unwrap();
+        graph.apply(vec![1.0]);
+        graph.apply_batch(vec![vec![1.0], vec![2.0]]);
+
+        let snapshot = graph.metrics().snapshot();
+        assert_eq!(snapshot.graph.calls, 2);
+        assert_eq!(snapshot.nodes["source"].calls, 3);
+        assert_eq!(snapshot.nodes["sink"].calls, 3);
+    }
+
+    #[test]
+    fn prometheus_export_lists_every_node() {
+        let registry = MetricsRegistry::new(vec![(Uuid::nil(), String::from("say \"hi\""))]);
+        registry.graph.measure(|| ());
+        registry.node(&Uuid::nil()).measure(|| ());
+        let text = registry.snapshot().to_prometheus("demo");
+
+        assert!(text.contains("graph_calls_total{graph=\"demo\"} 1\n"));
+        assert!(text.contains("node_calls_total{graph=\"demo\",node=\"say \\\"hi\\\"\"} 1\n"));
+        assert!(text.contains("node_latency_seconds_bucket{graph=\"demo\",node=\"say \\\"hi\\\"\",le=\"+Inf\"} 1\n"));
+        assert!(text.contains("# TYPE queue_wait_seconds histogram\n"));
+
+        let path = std::env::temp_dir().join(format!("metrics_test_{}.prom", std::process::id()));
+        registry.snapshot().write_prometheus("demo", path.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), text);
+        fs::remove_file(path).unwrap();
+    }
+}
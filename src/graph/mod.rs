@@ -1,6 +1,52 @@
+use std::error::Error;
+use std::fmt;
+use uuid::Uuid;
+
 pub mod concurrent;
 pub mod serial;
 pub mod easy_functions;
 pub mod io_graph;
+pub mod optimizer;
+pub mod cache;
+pub mod trace;
+pub mod metrics;
+pub mod identity;
+pub mod introspection;
+pub mod routing;
+pub mod hybrid;
+pub mod limits;
+pub mod priority_pool;
+
+// nodes borrow their input, which is either the datum or the output of their parents.
+// a node that needs to mutate its input copies it first (`xs.to_vec()`).
+pub type GraphLikeFunc<T> = fn (xs: &[T]) -> Vec<T>;
+
+// forwards its input, used as the single entry of a subgraph with several sources.
+pub fn passthrough<T: Clone>(xs: &[T]) -> Vec<T> {
+    return xs.to_vec();
+}
+
+// why a graph could not be built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GraphError {
+    // nodes are looked up by name (`evaluate`, `enable_cache`, `limit`...), names must be unique within a graph.
+    DuplicateName(String),
+    // (id, name of the first node, name of the second node), one of them needs an explicit id.
+    DuplicateId(Uuid, String, String),
+    // a node that is part of a cycle, or reads from one (by name), graphs must be acyclic.
+    Cycle(String),
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GraphError::DuplicateName(name) => write!(f, "several nodes are named {}", name),
+            GraphError::DuplicateId(id, first, second) => {
+                write!(f, "nodes {} and {} have the same id {}, give one of them an explicit id", first, second, id)
+            }
+            GraphError::Cycle(name) => write!(f, "node {} is part of a cycle or reads from one", name),
+        }
+    }
+}
 
-pub type GraphLikeFunc<T> = fn (xs: Vec<T>) -> Vec<T>;
+impl Error for GraphError {}
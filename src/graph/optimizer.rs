@@ -0,0 +1,229 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use uuid::Uuid;
+
+// *******************************
+// graph optimizer.
+// Works on a flat description of the internal (parent pointing) graph so that every executor can share it.
+// Two passes are applied:
+// 1. dead node elimination: any node the sink does not read from is reported. executors only ever run
+//    what the sink reads, so this pass changes nothing to a run, it points at nodes left unwired.
+// 2. common sub-expression elimination: two nodes with the same operator on the same parents
+//    (in the same order, reading the same branches) always produce the same output, so only one of them is kept,
+//    provided they route it the same way. Nodes without an operator are never merged: function addresses
+//    can't tell whether two nodes compute the same thing.
+// The optimizer does not touch the graph itself, it returns a rewiring plan that each executor applies.
+// *******************************
+
+// what the optimizer changed, by node name.
+#[derive(Debug, Clone, Default)]
+pub struct OptimizationReport {
+    // (removed node, node it was merged into)
+    pub merged: Vec<(String, String)>,
+    // nodes that do not contribute to the sink. they would not run without the optimizer either.
+    pub pruned: Vec<String>,
+}
+
+impl fmt::Display for OptimizationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "merged: {:?}, pruned: {:?}", self.merged, self.pruned)
+    }
+}
+
+// flat view of an internal node, as seen by the optimizer.
+pub (in crate::graph) struct OptimizerNode {
+    pub (in crate::graph) id: Uuid,
+    pub (in crate::graph) name: String,
+    // see `GraphNode::with_operator`.
+    pub (in crate::graph) operator: Option<String>,
+    pub (in crate::graph) route: usize,
+    pub (in crate::graph) parents: Vec<Uuid>,
+    // which branch of each parent the node reads, when it matters (see `Route::branch_key`).
+    pub (in crate::graph) branches: Vec<usize>,
+}
+
+// given all nodes of the user graph, and the nodes reachable from the sink,
+// returns for each reachable node the id of the node that replaces it.
+pub (in crate::graph) fn plan(all_nodes: Vec<(Uuid, String)>,
+                              sink_id: Uuid,
+                              reachable: Vec<OptimizerNode>) -> (HashMap<Uuid, Uuid>, OptimizationReport) {
+    let mut report = OptimizationReport::default();
+
+    // 1. dead node elimination
+    let reachable_ids: HashSet<Uuid> = reachable.iter().map(|node| node.id).collect();
+    let mut seen = HashSet::new();
+    for (id, name) in all_nodes {
+        if !reachable_ids.contains(&id) && seen.insert(id) {
+            report.pruned.push(name);
+        }
+    }
+
+    // 2. common sub-expression elimination, parents always visited before their children.
+    let id_to_node: HashMap<Uuid, &OptimizerNode> = reachable.iter().map(|node| (node.id, node)).collect();
+    let mut replaced_by: HashMap<Uuid, Uuid> = HashMap::new();
+    let mut signature_to_id: HashMap<(String, usize, Vec<Uuid>, Vec<usize>), Uuid> = HashMap::new();
+
+    for id in topological_order(sink_id, &id_to_node) {
+        let node = id_to_node.get(&id).unwrap();
+        let parents: Vec<Uuid> = node.parents.iter().map(|parent| *replaced_by.get(parent).unwrap()).collect();
+        let operator = match &node.operator {
+            Some(operator) => operator.clone(),
+            None => {
+                replaced_by.insert(node.id, node.id);
+                continue;
+            }
+        };
+        let signature = (operator, node.route, parents, node.branches.clone());
+
+        match signature_to_id.get(&signature) {
+            Some(kept) => {
+                report.merged.push((node.name.clone(), id_to_node.get(kept).unwrap().name.clone()));
+                replaced_by.insert(node.id, *kept);
+            }
+            None => {
+                signature_to_id.insert(signature, node.id);
+                replaced_by.insert(node.id, node.id);
+            }
+        }
+    }
+    return (replaced_by, report);
+}
+
+// depth first post order from the sink, so that parents come first.
+fn topological_order(sink_id: Uuid, id_to_node: &HashMap<Uuid, &OptimizerNode>) -> Vec<Uuid> {
+    let mut order = vec![];
+    let mut visited = HashSet::new();
+    let mut stack = vec![(sink_id, false)];
+
+    while let Some((id, expanded)) = stack.pop() {
+        if expanded {
+            order.push(id);
+            continue;
+        }
+        if !visited.insert(id) {
+            continue;
+        }
+        stack.push((id, true));
+        for parent in id_to_node.get(&id).unwrap().parents.iter().rev() {
+            if !visited.contains(parent) {
+                stack.push((*parent, false));
+            }
+        }
+    }
+    return order;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+    use crate::graph::easy_functions;
+    use crate::graph::serial::{ComputeGraph, GraphNode};
+
+    fn node(name: &str, operator: Option<&str>, parents: &[Uuid]) -> OptimizerNode {
+        return OptimizerNode {
+            id: Uuid::new_v5(&Uuid::nil(), name.as_bytes()),
+            name: String::from(name),
+            operator: operator.map(String::from),
+            route: 0,
+            parents: parents.to_vec(),
+            branches: vec![0; parents.len()],
+        };
+    }
+
+    // source -> (left, right) -> sink
+    fn diamond(left: Option<&str>, right: Option<&str>) -> (Uuid, Vec<OptimizerNode>) {
+        let source = node("source", Some("add one"), &[]);
+        let left = node("left", left, &[source.id]);
+        let right = node("right", right, &[source.id]);
+        let sink = node("sink", Some("square"), &[left.id, right.id]);
+        return (sink.id, vec![source, left, right, sink]);
+    }
+
+    fn all_nodes(nodes: &[OptimizerNode]) -> Vec<(Uuid, String)> {
+        return nodes.iter().map(|node| (node.id, node.name.clone())).collect();
+    }
+
+    #[test]
+    fn merges_nodes_with_the_same_operator_and_parents() {
+        let (sink_id, nodes) = diamond(Some("add one"), Some("add one"));
+        let (replaced_by, report) = plan(all_nodes(&nodes), sink_id, nodes);
+
+        assert_eq!(report.merged, vec![(String::from("right"), String::from("left"))]);
+        assert!(report.pruned.is_empty());
+        let left = Uuid::new_v5(&Uuid::nil(), b"left");
+        let right = Uuid::new_v5(&Uuid::nil(), b"right");
+        assert_eq!(replaced_by[&right], left);
+        assert_eq!(replaced_by[&left], left);
+    }
+
+    #[test]
+    fn keeps_nodes_without_operator_or_with_different_ones() {
+        for (left, right) in [(None, None), (Some("add one"), None), (Some("add one"), Some("add five"))] {
+            let (sink_id, nodes) = diamond(left, right);
+            let (replaced_by, report) = plan(all_nodes(&nodes), sink_id, nodes);
+            assert!(report.merged.is_empty());
+            assert!(replaced_by.iter().all(|(id, kept)| id == kept));
+        }
+    }
+
+    #[test]
+    fn keeps_nodes_routing_differently() {
+        let (sink_id, mut nodes) = diamond(Some("add one"), Some("add one"));
+        nodes[2].route = 2;
+        let (_, report) = plan(all_nodes(&nodes), sink_id, nodes);
+        assert!(report.merged.is_empty());
+    }
+
+    #[test]
+    fn merges_children_of_merged_nodes() {
+        let source = node("source", None, &[]);
+        let a = node("a", Some("add one"), &[source.id]);
+        let b = node("b", Some("add one"), &[source.id]);
+        let a2 = node("a2", Some("square"), &[a.id]);
+        let b2 = node("b2", Some("square"), &[b.id]);
+        let sink = node("sink", None, &[a2.id, b2.id]);
+        let sink_id = sink.id;
+        let nodes = vec![source, a, b, a2, b2, sink];
+        let (_, report) = plan(all_nodes(&nodes), sink_id, nodes);
+
+        assert_eq!(report.merged, vec![
+            (String::from("b"), String::from("a")),
+            (String::from("b2"), String::from("a2")),
+        ]);
+    }
+
+    #[test]
+    fn prunes_nodes_the_sink_does_not_read() {
+        let (sink_id, nodes) = diamond(None, None);
+        let dangling = node("dangling", Some("add one"), &[]);
+        let mut all = all_nodes(&nodes);
+        all.push((dangling.id, dangling.name.clone()));
+        let (replaced_by, report) = plan(all, sink_id, nodes);
+
+        assert_eq!(report.pruned, vec![String::from("dangling")]);
+        assert!(!replaced_by.contains_key(&dangling.id));
+    }
+
+    #[test]
+    fn optimized_graph_computes_the_same_output() {
+        let last = Rc::new(GraphNode::new(easy_functions::square, String::from("last"), vec![]));
+        let mid1 = Rc::new(GraphNode::new(easy_functions::add_one, String::from("mid 1"), vec![Rc::clone(&last)])
+            .with_operator("add one"));
+        let mid2 = Rc::new(GraphNode::new(easy_functions::add_one, String::from("mid 2"), vec![Rc::clone(&last)])
+            .with_operator("add one"));
+        let start = Rc::new(GraphNode::new(easy_functions::add_one, String::from("start"),
+                                           vec![Rc::clone(&mid1), Rc::clone(&mid2)]));
+
+        let graph = ComputeGraph::new(Rc::clone(&start)).unwrap();
+        let (optimized, report) = ComputeGraph::optimized(Rc::clone(&start)).unwrap();
+
+        assert_eq!(report.merged.len(), 1);
+        let (removed, kept) = &report.merged[0];
+        let mut names = vec![removed.as_str(), kept.as_str()];
+        names.sort();
+        assert_eq!(names, vec!["mid 1", "mid 2"]);
+        assert_eq!(optimized.view().nodes().len(), 3);
+        assert_eq!(optimized.apply(vec![1.0, 2.0]), graph.apply(vec![1.0, 2.0]));
+    }
+}
@@ -0,0 +1,195 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread::{self, spawn, JoinHandle};
+
+// *******************************
+// priority pool.
+// A fixed set of threads running the queued task with the highest priority first,
+// tasks of the same priority run in the order they were queued.
+// *******************************
+pub (in crate::graph) struct PriorityPool {
+    queue: Arc<Queue>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+type Task = Box<dyn FnOnce() + Send>;
+
+struct Queue {
+    state: Mutex<QueueState>,
+    pushed: Condvar,
+}
+
+struct QueueState {
+    tasks: BinaryHeap<QueuedTask>,
+    seq: u64,
+    closed: bool,
+}
+
+// tasks queued together: workers only pick among them once they are all queued.
+pub (in crate::graph) struct Batch<'a> {
+    state: MutexGuard<'a, QueueState>,
+    pushed: &'a Condvar,
+}
+
+struct QueuedTask {
+    priority: u64,
+    seq: u64,
+    task: Task,
+}
+
+impl PriorityPool {
+
+    pub (in crate::graph) fn new(workers: usize) -> PriorityPool {
+        let queue = Arc::new(Queue {
+            state: Mutex::new(QueueState { tasks: BinaryHeap::new(), seq: 0, closed: false }),
+            pushed: Condvar::new(),
+        });
+        let workers = (0..workers.max(1)).map(|_| {
+            let queue = Arc::clone(&queue);
+            return spawn(move || {
+                while let Some(task) = queue.pop() {
+                    task();
+                }
+            });
+        }).collect();
+        return PriorityPool { queue, workers };
+    }
+
+    pub (in crate::graph) fn batch(&self) -> Batch<'_> {
+        return Batch {
+            state: self.queue.state.lock().unwrap(),
+            pushed: &self.queue.pushed,
+        };
+    }
+}
+
+impl <'a> Batch<'a> {
+    // the result (or the panic) of `f` is sent back on the returned receiver.
+    pub (in crate::graph) fn spawn<F, R>(&mut self, priority: u64, f: F) -> Receiver<thread::Result<R>>
+        where F: FnOnce() -> R + Send + 'static, R: Send + 'static {
+        let (sender, receiver) = channel();
+        let task: Task = Box::new(move || {
+            let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(f)));
+        });
+        self.state.seq += 1;
+        let seq = self.state.seq;
+        self.state.tasks.push(QueuedTask { priority, seq, task });
+        return receiver;
+    }
+}
+
+impl <'a> Drop for Batch<'a> {
+    fn drop(&mut self) {
+        self.pushed.notify_all();
+    }
+}
+
+impl Drop for PriorityPool {
+    // queued tasks are still run before the workers stop.
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().closed = true;
+        self.queue.pushed.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Queue {
+    // blocks until a task is queued, None once the pool is closed and drained.
+    fn pop(&self) -> Option<Task> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(queued) = state.tasks.pop() {
+                return Some(queued.task);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.pushed.wait(state).unwrap();
+        }
+    }
+}
+
+// highest priority first, then first queued first.
+impl Ord for QueuedTask {
+    fn cmp(&self, other: &Self) -> Ordering {
+        return self.priority.cmp(&other.priority).then_with(|| other.seq.cmp(&self.seq));
+    }
+}
+
+impl PartialOrd for QueuedTask {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl PartialEq for QueuedTask {
+    fn eq(&self, other: &Self) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl Eq for QueuedTask {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // runs tasks of the given priorities, queued together on a single worker, and returns the order they ran in.
+    fn run_order(priorities: &[u64]) -> Vec<usize> {
+        let pool = PriorityPool::new(1);
+        let order = Arc::new(Mutex::new(vec![]));
+        let receivers: Vec<_> = {
+            let mut batch = pool.batch();
+            priorities.iter().enumerate().map(|(i, priority)| {
+                let order = Arc::clone(&order);
+                batch.spawn(*priority, move || order.lock().unwrap().push(i))
+            }).collect()
+        };
+        for receiver in receivers {
+            receiver.recv().unwrap().unwrap();
+        }
+        let order = order.lock().unwrap().clone();
+        return order;
+    }
+
+    #[test]
+    fn highest_priority_runs_first() {
+        assert_eq!(run_order(&[1, 3, 2]), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn same_priority_runs_in_queue_order() {
+        assert_eq!(run_order(&[5, 5, 5, 7]), vec![3, 0, 1, 2]);
+    }
+
+    #[test]
+    fn results_and_panics_are_sent_back() {
+        let pool = PriorityPool::new(2);
+        let (ok, failed) = {
+            let mut batch = pool.batch();
+            (batch.spawn(0, || 42), batch.spawn(0, || -> i32 { panic!("task failed") }))
+        };
+        assert_eq!(ok.recv().unwrap().unwrap(), 42);
+        assert!(failed.recv().unwrap().is_err());
+        // a panicking task does not take its worker down.
+        let again = pool.batch().spawn(0, || 1);
+        assert_eq!(again.recv().unwrap().unwrap(), 1);
+    }
+
+    #[test]
+    fn queued_tasks_run_before_the_pool_is_dropped() {
+        let pool = PriorityPool::new(1);
+        let receivers: Vec<_> = {
+            let mut batch = pool.batch();
+            (0..4).map(|i| batch.spawn(i, move || i)).collect()
+        };
+        drop(pool);
+        let results: Vec<u64> = receivers.into_iter().map(|receiver| receiver.recv().unwrap().unwrap()).collect();
+        assert_eq!(results, vec![0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+
+// *******************************
+// conditional routing.
+// By default the output of a node is sent to every child. A node can instead route its output
+// to one of its children (switch), or drop it (filter).
+// A node none of its parents sent anything to does not fire either: it is not called and sends nothing.
+// A node with several parents (a join) reads the outputs of the parents that fired, in order.
+// When the sink does not fire, the graph outputs an empty vec.
+// *******************************
+pub enum Route<T> {
+    // every child receives the output.
+    All,
+    // only the child at the returned index (in the order children were given) receives the output,
+    // no child does when the index is out of range.
+    Switch(fn(output: &[T]) -> usize),
+    // every child receives the output when the predicate holds, none otherwise.
+    Filter(fn(output: &[T]) -> bool),
+}
+
+impl <T> Route<T> {
+    // whether the child at index `branch` receives `output`.
+    pub (in crate::graph) fn fires(&self, output: &[T], branch: usize) -> bool {
+        return match self {
+            Route::All => true,
+            Route::Switch(selector) => selector(output) == branch,
+            Route::Filter(predicate) => predicate(output),
+        };
+    }
+
+    // only a switch sends different things to different children: for any other route,
+    // every child reads the same branch.
+    pub (in crate::graph) fn branch_key(&self, branch: usize) -> usize {
+        return match self {
+            Route::Switch(_) => branch,
+            _ => 0,
+        };
+    }
+
+    // how the node routes, two nodes routing differently never compute the same thing.
+    // the routing functions themselves are covered by the operator of the node (see `GraphNode::with_operator`).
+    pub (in crate::graph) fn kind(&self) -> usize {
+        return match self {
+            Route::All => 0,
+            Route::Switch(_) => 1,
+            Route::Filter(_) => 2,
+        };
+    }
+}
+
+// fn pointers are Copy whatever T is, deriving would require T: Copy.
+impl <T> Clone for Route<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl <T> Copy for Route<T> {}
+
+impl <T> fmt::Debug for Route<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Route::All => write!(f, "All"),
+            Route::Switch(_) => write!(f, "Switch"),
+            Route::Filter(_) => write!(f, "Filter"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use crate::graph::easy_functions;
+    use crate::graph::serial::{ComputeGraph, GraphNode};
+    use crate::graph::concurrent::{ConcurrentComputeGraph, ConcurrentGraphNode};
+    use crate::graph::io_graph::IoConcurrentComputeGraph;
+    use crate::graph::hybrid::HybridComputeGraph;
+
+    //                  / add_five (negative) \
+    // add_one (switch)                         add_one (sink)
+    //                  \ square (positive)   /
+    fn serial_switch() -> ComputeGraph<f64> {
+        let sink = Rc::new(GraphNode::new(easy_functions::add_one, String::from("sink"), vec![]));
+        let negative = Rc::new(GraphNode::new(easy_functions::add_five, String::from("negative"), vec![Rc::clone(&sink)]));
+        let positive = Rc::new(GraphNode::new(easy_functions::square, String::from("positive"), vec![Rc::clone(&sink)]));
+        let source = Rc::new(GraphNode::switch(easy_functions::add_one, String::from("source"),
+                                               vec![negative, positive], easy_functions::by_sign));
+        return ComputeGraph::new(source).unwrap();
+    }
+
+    fn concurrent_switch() -> Arc<ConcurrentGraphNode<f64>> {
+        let sink = Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("sink"), vec![]));
+        let negative = Arc::new(ConcurrentGraphNode::new(easy_functions::add_five, String::from("negative"), vec![Arc::clone(&sink)]));
+        let positive = Arc::new(ConcurrentGraphNode::io(easy_functions::square, String::from("positive"), vec![Arc::clone(&sink)]));
+        return Arc::new(ConcurrentGraphNode::switch(easy_functions::add_one, String::from("source"),
+                                                    vec![negative, positive], easy_functions::by_sign));
+    }
+
+    // square (filter) -> add_one (sink)
+    fn concurrent_filter() -> Arc<ConcurrentGraphNode<f64>> {
+        let sink = Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("sink"), vec![]));
+        return Arc::new(ConcurrentGraphNode::filter(easy_functions::square, String::from("source"),
+                                                    vec![sink], easy_functions::below_hundred));
+    }
+
+    #[test]
+    fn switch_fires_a_single_branch() {
+        let graph = serial_switch();
+        assert_eq!(graph.apply(vec![-3.0]), vec![4.0]);
+        assert_eq!(graph.apply(vec![2.0]), vec![10.0]);
+        assert_eq!(graph.apply_batch(vec![vec![-3.0], vec![2.0]]), vec![vec![4.0], vec![10.0]]);
+
+        let root = concurrent_switch();
+        let concurrent = ConcurrentComputeGraph::new(Arc::clone(&root)).unwrap();
+        let hybrid = HybridComputeGraph::new(Arc::clone(&root), 2).unwrap();
+        for (datum, expected) in [(vec![-3.0], vec![4.0]), (vec![2.0], vec![10.0])] {
+            assert_eq!(concurrent.apply(datum.clone()), expected);
+            assert_eq!(hybrid.apply(datum), expected);
+        }
+
+        // the io graph does not call its source, the datum is routed as is.
+        let io = IoConcurrentComputeGraph::new(Arc::clone(&root)).unwrap();
+        assert_eq!(io.apply(vec![-3.0]), vec![3.0]);
+        assert_eq!(io.apply(vec![2.0]), vec![5.0]);
+    }
+
+    #[test]
+    fn filter_drops_the_output() {
+        let sink = Rc::new(GraphNode::new(easy_functions::add_one, String::from("sink"), vec![]));
+        let source = Rc::new(GraphNode::filter(easy_functions::square, String::from("source"),
+                                               vec![sink], easy_functions::below_hundred));
+        let graph = ComputeGraph::new(source).unwrap();
+        assert_eq!(graph.apply(vec![3.0]), vec![10.0]);
+        assert_eq!(graph.apply(vec![20.0]), Vec::<f64>::new());
+
+        let root = concurrent_filter();
+        let concurrent = ConcurrentComputeGraph::new(Arc::clone(&root)).unwrap();
+        let hybrid = HybridComputeGraph::new(Arc::clone(&root), 2).unwrap();
+        for (datum, expected) in [(vec![3.0], vec![10.0]), (vec![20.0], vec![])] {
+            assert_eq!(concurrent.apply(datum.clone()), expected);
+            assert_eq!(hybrid.apply(datum), expected);
+        }
+
+        // the io graph does not call its source, the datum is filtered as is.
+        let io = IoConcurrentComputeGraph::new(Arc::clone(&root)).unwrap();
+        assert_eq!(io.apply(vec![3.0]), vec![4.0]);
+        assert_eq!(io.apply(vec![200.0]), Vec::<f64>::new());
+    }
+}
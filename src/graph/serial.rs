@@ -1,33 +1,115 @@
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::collections::{VecDeque, HashMap};
+use std::collections::{VecDeque, HashMap, HashSet};
 use std::fmt;
 use uuid::Uuid;
 
 pub use crate::graph::GraphLikeFunc;
+use crate::graph::GraphError;
+use crate::graph::passthrough;
+use crate::graph::optimizer::{self, OptimizationReport, OptimizerNode};
+use crate::graph::identity::{self, IdentityNode};
+use crate::graph::introspection::GraphView;
+use crate::graph::routing::Route;
+use crate::graph::trace::{self, NodeRecord, NodeRecords, Trace};
+use std::time::Instant;
 
 // exposed graph structure
 pub struct ComputeGraph<T> where T: Clone {
-    pub root: Rc<GraphNode<T>>,
+    roots: Vec<Rc<GraphNode<T>>>,
     internal_root: Rc<InternalGraphNode<T>>,
+    // state kept between two incremental calls.
+    last_outputs: RefCell<HashMap<Uuid, Option<Rc<Vec<T>>>>>,
+    last_recomputed: RefCell<Vec<String>>,
 }
 
 impl <T> ComputeGraph<T> where T: Clone {
 
-    pub fn new(root: Rc<GraphNode<T>>) -> ComputeGraph<T> {
-        return ComputeGraph {
-            root: Rc::clone(&root),
-            internal_root: InternalGraphNode::to_internal_graph_node(Rc::clone(&root)),
-        };
+    // fails when the graph is not valid (see `GraphError`).
+    pub fn new(root: Rc<GraphNode<T>>) -> Result<ComputeGraph<T>, GraphError> {
+        return ComputeGraph::with_sources(vec![root]);
+    }
+
+    // a graph with several inputs, each root is a source reading its own input (see `apply_incremental`).
+    // with `apply` and `apply_batch`, every source reads the same datum.
+    pub fn with_sources(roots: Vec<Rc<GraphNode<T>>>) -> Result<ComputeGraph<T>, GraphError> {
+        return Ok(ComputeGraph {
+            internal_root: InternalGraphNode::to_internal_graph(roots.iter().map(Rc::clone).collect())?,
+            roots,
+            last_outputs: RefCell::new(HashMap::new()),
+            last_recomputed: RefCell::new(vec![]),
+        });
+    }
+
+    // same as `new`, but drops the nodes the sink does not read and merges duplicated computations.
+    pub fn optimized(root: Rc<GraphNode<T>>) -> Result<(ComputeGraph<T>, OptimizationReport), GraphError> {
+        let graph = ComputeGraph::new(root)?;
+        let report = graph.internal_root.optimize(&graph.roots)?;
+        return Ok((graph, report));
+    }
+
+    // only recomputes the nodes downstream of the sources present in `changed_inputs` (keyed by source name),
+    // every other node reuses its output from the previous call.
+    // panics if a source never received an input.
+    pub fn apply_incremental(&self, changed_inputs: HashMap<String, Vec<T>>) -> Vec<T> {
+        let mut dirty = HashMap::new();
+        self.internal_root.mark_dirty(&changed_inputs, &mut dirty);
+
+        let mut recomputed = vec![];
+        let result = self.internal_root.apply_incremental(&changed_inputs,
+                                                          &mut self.last_outputs.borrow_mut(),
+                                                          &mut dirty,
+                                                          &mut recomputed);
+        *self.last_recomputed.borrow_mut() = recomputed;
+        return result.map_or_else(Vec::new, |result| (*result).clone());
+    }
+
+    // names of the nodes computed by the last call to `apply_incremental`.
+    pub fn last_recomputed(&self) -> Vec<String> {
+        return self.last_recomputed.borrow().clone();
     }
 
     pub fn apply(&self, datum: Vec<T>) -> Vec<T> {
         return self.internal_root.apply(datum);
     }
 
+    // same as `apply`, along with the input, output and duration of every node.
+    pub fn apply_traced(&self, datum: Vec<T>) -> Trace<T> {
+        return self.internal_root.apply_traced(datum);
+    }
+
     pub fn apply_batch(&self, data: Vec<Vec<T>>) -> Vec<Vec<T>> {
         return self.internal_root.apply_batch(data);
     }
+
+    // output of the given node, only its ancestors are computed (empty if the node does not fire).
+    // returns None if the sink does not read from a node with this name (names are unique within a graph).
+    pub fn evaluate(&self, node_name: &str, datum: Vec<T>) -> Option<Vec<T>> {
+        let id_to_node = InternalGraphNode::index_by_id(&self.internal_root);
+        let node = id_to_node.values().find(|node| node.name == node_name)?;
+        return Some(node.apply(datum));
+    }
+
+    // a graph where the sink of this graph feeds the sources of `next`.
+    // node names are kept as they are, use `GraphNode::subgraph` to namespace them: fails when both graphs share a name.
+    pub fn then(&self, next: &ComputeGraph<T>) -> Result<ComputeGraph<T>, GraphError> {
+        let next_roots = GraphNode::copy(&next.roots, None, &[]);
+        return ComputeGraph::with_sources(GraphNode::copy(&self.roots, None, &next_roots));
+    }
+
+    // id of every node the sink reads from, by node name. ids are the same from one run to the next.
+    pub fn node_ids(&self) -> HashMap<String, Uuid> {
+        return InternalGraphNode::index_by_id(&self.internal_root).values()
+            .map(|node| (node.name.clone(), node.id))
+            .collect();
+    }
+
+    // read-only structure of the graph, as it is executed.
+    pub fn view(&self) -> GraphView {
+        return GraphView::new(InternalGraphNode::index_by_id(&self.internal_root).values()
+            .map(|node| (node.id, node.name.clone(), node.parents.borrow().iter().map(|parent| parent.id).collect()))
+            .collect());
+    }
 }
 
 
@@ -38,8 +120,11 @@ pub struct GraphNode<T> where T: Clone {
     pub f: GraphLikeFunc<T>,
     pub name: String,
     pub children: Vec<Rc<GraphNode<T>>>,
-    id: Uuid,
-
+    pub route: Route<T>,
+    // when not set, the id is derived from the name, the operator and the parents of the node (see `identity`).
+    id: Option<Uuid>,
+    // what the node computes, nodes are only merged by the optimizer when they share it (see `with_operator`).
+    operator: Option<String>,
 }
 
 impl <T> GraphNode<T> where T: Clone {
@@ -49,22 +134,169 @@ impl <T> GraphNode<T> where T: Clone {
             f,
             name: m,
             children,
-            id: Uuid::new_v4(),
+            route: Route::All,
+            id: None,
+            operator: None,
         };
     }
+
+    pub fn with_id(f: GraphLikeFunc<T>, m: String, children: Vec<Rc<GraphNode<T>>>, id: Uuid) -> Self {
+        return GraphNode {
+            f,
+            name: m,
+            children,
+            route: Route::All,
+            id: Some(id),
+            operator: None,
+        };
+    }
+
+    // sends its output to a single child, the one at the index returned by `selector` (see `routing`).
+    pub fn switch(f: GraphLikeFunc<T>, m: String, children: Vec<Rc<GraphNode<T>>>, selector: fn(&[T]) -> usize) -> Self {
+        return GraphNode {
+            f,
+            name: m,
+            children,
+            route: Route::Switch(selector),
+            id: None,
+            operator: None,
+        };
+    }
+
+    // sends its output to its children only when `predicate` holds, drops it otherwise (see `routing`).
+    pub fn filter(f: GraphLikeFunc<T>, m: String, children: Vec<Rc<GraphNode<T>>>, predicate: fn(&[T]) -> bool) -> Self {
+        return GraphNode {
+            f,
+            name: m,
+            children,
+            route: Route::Filter(predicate),
+            id: None,
+            operator: None,
+        };
+    }
+
+    // names what the node computes (e.g. "add one"). Nodes given the same operator must apply the same function
+    // and route the same way: the optimizer merges them when they read the same parents.
+    // function addresses can't tell it, the same function may have several addresses and two functions the same one.
+    // the operator is also part of the id of the node (see `identity`).
+    pub fn with_operator(mut self, operator: &str) -> Self {
+        self.operator = Some(String::from(operator));
+        return self;
+    }
+
+    // embeds a copy of `graph` as a single node of another graph: the returned node reads the datum
+    // the subgraph would read, and the sink of the subgraph feeds `children`.
+    // names (and explicit ids) of the copied nodes are prefixed with `namespace/`, so that they don't collide.
+    pub fn subgraph(graph: &ComputeGraph<T>, namespace: &str, children: Vec<Rc<GraphNode<T>>>) -> Rc<GraphNode<T>> {
+        let mut sources = GraphNode::copy(&graph.roots, Some(namespace), &children);
+        if sources.len() == 1 {
+            return sources.pop().unwrap();
+        }
+        let (name, _) = identity::namespaced(namespace, "entry", None);
+        return Rc::new(GraphNode::new(passthrough, name, sources));
+    }
+
+    // copies the nodes reachable from the roots, the sinks of the copy get `sink_children` as children.
+    // returns the copies of the roots.
+    fn copy(roots: &[Rc<GraphNode<T>>], namespace: Option<&str>, sink_children: &[Rc<GraphNode<T>>]) -> Vec<Rc<GraphNode<T>>> {
+        let mut copies = HashMap::new();
+        return roots.iter().map(|root| GraphNode::copy_memo(root, namespace, sink_children, &mut copies)).collect();
+    }
+
+    // a node read by several children is only copied once.
+    fn copy_memo(node: &Rc<GraphNode<T>>,
+                 namespace: Option<&str>,
+                 sink_children: &[Rc<GraphNode<T>>],
+                 copies: &mut HashMap<usize, Rc<GraphNode<T>>>) -> Rc<GraphNode<T>> {
+        if let Some(copy) = copies.get(&GraphNode::key(node)) {
+            return Rc::clone(copy);
+        }
+        let children = if node.children.is_empty() {
+            sink_children.to_vec()
+        } else {
+            node.children.iter().map(|child| GraphNode::copy_memo(child, namespace, sink_children, copies)).collect()
+        };
+        let (name, id) = match namespace {
+            Some(namespace) => identity::namespaced(namespace, &node.name, node.id),
+            None => (node.name.clone(), node.id),
+        };
+        let copy = Rc::new(GraphNode { f: node.f, name, children, route: node.route, id, operator: node.operator.clone() });
+        copies.insert(GraphNode::key(node), Rc::clone(&copy));
+        return copy;
+    }
+
+    // until ids are assigned, nodes are identified by their address.
+    fn key(node: &Rc<GraphNode<T>>) -> usize {
+        return Rc::as_ptr(node) as usize;
+    }
+
+    // id of every node reachable from the roots, by address.
+    fn assign_ids(roots: &[Rc<GraphNode<T>>]) -> Result<HashMap<usize, Uuid>, GraphError> {
+        let nodes = GraphNode::all_nodes(roots).iter().map(|node| IdentityNode {
+            key: GraphNode::key(node),
+            name: node.name.clone(),
+            operator: node.operator.clone(),
+            explicit_id: node.id,
+            children: node.children.iter().map(GraphNode::key).collect(),
+        }).collect();
+        return identity::assign_ids(nodes);
+    }
+
+    // every node reachable from the roots, in bfs order.
+    fn all_nodes(roots: &[Rc<GraphNode<T>>]) -> Vec<Rc<GraphNode<T>>> {
+        let mut nodes = vec![];
+        let mut visited = HashSet::new();
+        let mut bfs_q: VecDeque<Rc<GraphNode<T>>> = roots.iter().map(Rc::clone).collect();
+
+        while let Some(node) = bfs_q.pop_front() {
+            if !visited.insert(GraphNode::key(&node)) {
+                continue;
+            }
+            for child in node.children.iter() {
+                bfs_q.push_back(Rc::clone(child));
+            }
+            nodes.push(node);
+        }
+        return nodes;
+    }
 }
 
 // ****************************
 // internal graph constructs.
 // ****************************
+
+// state of a single call to apply.
+struct Run<T> {
+    started: Instant,
+    // None for the nodes that did not fire.
+    memo: HashMap<Uuid, Option<Rc<Vec<T>>>>,
+    // only kept for traced runs.
+    records: Option<NodeRecords<T>>,
+}
+
+impl <T> Run<T> {
+    fn new(traced: bool) -> Run<T> {
+        return Run {
+            started: Instant::now(),
+            memo: HashMap::new(),
+            records: if traced { Some(NodeRecords::new()) } else { None },
+        };
+    }
+}
+
 type ParentRefs<T> = RefCell<Vec<Rc<InternalGraphNode<T>>>>;
+// for every parent, the index of the node among the children of that parent (what a switch selects).
+type Branches = RefCell<Vec<usize>>;
 
 // for the internal structure each node points to its parents.
 // because of the non-natural way to express such a graph, we keep this representation private.
 pub struct InternalGraphNode<T> {
     f: GraphLikeFunc<T>,
     name: String,
+    operator: Option<String>,
+    route: Route<T>,
     parents: ParentRefs<T>,
+    branches: Branches,
     id: Uuid,
 }
 
@@ -76,78 +308,403 @@ impl <T> fmt::Display for InternalGraphNode<T> where T: Clone {
 
 impl <T> InternalGraphNode<T> where T: Clone {
 
-    fn empty(f: GraphLikeFunc<T>, m: String, id: Uuid,) -> InternalGraphNode<T> {
+    fn empty(node: &GraphNode<T>, id: Uuid) -> InternalGraphNode<T> {
         return InternalGraphNode {
-            f,
-            name: m,
+            f: node.f,
+            name: node.name.clone(),
+            operator: node.operator.clone(),
+            route: node.route,
             id,
-            parents: RefCell::new(vec![])
+            parents: RefCell::new(vec![]),
+            branches: RefCell::new(vec![]),
         };
     }
-    // we start from the root node, and build a transpose of the given graph.
+    // we start from the root nodes, and build a transpose of the given graph.
     // ref: https://en.wikipedia.org/wiki/Transpose_graph
-    fn to_internal_graph_node(node: Rc<GraphNode<T>>) -> Rc<InternalGraphNode<T>> {
+    fn to_internal_graph(roots: Vec<Rc<GraphNode<T>>>) -> Result<Rc<InternalGraphNode<T>>, GraphError> {
+        let ids = GraphNode::assign_ids(&roots)?;
         let mut nodes = VecDeque::new();
         let mut internal_nodes = VecDeque::new();
         let mut id_to_internal_node = HashMap::new();
+        let mut expanded = HashSet::new(); // a node reached from several parents only links its children once.
 
-        let internal = InternalGraphNode::empty(node.f, node.name.clone(), node.id);
-
-        let mut internal_rc = Rc::new(internal);
-
-        id_to_internal_node.insert(node.id, Rc::clone(&internal_rc));
-        nodes.push_back(node);
-        internal_nodes.push_back(Rc::clone(&internal_rc));
+        let mut internal_rc = None;
+        for node in roots {
+            let id = ids[&GraphNode::key(&node)];
+            let internal = Rc::new(InternalGraphNode::empty(&node, id));
+            id_to_internal_node.insert(id, Rc::clone(&internal));
+            internal_nodes.push_back(Rc::clone(&internal));
+            nodes.push_back(node);
+            internal_rc = Some(internal);
+        }
 
-        while nodes.len() != 0 {
+        while !nodes.is_empty() {
             let node = nodes.pop_front().unwrap();
             let internal_node = internal_nodes.pop_front().unwrap();
+            if !expanded.insert(internal_node.id) {
+                continue;
+            }
 
-            for child in node.children.iter() {
+            for (branch, child) in node.children.iter().enumerate() {
 
+                let child_id = ids[&GraphNode::key(child)];
                 let new_internal: Rc<InternalGraphNode<T>> = {
-                    if id_to_internal_node.contains_key(&child.id) {
-                        Rc::clone(&id_to_internal_node.get(&child.id).unwrap())
+                    if id_to_internal_node.contains_key(&child_id) {
+                        Rc::clone(id_to_internal_node.get(&child_id).unwrap())
                     } else {
-                        Rc::new(InternalGraphNode::empty(child.f, child.name.clone(), child.id))
+                        Rc::new(InternalGraphNode::empty(child, child_id))
                     }
                 };
                 new_internal.parents.borrow_mut().push(Rc::clone(&internal_node));
+                new_internal.branches.borrow_mut().push(branch);
                 id_to_internal_node.insert(new_internal.id, Rc::clone(&new_internal));
 
                 internal_nodes.push_back(Rc::clone(&new_internal));
                 nodes.push_back(Rc::clone(child));
-                internal_rc = new_internal;
+                internal_rc = Some(new_internal);
             }
         }
-        return internal_rc;
+        return Ok(internal_rc.unwrap());
     }
 
     // given the tap node, apply starting from sink node up to the tap.
     // fits for general purpose computation.
     fn apply(&self, datum: Vec<T>) -> Vec<T> {
-        let f = self.f;
-        let mut data = vec![];
-        if self.parents.borrow().len() == 0 {
-            return f(datum);
+        // the run (and its memo) is dropped here, so that the output is not shared anymore and is not copied.
+        let result = self.apply_memo(&datum, &mut Run::new(false));
+        return match result {
+            Some(result) => Rc::try_unwrap(result).unwrap_or_else(|shared| (*shared).clone()),
+            None => vec![],
+        };
+    }
+
+    fn apply_traced(&self, datum: Vec<T>) -> Trace<T> {
+        let mut run = Run::new(true);
+        let result = self.apply_memo(&datum, &mut run);
+        return Trace {
+            output: result.map_or_else(Vec::new, |result| (*result).clone()),
+            nodes: run.records.unwrap(),
+            segments: vec![],
+        };
+    }
+
+    // each node is computed once per call, even when several children read from it.
+    // outputs are shared by reference, the only copies made are when a node has to concatenate its parents.
+    fn apply_memo(&self, datum: &[T], run: &mut Run<T>) -> Option<Rc<Vec<T>>> {
+        if let Some(result) = run.memo.get(&self.id) {
+            return result.clone();
         }
-        for parent in self.parents.borrow().iter() {
-            let result = parent.apply(datum.clone());
-            data.extend(result);
+        let result = if self.parents.borrow().is_empty() {
+            Some(self.call(datum, run))
+        } else {
+            let results = self.fired_inputs(|parent| parent.apply_memo(datum, run));
+            match results.len() {
+                0 => None,
+                1 => Some(self.call(&results[0], run)),
+                _ => {
+                    let mut data = Vec::with_capacity(results.iter().map(|result| result.len()).sum());
+                    for result in results.iter() {
+                        data.extend_from_slice(result);
+                    }
+                    Some(self.call(&data, run))
+                }
+            }
+        };
+        run.memo.insert(self.id, result.clone());
+        return result;
+    }
+
+    // outputs of the parents that fired towards this node, in order.
+    fn fired_inputs<F>(&self, mut output_of: F) -> Vec<Rc<Vec<T>>> where F: FnMut(&InternalGraphNode<T>) -> Option<Rc<Vec<T>>> {
+        let mut results = vec![];
+        for (parent, branch) in self.parents.borrow().iter().zip(self.branches.borrow().iter()) {
+            if let Some(result) = output_of(parent) {
+                if parent.route.fires(&result, *branch) {
+                    results.push(result);
+                }
+            }
         }
-        return f(data);
+        return results;
+    }
+
+    fn call(&self, xs: &[T], run: &mut Run<T>) -> Rc<Vec<T>> {
+        let f = self.f;
+        let start = Instant::now();
+        let result = Rc::new(f(xs));
+        if let Some(records) = run.records.as_mut() {
+            trace::record(records, &self.name, NodeRecord {
+                input: xs.to_vec(),
+                output: (*result).clone(),
+                start: start.duration_since(run.started),
+                duration: start.elapsed(),
+                thread: trace::current_thread_id(),
+            });
+        }
+        return result;
     }
 
     fn apply_batch(&self, batch: Vec<Vec<T>>) -> Vec<Vec<T>> {
+        let result = self.apply_batch_memo(&batch, &mut HashMap::new());
+        return Rc::try_unwrap(result).unwrap_or_else(|shared| (*shared).clone());
+    }
+
+    fn apply_batch_memo(&self, batch: &[Vec<T>], memo: &mut HashMap<Uuid, Rc<Vec<Vec<T>>>>) -> Rc<Vec<Vec<T>>> {
+        if let Some(result) = memo.get(&self.id) {
+            return Rc::clone(result);
+        }
         let f = self.f;
         let mut data: Vec<Vec<T>> = Vec::new();
-        if self.parents.borrow().len() == 0 {
-            return batch.iter().map(|xs| f(xs.to_vec())).collect();
+        if self.parents.borrow().is_empty() {
+            data.extend(batch.iter().map(|xs| f(xs)));
+        }
+        for (parent, branch) in self.parents.borrow().iter().zip(self.branches.borrow().iter()) {
+            let result = parent.apply_batch_memo(batch, memo);
+            data.extend(result.iter().filter(|xs| parent.route.fires(xs, *branch)).map(|xs| f(xs)));
         }
+        let result = Rc::new(data);
+        memo.insert(self.id, Rc::clone(&result));
+        return result;
+    }
+
+    // a node is dirty when it is a changed source, or when one of its parents is dirty.
+    fn mark_dirty(&self, changed_inputs: &HashMap<String, Vec<T>>, dirty: &mut HashMap<Uuid, bool>) -> bool {
+        if let Some(is_dirty) = dirty.get(&self.id) {
+            return *is_dirty;
+        }
+        let mut is_dirty = self.parents.borrow().is_empty() && changed_inputs.contains_key(&self.name);
         for parent in self.parents.borrow().iter() {
-            let result = parent.apply_batch(batch.clone());
-            data.extend(result);
+            is_dirty |= parent.mark_dirty(changed_inputs, dirty);
+        }
+        dirty.insert(self.id, is_dirty);
+        return is_dirty;
+    }
+
+    fn apply_incremental(&self,
+                         changed_inputs: &HashMap<String, Vec<T>>,
+                         last_outputs: &mut HashMap<Uuid, Option<Rc<Vec<T>>>>,
+                         dirty: &mut HashMap<Uuid, bool>,
+                         recomputed: &mut Vec<String>) -> Option<Rc<Vec<T>>> {
+        if !dirty.get(&self.id).unwrap() {
+            if let Some(result) = last_outputs.get(&self.id) {
+                return result.clone();
+            }
+        }
+        let f = self.f;
+        let result = if self.parents.borrow().is_empty() {
+            let input = changed_inputs.get(&self.name)
+                .unwrap_or_else(|| panic!("no input for source {}", self.name));
+            Some(Rc::new(f(input)))
+        } else {
+            let results = self.fired_inputs(|parent| parent.apply_incremental(changed_inputs, last_outputs, dirty, recomputed));
+            if results.is_empty() {
+                None
+            } else {
+                let mut data = Vec::with_capacity(results.iter().map(|result| result.len()).sum());
+                for result in results.iter() {
+                    data.extend_from_slice(result);
+                }
+                Some(Rc::new(f(&data)))
+            }
+        };
+
+        // computed once per call, even when several children read from it.
+        dirty.insert(self.id, false);
+        last_outputs.insert(self.id, result.clone());
+        if result.is_some() {
+            recomputed.push(self.name.clone());
+        }
+        return result;
+    }
+
+    // every node the sink reads from, sink included.
+    fn index_by_id(sink: &Rc<InternalGraphNode<T>>) -> HashMap<Uuid, Rc<InternalGraphNode<T>>> {
+        let mut id_to_node = HashMap::new();
+        let mut bfs_q = VecDeque::new();
+        bfs_q.push_back(Rc::clone(sink));
+
+        while let Some(node) = bfs_q.pop_front() {
+            if id_to_node.contains_key(&node.id) {
+                continue;
+            }
+            for parent in node.parents.borrow().iter() {
+                bfs_q.push_back(Rc::clone(parent));
+            }
+            id_to_node.insert(node.id, node);
+        }
+        return id_to_node;
+    }
+
+    // rewires the parents of every node so that duplicated computations point to a single node.
+    fn optimize(self: &Rc<Self>, roots: &[Rc<GraphNode<T>>]) -> Result<OptimizationReport, GraphError> {
+        let id_to_node = InternalGraphNode::index_by_id(self);
+        let ids = GraphNode::assign_ids(roots)?;
+        let all_nodes = GraphNode::all_nodes(roots).iter().map(|node| (ids[&GraphNode::key(node)], node.name.clone())).collect();
+        let reachable = id_to_node.values().map(|node| OptimizerNode {
+            id: node.id,
+            name: node.name.clone(),
+            operator: node.operator.clone(),
+            route: node.route.kind(),
+            parents: node.parents.borrow().iter().map(|parent| parent.id).collect(),
+            branches: node.parents.borrow().iter().zip(node.branches.borrow().iter())
+                .map(|(parent, branch)| parent.route.branch_key(*branch))
+                .collect(),
+        }).collect();
+
+        let (replaced_by, report) = optimizer::plan(all_nodes, self.id, reachable);
+        for node in id_to_node.values() {
+            let parents = node.parents.borrow().iter()
+                .map(|parent| Rc::clone(id_to_node.get(replaced_by.get(&parent.id).unwrap()).unwrap()))
+                .collect();
+            *node.parents.borrow_mut() = parents;
         }
-        return data.iter().map(|xs| f(xs.to_vec())).collect();
+        return Ok(report);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc_counter::{self, LARGE};
+    use crate::graph::easy_functions;
+
+    // large enough for every copy of the datum to be counted.
+    const DATUM_LEN: usize = LARGE;
+
+    fn head(xs: &[f64]) -> Vec<f64> {
+        return xs.iter().take(1).cloned().collect();
+    }
+
+    // add_one (source) -> 32 x head -> head (sink)
+    fn fan_out() -> ComputeGraph<f64> {
+        let sink = Rc::new(GraphNode::new(head, String::from("sink"), vec![]));
+        let branches = (0..32).map(|i| {
+            Rc::new(GraphNode::new(head, format!("branch {}", i), vec![Rc::clone(&sink)]))
+        }).collect();
+        return ComputeGraph::new(Rc::new(GraphNode::new(easy_functions::add_one, String::from("source"), branches))).unwrap();
+    }
+
+    // a (add_one) -> a2 (add_one) \
+    //                               sink (square)
+    // b (add_five)                 /
+    // the sink reads b first, its parents are linked breadth first.
+    fn two_sources() -> ComputeGraph<f64> {
+        let sink = Rc::new(GraphNode::new(easy_functions::square, String::from("sink"), vec![]));
+        let a2 = Rc::new(GraphNode::new(easy_functions::add_one, String::from("a2"), vec![Rc::clone(&sink)]));
+        let a = Rc::new(GraphNode::new(easy_functions::add_one, String::from("a"), vec![a2]));
+        let b = Rc::new(GraphNode::new(easy_functions::add_five, String::from("b"), vec![sink]));
+        return ComputeGraph::with_sources(vec![a, b]).unwrap();
+    }
+
+    fn inputs(inputs: &[(&str, f64)]) -> HashMap<String, Vec<f64>> {
+        return inputs.iter().map(|(name, x)| (String::from(*name), vec![*x])).collect();
+    }
+
+    fn sorted(mut names: Vec<String>) -> Vec<String> {
+        names.sort();
+        return names;
+    }
+
+    #[test]
+    fn incremental_only_recomputes_downstream_of_changed_sources() {
+        let graph = two_sources();
+        assert_eq!(graph.apply_incremental(inputs(&[("a", 1.0), ("b", 1.0)])), vec![36.0, 9.0]);
+        assert_eq!(sorted(graph.last_recomputed()), vec!["a", "a2", "b", "sink"]);
+
+        assert_eq!(graph.apply_incremental(inputs(&[("b", 2.0)])), vec![49.0, 9.0]);
+        assert_eq!(sorted(graph.last_recomputed()), vec!["b", "sink"]);
+
+        assert_eq!(graph.apply_incremental(inputs(&[("a", 0.0)])), vec![49.0, 4.0]);
+        assert_eq!(sorted(graph.last_recomputed()), vec!["a", "a2", "sink"]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn incremental_panics_when_a_source_never_received_an_input() {
+        two_sources().apply_incremental(inputs(&[("a", 1.0)]));
+    }
+
+    // start (add_one) -> left (add_one) / right (add_five) -> last (square)
+    fn diamond(right_name: &str) -> Result<ComputeGraph<f64>, GraphError> {
+        let last = Rc::new(GraphNode::new(easy_functions::square, String::from("last"), vec![]));
+        let left = Rc::new(GraphNode::new(easy_functions::add_one, String::from("left"), vec![Rc::clone(&last)]));
+        let right = Rc::new(GraphNode::new(easy_functions::add_five, String::from(right_name), vec![last]));
+        return ComputeGraph::new(Rc::new(GraphNode::new(easy_functions::add_one, String::from("start"), vec![left, right])));
+    }
+
+    #[test]
+    fn evaluate_returns_the_output_of_any_node() {
+        let graph = diamond("right").unwrap();
+        assert_eq!(graph.evaluate("start", vec![1.0]), Some(vec![2.0]));
+        assert_eq!(graph.evaluate("left", vec![1.0]), Some(vec![3.0]));
+        assert_eq!(graph.evaluate("right", vec![1.0]), Some(vec![7.0]));
+        assert_eq!(graph.evaluate("last", vec![1.0]), Some(graph.apply(vec![1.0])));
+        assert_eq!(graph.evaluate("missing", vec![1.0]), None);
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected() {
+        assert_eq!(diamond("left").err(), Some(GraphError::DuplicateName(String::from("left"))));
+    }
+
+    #[test]
+    fn subgraph_namespaces_the_embedded_nodes() {
+        let inner = diamond("right").unwrap();
+        let sink = Rc::new(GraphNode::new(easy_functions::add_five, String::from("sink"), vec![]));
+        let embedded = GraphNode::subgraph(&inner, "inner", vec![sink]);
+        let outer = ComputeGraph::new(Rc::new(GraphNode::new(easy_functions::add_one, String::from("start"), vec![embedded]))).unwrap();
+
+        let mut names: Vec<String> = outer.node_ids().into_keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["inner/last", "inner/left", "inner/right", "inner/start", "sink", "start"]);
+        let expected: Vec<f64> = inner.apply(vec![2.0]).iter().map(|x| x + 5.0).collect();
+        assert_eq!(outer.apply(vec![1.0]), expected);
+        // the embedded graph is copied, it still runs on its own.
+        assert_eq!(inner.apply(vec![1.0]), diamond("right").unwrap().apply(vec![1.0]));
+    }
+
+    #[test]
+    fn then_feeds_the_sink_into_the_next_graph() {
+        let first = diamond("right").unwrap();
+        let sink = Rc::new(GraphNode::new(easy_functions::square, String::from("sink"), vec![]));
+        let next = ComputeGraph::new(Rc::new(GraphNode::new(easy_functions::add_five, String::from("next"), vec![sink]))).unwrap();
+
+        let chained = first.then(&next).unwrap();
+        assert_eq!(chained.node_ids().len(), 6);
+        assert_eq!(chained.apply(vec![1.0]), next.apply(first.apply(vec![1.0])));
+    }
+
+    #[test]
+    fn then_rejects_shared_names() {
+        let graph = diamond("right").unwrap();
+        assert!(matches!(graph.then(&graph), Err(GraphError::DuplicateName(_))));
+    }
+
+    #[test]
+    fn fan_out_does_not_copy_the_source_output() {
+        let graph = fan_out();
+        let datum = vec![1.0; DATUM_LEN];
+        let datum_bytes = DATUM_LEN * std::mem::size_of::<f64>();
+
+        // before: every edge copied the output of its parent.
+        let (_, _, copied_bytes) = alloc_counter::measure(|| (0..32).map(|_| datum.clone()).collect::<Vec<_>>());
+        // after: only the output of the source is allocated.
+        let (output, allocations, bytes) = alloc_counter::measure(|| graph.apply(datum));
+
+        assert_eq!(output, vec![2.0]);
+        assert_eq!(allocations, 1);
+        assert!(bytes < 2 * datum_bytes);
+        assert!(copied_bytes >= 32 * datum_bytes);
+    }
+
+    #[test]
+    fn sink_output_is_not_copied() {
+        let sink = Rc::new(GraphNode::new(easy_functions::add_one, String::from("sink"), vec![]));
+        let graph = ComputeGraph::new(Rc::new(GraphNode::new(easy_functions::add_one, String::from("source"), vec![sink]))).unwrap();
+        let datum = vec![1.0; DATUM_LEN];
+
+        // one output per node.
+        let (output, allocations, _) = alloc_counter::measure(|| graph.apply(datum));
+        assert_eq!(output[0], 3.0);
+        assert_eq!(allocations, 2);
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::{Serialize, Deserialize};
+
+// *******************************
+// debug taps.
+// A traced run returns the output of the graph along with what every node read and produced.
+// Timestamps are offsets from the start of the run.
+// *******************************
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeRecord<T> {
+    pub input: Vec<T>,
+    pub output: Vec<T>,
+    pub start: Duration,
+    pub duration: Duration,
+    pub thread: u64,
+}
+
+// a chain of nodes ran by the io graph on its own thread.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentRecord {
+    pub nodes: Vec<String>,
+    pub start: Duration,
+    pub duration: Duration,
+    pub thread: u64,
+}
+
+// by node name, a node has one record per invocation.
+// (a join node of the io graph is invoked once per incoming segment)
+pub type NodeRecords<T> = BTreeMap<String, Vec<NodeRecord<T>>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trace<T> {
+    pub output: Vec<T>,
+    pub nodes: NodeRecords<T>,
+    // only filled by the io graph.
+    pub segments: Vec<SegmentRecord>,
+}
+
+impl <T> Trace<T> where T: Serialize {
+    pub fn to_json(&self) -> String {
+        return serde_json::to_string(self).unwrap();
+    }
+}
+
+impl <T> Trace<T> {
+    // timeline of the run in the chrome `about:tracing` format (also read by perfetto and speedscope).
+    // ref: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+    pub fn to_chrome_trace(&self) -> String {
+        let mut events = vec![];
+        for segment in self.segments.iter() {
+            events.push(ChromeEvent {
+                name: segment.nodes.join(" -> "),
+                cat: "segment",
+                ph: "X",
+                ts: micros(segment.start),
+                dur: micros(segment.duration),
+                pid: 1,
+                tid: segment.thread,
+            });
+        }
+        for (name, records) in self.nodes.iter() {
+            for record in records {
+                events.push(ChromeEvent {
+                    name: name.clone(),
+                    cat: "node",
+                    ph: "X",
+                    ts: micros(record.start),
+                    dur: micros(record.duration),
+                    pid: 1,
+                    tid: record.thread,
+                });
+            }
+        }
+        return serde_json::to_string(&ChromeTrace { trace_events: events }).unwrap();
+    }
+}
+
+#[derive(Serialize)]
+struct ChromeTrace {
+    #[serde(rename = "traceEvents")]
+    trace_events: Vec<ChromeEvent>,
+}
+
+// a complete event ("ph": "X"), timestamps are in micro seconds.
+#[derive(Serialize)]
+struct ChromeEvent {
+    name: String,
+    cat: &'static str,
+    ph: &'static str,
+    ts: f64,
+    dur: f64,
+    pid: u32,
+    tid: u64,
+}
+
+fn micros(duration: Duration) -> f64 {
+    return duration.as_nanos() as f64 / 1000.;
+}
+
+pub (in crate::graph) fn record<T>(records: &mut NodeRecords<T>, name: &str, record: NodeRecord<T>) {
+    records.entry(String::from(name)).or_default().push(record);
+}
+
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static THREAD_ID: u64 = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+}
+
+// small sequential id of the current thread, `std::thread::ThreadId` can't be turned into a number.
+pub (in crate::graph) fn current_thread_id() -> u64 {
+    return THREAD_ID.with(|id| *id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn node_record(start_micros: u64, duration_micros: u64, thread: u64) -> NodeRecord<f64> {
+        return NodeRecord {
+            input: vec![1.0],
+            output: vec![2.0],
+            start: Duration::from_micros(start_micros),
+            duration: Duration::from_micros(duration_micros),
+            thread,
+        };
+    }
+
+    fn trace() -> Trace<f64> {
+        let mut nodes = NodeRecords::new();
+        record(&mut nodes, "a", node_record(0, 10, 1));
+        record(&mut nodes, "b", node_record(12, 5, 2));
+        record(&mut nodes, "b", node_record(20, 5, 3));
+        return Trace {
+            output: vec![2.0],
+            nodes,
+            segments: vec![SegmentRecord {
+                nodes: vec![String::from("b"), String::from("c")],
+                start: Duration::from_micros(11),
+                duration: Duration::from_micros(8),
+                thread: 2,
+            }],
+        };
+    }
+
+    #[test]
+    fn chrome_trace_has_one_event_per_segment_and_invocation() {
+        let chrome: Value = serde_json::from_str(&trace().to_chrome_trace()).unwrap();
+        let events = chrome["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), 4);
+
+        let segment = &events[0];
+        assert_eq!(segment["name"], "b -> c");
+        assert_eq!(segment["cat"], "segment");
+        assert_eq!(segment["ph"], "X");
+        assert_eq!(segment["ts"], 11.0);
+        assert_eq!(segment["dur"], 8.0);
+        assert_eq!(segment["tid"], 2);
+
+        let invocations: Vec<(&str, f64, u64)> = events[1..].iter()
+            .map(|event| (event["name"].as_str().unwrap(), event["ts"].as_f64().unwrap(), event["tid"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(invocations, vec![("a", 0.0, 1), ("b", 12.0, 2), ("b", 20.0, 3)]);
+        assert!(events[1..].iter().all(|event| event["cat"] == "node"));
+    }
+
+    #[test]
+    fn trace_round_trips_through_json() {
+        let json = trace().to_json();
+        let parsed: Trace<f64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.output, vec![2.0]);
+        assert_eq!(parsed.nodes["b"].len(), 2);
+        assert_eq!(parsed.nodes["b"][1].start, Duration::from_micros(20));
+        assert_eq!(parsed.segments[0].nodes, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn threads_get_distinct_ids() {
+        let here = current_thread_id();
+        assert_eq!(current_thread_id(), here);
+        let there = std::thread::spawn(current_thread_id).join().unwrap();
+        assert_ne!(here, there);
+    }
+}
@@ -6,8 +6,11 @@ use std::io::{self, BufReader, BufWriter};
 use std::time::Duration;
 use std::thread::JoinHandle;
 
+// both ends of a channel.
+pub type Channel<T> = (Sender<T>, Receiver<T>);
+
 // READ
-pub fn read(filename: String) -> io::Result<(Sender<Vec<u8>>, Receiver<Vec<u8>>)> {
+pub fn read(filename: String) -> io::Result<Channel<Vec<u8>>> {
 
     let (io_sender, io_receiver) = channel();
 
@@ -15,14 +18,14 @@ pub fn read(filename: String) -> io::Result<(Sender<Vec<u8>>, Receiver<Vec<u8>>)
     let buf_reader = BufReader::new(f);
 
     for line in buf_reader.lines() {
-        io_sender.send(line.unwrap().as_bytes().to_owned());
+        io_sender.send(line.unwrap().as_bytes().to_owned()).unwrap();
     }
-    Ok(((io_sender, io_receiver)))
+    Ok((io_sender, io_receiver))
 }
 
-pub fn read_and_transform_to_work_unit<'a>(filename: String) -> (Sender<Vec<u8>>, Receiver<Vec<u8>>) {
+pub fn read_and_transform_to_work_unit(filename: String) -> Channel<Vec<u8>> {
 
-    let (io_sender, io_receiver) = read(filename).unwrap();
+    let (_io_sender, io_receiver) = read(filename).unwrap();
     let (work_sender, work_receiver) = channel();
     loop {
         let result = io_receiver.recv_timeout(Duration::from_millis(100));
@@ -30,7 +33,7 @@ pub fn read_and_transform_to_work_unit<'a>(filename: String) -> (Sender<Vec<u8>>
             break;
         }
         let bytes = result.unwrap();
-        work_sender.send(bytes);
+        work_sender.send(bytes).unwrap();
     }
     return (work_sender, work_receiver);
 }
@@ -47,8 +50,7 @@ pub fn write(filename: String, io_out_receiver: Receiver<JoinHandle<Vec<u8>>>) -
         let val = result.unwrap().join().unwrap();
 
         //let val = result.join().unwrap();
-        let as_string = std::str::from_utf8(&val).unwrap();
-        buf_writer.write(&val).unwrap();
+        buf_writer.write_all(&val).unwrap();
     }
 
     buf_writer.flush().unwrap();
@@ -0,0 +1,16 @@
+// explicit returns are the style of this crate.
+#![allow(clippy::needless_return)]
+
+extern crate serde;
+extern crate serde_json;
+
+// the examples live in main.rs, the benchmarks in benches/.
+pub mod pipeline;
+#[cfg(test)]
+mod alloc_counter;
+pub mod stupid_work;
+pub mod io;
+pub mod graph;
+// not used by the examples yet.
+#[allow(dead_code)]
+mod future;
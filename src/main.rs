@@ -1,45 +1,62 @@
+// explicit returns are the style of this crate.
+#![allow(clippy::needless_return)]
+
 use std::sync::Arc;
 use std::rc::Rc;
-use std::thread::{spawn, sleep};
-
-use crate::graph::serial::{GraphNode, ComputeGraph};
-use crate::graph::easy_functions;
-use crate::graph::io_graph;
-use crate::graph::concurrent::{ConcurrentGraphNode, ConcurrentComputeGraph};
-use crate::pipeline::word_count::WordCount;
+use std::thread::spawn;
+
+use n1::graph::serial::{GraphNode, ComputeGraph};
+use n1::graph::easy_functions;
+use n1::graph::io_graph;
+use n1::graph::concurrent::{ConcurrentGraphNode, ConcurrentComputeGraph};
+use n1::graph::cache::{self, CacheConfig, CacheScope};
+use n1::graph::hybrid::HybridComputeGraph;
+use n1::graph::limits::{NodeLimits, Rate};
 use std::collections::HashMap;
-use std::borrow::BorrowMut;
-use std::env::var;
-use std::cell::RefCell;
 use uuid::Uuid;
-use atomic_refcell;
 use std::time::Duration;
-use crate::future::PollableFuture;
-
-extern crate serde;
-extern crate serde_json;
 
-mod pipeline;
-mod stupid_work;
-mod io;
-mod graph;
-mod future;
+use n1::{graph, io, pipeline, stupid_work};
 
 fn main() {
     println!("--- playing w graph");
     graph_example();
 
+    println!("--- playing w node ids");
+    node_ids_example();
+
+    println!("--- playing w subgraphs");
+    subgraph_example();
+
+    println!("--- playing w routing");
+    routing_example();
+
+    println!("--- playing w incremental graph");
+    incremental_graph_example();
+
     println!("--- playing w concurrent graph");
     concurrent_graph_example();
 
     println!("--- playing w io concurrent graph");
     concurrent_io_graph();
 
+    println!("--- playing w hybrid graph");
+    hybrid_graph_example();
+
+    println!("--- playing w node limits");
+    node_limits_example();
+    priority_scheduling_example();
+
+    println!("--- fan out benchmark");
+    fan_out_benchmark();
+
     println!("--- playing w a par map example");
     par_map_example();
 
     println!("--- playing w a simple map reduce");
     word_count();
+    word_count_job();
+    join_example();
 }
 
 fn par_map_example() {
@@ -55,9 +72,9 @@ fn par_map_example() {
     // open io in
     let (work_sender, work_receiver) = io::read_and_transform_to_work_unit(filename_in);
     // open compute
-    let (io_out_sender, io_out_receiver) = map_like_seq.compute_async(work_sender, work_receiver);
+    let (_io_out_sender, io_out_receiver) = map_like_seq.compute_async(work_sender, work_receiver);
     // io out:
-    io::write(filename_out, io_out_receiver);
+    io::write(filename_out, io_out_receiver).unwrap();
 }
 
 fn concurrent_graph_example() {
@@ -85,13 +102,104 @@ fn concurrent_graph_example() {
                                                    String::from("start node"),
                                                    vec![Arc::clone(&mid_node1), Arc::clone(&mid_node2)]));
 
-    let concurrent_graph = ConcurrentComputeGraph::new(Arc::clone(&start_node));
+    let concurrent_graph = ConcurrentComputeGraph::new(Arc::clone(&start_node)).unwrap();
 
     let handle = spawn(move || {
         return concurrent_graph.apply_batch(vec![vec![1.0, 2.0], vec![5.0, 5.0]]);
     });
     let results = handle.join().unwrap();
-    println!("batch mode: {:?}", results)
+    println!("batch mode: {:?}", results);
+
+    let (optimized_graph, report) = ConcurrentComputeGraph::optimized(Arc::clone(&start_node)).unwrap();
+    println!("optimized graph: {}", report);
+    println!("optimized batch mode: {:?}", optimized_graph.apply_batch(vec![vec![1.0, 2.0], vec![5.0, 5.0]]));
+
+    // callers sharing the graph share the cache, repeated inputs skip the cached nodes.
+    let mut cached_graph = ConcurrentComputeGraph::new(Arc::clone(&start_node)).unwrap();
+    cached_graph.enable_cache("last node", CacheConfig { capacity: 16, scope: CacheScope::Subgraph, hasher: cache::hash_f64s });
+    cached_graph.enable_cache("mid node 2", CacheConfig { capacity: 16, scope: CacheScope::Node, hasher: cache::hash_f64s });
+    let cached_graph = Arc::new(cached_graph);
+    let handles: Vec<_> = vec![vec![1.0, 2.0], vec![1.0, 2.0], vec![5.0, 5.0], vec![1.0, 2.0]].into_iter().map(|datum| {
+        let graph = Arc::clone(&cached_graph);
+        return spawn(move || graph.apply(datum));
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    println!("cache stats: {:?}", cached_graph.cache_stats());
+    println!("metrics: {}", serde_json::to_string(&cached_graph.metrics().snapshot().nodes["last node"]).unwrap());
+    println!("mid node 2 only: {:?}", cached_graph.evaluate("mid node 2", vec![1.0, 2.0]));
+    println!("structure: {}", cached_graph.view().to_json());
+
+    let trace = cached_graph.apply_traced(vec![3.0, 3.0]);
+    for (name, records) in trace.nodes.iter() {
+        println!("traced {}: {:?}", name, records);
+    }
+}
+
+fn incremental_graph_example() {
+    //                      MULTI INPUT GRAPH
+    //
+    // add_one (source a)  ----------------------- \
+    //                                               square (merge)
+    // add_five (source b) --- add_one (b plus one) /
+    //
+    let merge = Rc::new(GraphNode::new(easy_functions::square, String::from("merge"), vec![]));
+    let b_plus_one = Rc::new(GraphNode::new(easy_functions::add_one, String::from("b plus one"), vec![Rc::clone(&merge)]));
+    let source_a = Rc::new(GraphNode::new(easy_functions::add_one, String::from("source a"), vec![Rc::clone(&merge)]));
+    let source_b = Rc::new(GraphNode::new(easy_functions::add_five, String::from("source b"), vec![Rc::clone(&b_plus_one)]));
+
+    let compute_graph = ComputeGraph::with_sources(vec![source_a, source_b]).unwrap();
+
+    let mut inputs = HashMap::new();
+    inputs.insert(String::from("source a"), vec![1.0]);
+    inputs.insert(String::from("source b"), vec![2.0]);
+    println!("first run {:?}, recomputed {:?}", compute_graph.apply_incremental(inputs), compute_graph.last_recomputed());
+
+    // only source b changed, source a keeps its last output.
+    let mut inputs = HashMap::new();
+    inputs.insert(String::from("source b"), vec![3.0]);
+    println!("second run {:?}, recomputed {:?}", compute_graph.apply_incremental(inputs), compute_graph.last_recomputed());
+}
+
+fn fan_out_benchmark() {
+    //                      WIDE FAN OUT
+    //
+    //                     add_one (branch 0)
+    //                   /                    \
+    // add_one (source) ---   ...  (x 32)   ---  square (sink)
+    //                   \                    /
+    //                     add_one (branch 31)
+    //
+    // the source output is read by 32 branches, none of them should copy it.
+    // allocations are counted by the tests of each executor (see `alloc_counter`), `benches/fan_out.rs` times it properly.
+    let n_branches = 32;
+    let datum: Vec<f64> = (0..100_000).map(|x| x as f64).collect();
+
+    let sink = Arc::new(ConcurrentGraphNode::new(easy_functions::square, String::from("sink"), vec![]));
+    let branches = (0..n_branches).map(|i| {
+        Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, format!("branch {}", i), vec![Arc::clone(&sink)]))
+    }).collect();
+    let source = Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("source"), branches));
+
+    let serial_sink = Rc::new(GraphNode::new(easy_functions::square, String::from("sink"), vec![]));
+    let serial_branches = (0..n_branches).map(|i| {
+        Rc::new(GraphNode::new(easy_functions::add_one, format!("branch {}", i), vec![Rc::clone(&serial_sink)]))
+    }).collect();
+    let serial_source = Rc::new(GraphNode::new(easy_functions::add_one, String::from("source"), serial_branches));
+
+    let serial_graph = ComputeGraph::new(serial_source).unwrap();
+    let concurrent_graph = ConcurrentComputeGraph::new(Arc::clone(&source)).unwrap();
+    let io_graph = io_graph::IoConcurrentComputeGraph::new(Arc::clone(&source)).unwrap();
+
+    let measure = |name: &str, run: &dyn Fn() -> Vec<f64>| {
+        let start = std::time::Instant::now();
+        run();
+        println!("{}: {:?}", name, start.elapsed());
+    };
+    measure("serial graph", &|| serial_graph.apply(datum.clone()));
+    measure("concurrent graph", &|| concurrent_graph.apply(datum.clone()));
+    measure("io graph", &|| io_graph.apply(datum.clone()));
 }
 
 fn graph_example() {
@@ -110,33 +218,421 @@ fn graph_example() {
                                                   vec![]));
     let mid_node1 = Rc::new(GraphNode::new(easy_functions::add_one,
                                                   String::from("mid node 1"),
-                                                  vec![Rc::clone(&last_node)]));
+                                                  vec![Rc::clone(&last_node)]).with_operator("add one"));
     let mid_node2 = Rc::new(GraphNode::new(easy_functions::add_one,
                                                   String::from("mid node 2"),
-                                                  vec![Rc::clone(&last_node)]));
+                                                  vec![Rc::clone(&last_node)]).with_operator("add one"));
     let start_node = Rc::new(GraphNode::new(easy_functions::add_one,
                                                    String::from("start node"),
                                                    vec![Rc::clone(&mid_node1), Rc::clone(&mid_node2)]));
 
-    let compute_graph = ComputeGraph::new(start_node);
+    let compute_graph = ComputeGraph::new(Rc::clone(&start_node)).unwrap();
     let applied_all = compute_graph.apply(vec![1.0, 2.0]);
     println!("single mode {:?}", applied_all);
     let batch_result = compute_graph.apply_batch(vec![vec![1.0, 2.0]]);
     println!("batch mode {:?}", batch_result);
+
+    // mid node 1 and mid node 2 share their operator and parent, the optimizer keeps only one of them.
+    let (optimized_graph, report) = ComputeGraph::optimized(Rc::clone(&start_node)).unwrap();
+    println!("optimized graph: {}", report);
+    println!("optimized single mode {:?}", optimized_graph.apply(vec![1.0, 2.0]));
+
+    // only start node and mid node 1 are computed.
+    println!("mid node 1 only {:?}", compute_graph.evaluate("mid node 1", vec![1.0, 2.0]));
+
+    let view = optimized_graph.view();
+    println!("optimized graph: {} nodes, topological order {:?}, longest path {:?}",
+             view.nodes().len(), view.topological_order(), view.longest_path());
+    println!("sources: {:?}, sinks: {:?}, edges: {}, depth: {}, last node fan in: {}",
+             view.sources().iter().map(|node| &node.name).collect::<Vec<_>>(),
+             view.sinks().iter().map(|node| &node.name).collect::<Vec<_>>(),
+             view.edges().len(),
+             view.depth(),
+             view.node("last node").unwrap().fan_in());
+
+    // what every node read and produced, as json.
+    println!("traced {}", compute_graph.apply_traced(vec![1.0, 2.0]).to_json());
+}
+
+fn node_ids_example() {
+    // ids are derived from the name, the operator and the parents of every node,
+    // so that a graph built twice (e.g. on two runs, or by two builds of the binary) gets the same ids.
+    let build = |sink_id: Option<Uuid>| {
+        let last_node = match sink_id {
+            Some(id) => Rc::new(GraphNode::with_id(easy_functions::square, String::from("last node"), vec![], id)),
+            None => Rc::new(GraphNode::new(easy_functions::square, String::from("last node"), vec![])),
+        };
+        let mid_node = Rc::new(GraphNode::new(easy_functions::add_five,
+                                              String::from("mid node"),
+                                              vec![Rc::clone(&last_node)]));
+        return ComputeGraph::new(Rc::new(GraphNode::new(easy_functions::add_one,
+                                                        String::from("start node"),
+                                                        vec![mid_node]))).unwrap();
+    };
+    let ids = build(None).node_ids();
+    println!("same ids when rebuilt: {}", ids == build(None).node_ids());
+
+    // an explicit id only changes the id of that node.
+    let pinned = build(Some(Uuid::from_u128(42))).node_ids();
+    println!("pinned last node: {}, start node unchanged: {}", pinned["last node"], pinned["start node"] == ids["start node"]);
+
+    // the concurrent executor derives the same ids from the same graph.
+    let build_concurrent = |sink_id: Option<Uuid>| {
+        let last_node = match sink_id {
+            Some(id) => Arc::new(ConcurrentGraphNode::with_id(easy_functions::square, String::from("last node"), vec![], id)),
+            None => Arc::new(ConcurrentGraphNode::empty(easy_functions::square, String::from("last node"))),
+        };
+        let mid_node = Arc::new(ConcurrentGraphNode::new(easy_functions::add_five,
+                                                         String::from("mid node"),
+                                                         vec![last_node]));
+        return ConcurrentComputeGraph::new(Arc::new(ConcurrentGraphNode::new(easy_functions::add_one,
+                                                                           String::from("start node"),
+                                                                           vec![mid_node]))).unwrap();
+    };
+    println!("same ids in the concurrent graph: {}, pinned: {}",
+             build_concurrent(None).node_ids() == ids, build_concurrent(Some(Uuid::from_u128(42))).node_ids() == pinned);
+}
+
+fn subgraph_example() {
+    //                      COMPOSED GRAPH
+    //
+    //                  / a/add one -> a/square \
+    // add_five (start)                          add_one (end)
+    //                  \ b/add one -> b/square /
+    //
+    // the same reusable piece, embedded twice under two namespaces.
+    let square = Rc::new(GraphNode::new(easy_functions::square, String::from("square"), vec![]));
+    let piece = ComputeGraph::new(Rc::new(GraphNode::new(easy_functions::add_one, String::from("add one"), vec![square]))).unwrap();
+
+    let end_node = Rc::new(GraphNode::new(easy_functions::add_one, String::from("end"), vec![]));
+    let branch_a = GraphNode::subgraph(&piece, "a", vec![Rc::clone(&end_node)]);
+    let branch_b = GraphNode::subgraph(&piece, "b", vec![Rc::clone(&end_node)]);
+    let start_node = Rc::new(GraphNode::new(easy_functions::add_five, String::from("start"), vec![branch_a, branch_b]));
+    let composed = ComputeGraph::new(start_node).unwrap();
+    println!("composed {:?}: {:?}", composed.view().topological_order(), composed.apply(vec![1.0, 2.0]));
+
+    // sink to source chaining.
+    let chained = piece.then(&composed).unwrap();
+    println!("chained {:?}: {:?}", chained.view().longest_path(), chained.apply(vec![1.0, 2.0]));
+
+    let concurrent_piece = ConcurrentComputeGraph::new(Arc::new(ConcurrentGraphNode::new(easy_functions::add_one,
+                                                                                         String::from("add one"),
+                                                                                         vec![]))).unwrap();
+    let wrapped = ConcurrentGraphNode::subgraph(&concurrent_piece, "inner", vec![]);
+    let concurrent_chained = ConcurrentComputeGraph::new(wrapped).unwrap().then(&concurrent_piece).unwrap();
+    println!("concurrent chained {:?}: {:?}", concurrent_chained.view().topological_order(), concurrent_chained.apply(vec![1.0, 2.0]));
+}
+
+fn routing_example() {
+    //                      ROUTED GRAPH
+    //
+    //                   / square (negative) ----------------------- \
+    // add_one (router)                                               add_one (end)
+    //                   \ add_five (positive) -> filter (below 100) /
+    //
+    // the router only sends its output to one branch, the filter drops data >= 100,
+    // the end node joins whatever reached it and does not fire when nothing did.
+    let end_node = Rc::new(GraphNode::new(easy_functions::add_one, String::from("end"), vec![]));
+    let below_hundred = Rc::new(GraphNode::filter(graph::passthrough, String::from("below 100"), vec![Rc::clone(&end_node)], easy_functions::below_hundred));
+    let positive = Rc::new(GraphNode::new(easy_functions::add_five, String::from("positive"), vec![below_hundred]));
+    let negative = Rc::new(GraphNode::new(easy_functions::square, String::from("negative"), vec![Rc::clone(&end_node)]));
+    let router = Rc::new(GraphNode::switch(easy_functions::add_one, String::from("router"), vec![negative, positive], easy_functions::by_sign));
+    let routed_graph = ComputeGraph::new(router).unwrap();
+    for datum in [vec![-3.0, 1.0], vec![1.0, 2.0], vec![200.0, 1.0]] {
+        let trace = routed_graph.apply_traced(datum.clone());
+        println!("{:?} -> {:?}, fired: {:?}", datum, trace.output, trace.nodes.keys().collect::<Vec<_>>());
+    }
+    println!("batch mode: {:?}", routed_graph.apply_batch(vec![vec![-3.0, 1.0], vec![1.0, 2.0], vec![200.0, 1.0]]));
+
+    // same graph, on the concurrent and io executors.
+    let end_node = Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("end"), vec![]));
+    let below_hundred = Arc::new(ConcurrentGraphNode::filter(graph::passthrough, String::from("below 100"), vec![Arc::clone(&end_node)], easy_functions::below_hundred));
+    let positive = Arc::new(ConcurrentGraphNode::new(easy_functions::add_five, String::from("positive"), vec![below_hundred]));
+    let negative = Arc::new(ConcurrentGraphNode::new(easy_functions::square, String::from("negative"), vec![Arc::clone(&end_node)]));
+    let router = Arc::new(ConcurrentGraphNode::switch(easy_functions::add_one, String::from("router"), vec![negative, positive], easy_functions::by_sign));
+    let concurrent_graph = ConcurrentComputeGraph::new(Arc::clone(&router)).unwrap();
+    // the io graph forwards the datum of its root as is.
+    let io_graph = io_graph::IoConcurrentComputeGraph::new(Arc::new(ConcurrentGraphNode::new(graph::passthrough, String::from("input"), vec![router]))).unwrap();
+    for datum in [vec![-3.0, 1.0], vec![1.0, 2.0], vec![200.0, 1.0]] {
+        println!("{:?} -> concurrent {:?}, io {:?}", datum, concurrent_graph.apply(datum.clone()), io_graph.apply(datum.clone()));
+    }
+}
+
+fn hybrid_graph_example() {
+    //                      HYBRID GRAPH
+    //
+    //                   / slow_add_one (fetch a, io) -> slow_add_one (fetch a again, io) \
+    // add_one (start)  -- slow_add_one (fetch b, io) -------------------------------------- add_five (join)
+    //                   \ square (cpu) ------------------------------------------------------/
+    //
+    // the two fetches wait on the pool at the same time, while square runs inline.
+    // fetch a and fetch b read the same thing, the optimizer only keeps one of them.
+    let join_node = Arc::new(ConcurrentGraphNode::new(easy_functions::add_five, String::from("join"), vec![]));
+    let fetch_a_again = Arc::new(ConcurrentGraphNode::io(easy_functions::slow_add_one, String::from("fetch a again"), vec![Arc::clone(&join_node)]));
+    let fetch_a = Arc::new(ConcurrentGraphNode::io(easy_functions::slow_add_one, String::from("fetch a"), vec![fetch_a_again]).with_operator("fetch"));
+    let fetch_b = Arc::new(ConcurrentGraphNode::io(easy_functions::slow_add_one, String::from("fetch b"), vec![Arc::clone(&join_node)]).with_operator("fetch"));
+    let square = Arc::new(ConcurrentGraphNode::new(easy_functions::square, String::from("square"), vec![Arc::clone(&join_node)]));
+    let start_node = Arc::new(ConcurrentGraphNode::new(easy_functions::add_one, String::from("start"), vec![fetch_a, fetch_b, square]));
+
+    let started = std::time::Instant::now();
+    let inline_result = ConcurrentComputeGraph::new(Arc::clone(&start_node)).unwrap().apply(vec![1.0, 2.0]);
+    println!("everything inline: {:?} in {:?}", inline_result, started.elapsed());
+
+    let hybrid_graph = HybridComputeGraph::new(Arc::clone(&start_node), 4).unwrap();
+    let started = std::time::Instant::now();
+    let trace = hybrid_graph.apply_traced(vec![1.0, 2.0]);
+    println!("hybrid: {:?} in {:?}", trace.output, started.elapsed());
+    for segment in trace.segments.iter() {
+        println!("segment {:?} on thread {}", segment.nodes, segment.thread);
+    }
+    for (name, records) in trace.nodes.iter() {
+        println!("{} ran on thread {}", name, records[0].thread);
+    }
+    println!("queue wait: {} segments", hybrid_graph.metrics().snapshot().queue_wait.count);
+
+    let (optimized_graph, report) = HybridComputeGraph::optimized(start_node, 4).unwrap();
+    println!("optimized hybrid graph: {}", report);
+    println!("optimized hybrid: {:?}, topological order {:?}", optimized_graph.apply(vec![1.0, 2.0]), optimized_graph.view().topological_order());
+}
+
+fn node_limits_example() {
+    // 8 concurrent calls to a graph reading from a slow service, which only takes 2 calls at a time,
+    // and no more than 20 calls per second.
+    let sink = Arc::new(ConcurrentGraphNode::new(easy_functions::square, String::from("sink"), vec![]));
+    let service = Arc::new(ConcurrentGraphNode::new(easy_functions::slow_add_one, String::from("service"), vec![sink]));
+    let input = Arc::new(ConcurrentGraphNode::new(graph::passthrough, String::from("input"), vec![service]));
+    let mut limited_graph = io_graph::IoConcurrentComputeGraph::new(input).unwrap();
+    limited_graph.limit("service", NodeLimits {
+        max_in_flight: Some(2),
+        rate: Some(Rate { per_second: 20., burst: 2 }),
+    });
+
+    let limited_graph = Arc::new(limited_graph);
+    let started = std::time::Instant::now();
+    let handles: Vec<_> = (0..8).map(|i| {
+        let graph = Arc::clone(&limited_graph);
+        return spawn(move || graph.apply(vec![i as f64]));
+    }).collect();
+    let results: Vec<Vec<f64>> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+    let service_metrics = &limited_graph.metrics().snapshot().nodes["service"];
+    println!("{:?} in {:?}, service waited {}ms over {} calls",
+             results, started.elapsed(), service_metrics.limit_wait.sum_micros / 1000, service_metrics.limit_wait.count);
+}
+
+fn priority_scheduling_example() {
+    // three independent slow nodes, and a chain of three slow nodes, on 2 workers.
+    // ranked by remaining critical path, the chain starts first and the graph takes 3 calls instead of 4.
+    // before the first run, every node costs the same and the path with the most nodes wins.
+    let build = || {
+        let sink = Arc::new(ConcurrentGraphNode::new(easy_functions::add_five, String::from("sink"), vec![]));
+        let long3 = Arc::new(ConcurrentGraphNode::new(easy_functions::slow_add_one, String::from("long 3"), vec![Arc::clone(&sink)]));
+        let long2 = Arc::new(ConcurrentGraphNode::new(easy_functions::slow_add_one, String::from("long 2"), vec![long3]));
+        let long1 = Arc::new(ConcurrentGraphNode::new(easy_functions::slow_add_one, String::from("long 1"), vec![long2]));
+        let mut children: Vec<_> = (1..4).map(|i| Arc::new(ConcurrentGraphNode::new(
+            easy_functions::slow_add_one, format!("short {}", i), vec![Arc::clone(&sink)]))).collect();
+        children.push(long1);
+        let root = Arc::new(ConcurrentGraphNode::new(graph::passthrough, String::from("root"), children));
+        let mut graph = io_graph::IoConcurrentComputeGraph::new(root).unwrap();
+        graph.set_workers(2);
+        return graph;
+    };
+
+    let graph = build();
+    for run in 0..2 {
+        let started = std::time::Instant::now();
+        let result = graph.apply(vec![1.]);
+        println!("run {}: {:?} in {:?}", run, result, started.elapsed());
+    }
+
+    // a declared cost takes precedence: claiming the short nodes are the expensive ones delays the chain.
+    let mut misranked_graph = build();
+    for i in 1..4 {
+        misranked_graph.set_cost(&format!("short {}", i), Duration::from_secs(1));
+    }
+    let started = std::time::Instant::now();
+    let result = misranked_graph.apply(vec![1.]);
+    println!("misranked: {:?} in {:?}", result, started.elapsed());
 }
 
 fn word_count() {
     let line = String::from("hello world yo universe hello yp yo yop");
     let bytes = line.as_bytes().to_vec();
-    let word_count_pipeline = pipeline::map_reduce::MapReduce {
-        map_func: pipeline::word_count::word_count_mapper,
-        reduce_func: pipeline::word_count::word_count_reducer,
-    };
+    // the keyable mapper, through the adapter.
+    let word_count_pipeline = pipeline::map_reduce::MapReduce::from_keyable(
+        pipeline::word_count::word_count_mapper,
+        pipeline::word_count::word_count_reducer);
 
-    let reduce_output = word_count_pipeline.apply(bytes);
+    let (reduce_output, _) = word_count_pipeline.apply(bytes);
     for (k, v) in reduce_output.iter() {
         println!("{}, {}", k, v);
     }
+
+    // the same counts, with the text emitted in 8 splits cut on spaces.
+    let text = std::fs::read("data/data.txt").unwrap();
+    let mut parallel_pipeline = pipeline::map_reduce::MapReduce::new(
+        pipeline::word_count::word_count_emitter,
+        pipeline::word_count::word_count_reducer);
+    parallel_pipeline.set_splits(8, b' ');
+    let (mut serial_counts, _) = word_count_pipeline.apply(text.clone());
+    let (mut parallel_counts, report) = parallel_pipeline.apply(text.clone());
+    serial_counts.sort();
+    parallel_counts.sort();
+    println!("{} words, same counts in 8 splits: {}", serial_counts.len(), serial_counts == parallel_counts);
+
+    // summing the counts of every split before the shuffle.
+    parallel_pipeline.set_combiner(pipeline::word_count::word_count_reducer);
+    let (mut combined_counts, combined_report) = parallel_pipeline.apply(text.clone());
+    combined_counts.sort();
+    println!("same counts with a combiner: {}, shuffling {} records instead of {}",
+             serial_counts == combined_counts, combined_report.shuffled_records, report.shuffled_records);
+    println!("{} bytes read, {} numbers", report.input_records, report.counters["numbers"]);
+
+    // every split holding no more than 16 records in memory: splits spill sorted runs to disk, merged back by the reducers.
+    let mut spilling_pipeline = pipeline::map_reduce::MapReduce::new(
+        pipeline::word_count::word_count_emitter,
+        pipeline::word_count::word_count_reducer);
+    spilling_pipeline.set_splits(8, b' ');
+    spilling_pipeline.set_partitions(2);
+    spilling_pipeline.set_spill_threshold(16);
+    spilling_pipeline.set_progress(|progress| if progress.done == progress.total {
+        println!("{:?} done ({} of {})", progress.phase, progress.done, progress.total);
+    });
+    let (mut spilled_counts, spilled_report) = spilling_pipeline.apply(text);
+    spilled_counts.sort();
+    println!("same counts spilling {} runs to disk: {}", spilled_report.spilled_runs, serial_counts == spilled_counts);
+
+    // the reducer emits a mean (f64) from lengths (i32), and nothing for the letters starting few words.
+    let mean_length_pipeline = pipeline::map_reduce::MapReduce::new(
+        pipeline::word_count::word_length_emitter,
+        pipeline::word_count::mean_length_reducer);
+    let (mut mean_lengths, mean_length_report) = mean_length_pipeline.apply(std::fs::read("data/data.txt").unwrap());
+    mean_lengths.sort_by(|(a, _), (b, _)| a.cmp(b));
+    println!("mean word length by first letter: {:?}, {} rare letters", mean_lengths, mean_length_report.counters["rare letters"]);
+
+    // sorted output, whatever the number of partitions: by word, then by length.
+    let mut sorted_pipeline = pipeline::map_reduce::MapReduce::new(
+        pipeline::word_count::word_count_emitter,
+        pipeline::word_count::word_count_reducer);
+    sorted_pipeline.set_partitions(3);
+    sorted_pipeline.set_sorted();
+    println!("sorted: {:?}", sorted_pipeline.apply(line.as_bytes().to_vec()).0);
+    sorted_pipeline.set_key_order(pipeline::word_count::by_length);
+    sorted_pipeline.set_spill_threshold(4);
+    println!("sorted by length, spilling: {:?}", sorted_pipeline.apply(line.as_bytes().to_vec()).0);
+
+    // secondary sort: the words of every letter are read in alphabetical order.
+    let mut word_list_pipeline = pipeline::map_reduce::MapReduce::new(
+        pipeline::word_count::first_letter_emitter,
+        pipeline::word_count::word_list_reducer);
+    word_list_pipeline.set_splits(4, b' ');
+    word_list_pipeline.set_sorted();
+    word_list_pipeline.set_value_order(String::cmp);
+    let (word_lists, _) = word_list_pipeline.apply(std::fs::read("data/data.txt").unwrap());
+    let (_, s_words) = word_lists.iter().find(|(letter, _)| letter == "s").unwrap();
+    println!("words starting with s: {:?}", s_words);
+
+    // 3 partitions reduced in parallel, each holding a range of the alphabet.
+    parallel_pipeline.set_partitions(3);
+    parallel_pipeline.set_partitioner(pipeline::word_count::AlphabetPartitioner);
+    let (partitions, _) = parallel_pipeline.apply_partitioned(line.as_bytes().to_vec());
+    for (i, partition) in partitions.iter().enumerate() {
+        let mut words: Vec<&String> = partition.iter().map(|(word, _)| word).collect();
+        words.sort();
+        println!("partition {}: {:?}", i, words);
+    }
+}
+
+fn word_count_job() {
+    // word count, then the 3 most frequent words of every letter, along with the mean word length by letter,
+    // which does not depend on word count and runs along with it.
+    // a job is consumed by its run, so it is built again for the second run.
+    let checkpoints = std::env::temp_dir().join(format!("word_count_job_{}", std::process::id()));
+    let build = || {
+        let mut job = pipeline::job::Job::new();
+        let text = job.input("text", std::fs::read("data/data.txt").unwrap()).unwrap();
+
+        let mut word_count = pipeline::map_reduce::MapReduce::new(
+            pipeline::word_count::word_count_emitter,
+            pipeline::word_count::word_count_reducer);
+        word_count.set_splits(4, b' ');
+        let counts = job.stage("word count", &text, word_count).unwrap();
+        // read by "top 3 words by letter", kept to print the number of words.
+        job.keep(&counts).unwrap();
+
+        let mut top_three = pipeline::map_reduce::MapReduce::new(
+            pipeline::word_count::letter_count_emitter,
+            pipeline::word_count::top_three_reducer);
+        top_three.set_record_splits(4);
+        top_three.set_sorted();
+        // most frequent first, ties in alphabetical order.
+        top_three.set_value_order(|(count_a, word_a), (count_b, word_b)| count_b.cmp(count_a).then_with(|| word_a.cmp(word_b)));
+        let top_words = job.stage("top 3 words by letter", &counts, top_three).unwrap();
+        // to bump once the value order or the reducer change, so that checkpoints of this version are not loaded.
+        job.set_version(&top_words, 1).unwrap();
+
+        let mean_lengths = job.stage("mean length", &text, pipeline::map_reduce::MapReduce::new(
+            pipeline::word_count::word_length_emitter,
+            pipeline::word_count::mean_length_reducer)).unwrap();
+
+        job.set_checkpoints(&checkpoints);
+        return (job, counts, top_words, mean_lengths);
+    };
+
+    // the second run loads every stage from its checkpoint.
+    for run in 0..2 {
+        let (job, counts, top_words, mean_lengths) = build();
+        let started = std::time::Instant::now();
+        let output = job.run();
+        let (_, top_d) = output.get(&top_words).unwrap().iter().find(|(letter, _)| letter == "d").unwrap();
+        // stages loaded from their checkpoint have no report.
+        let ran = output.report(&counts).map_or(String::from("loaded"), |report| format!("{} records mapped", report.map_output_records));
+        println!("run {} in {:?}: {} words ({}), {} letters, top d words {:?}",
+                 run, started.elapsed(), output.get(&counts).unwrap().len(), ran, output.get(&mean_lengths).unwrap().len(), top_d);
+    }
+    std::fs::remove_dir_all(checkpoints).unwrap();
+}
+
+fn join_example() {
+    use n1::pipeline::join;
+
+    // every word of the text along with its position, joined with a dictionary.
+    // "de" is skewed: besides its occurrences in the text, it is repeated 20000 times, and translated twice.
+    let text = String::from_utf8(std::fs::read("data/data.txt").unwrap()).unwrap().to_lowercase();
+    let mut words: Vec<(String, usize)> = text.split_whitespace().enumerate()
+        .map(|(position, word)| (String::from(word), position))
+        .collect();
+    words.extend((0..20000).map(|i| (String::from("de"), 100000 + i)));
+    let dictionary: Vec<(String, String)> = vec![("de", "of"), ("de", "from"), ("la", "the"), ("le", "the"), ("stade", "stadium"), ("zèbre", "zebra")]
+        .into_iter()
+        .map(|(word, translation)| (String::from(word), String::from(translation)))
+        .collect();
+
+    let mut inner_join = join::inner();
+    inner_join.set_record_splits(4);
+    inner_join.set_partitions(4);
+    let started = std::time::Instant::now();
+    let (mut inner, report) = inner_join.apply(join::tag(words.clone(), dictionary.clone()));
+    println!("inner join: {} records from {} keys in {:?}", inner.len(), report.distinct_keys, started.elapsed());
+
+    // the same pairs, joined map side.
+    let started = std::time::Instant::now();
+    let mut broadcast = join::broadcast_inner(words.clone(), &dictionary);
+    println!("broadcast join: {} records in {:?}", broadcast.len(), started.elapsed());
+    inner.sort();
+    broadcast.sort();
+    println!("same records: {}", inner == broadcast);
+
+    let (mut left, _) = join::left().apply(join::tag(words.clone(), dictionary.clone()));
+    let unmatched = left.iter().filter(|(_, (_, translation))| translation.is_none()).count();
+    println!("left join: {} records, {} words without translation", left.len(), unmatched);
+    let mut broadcast_left = join::broadcast_left(words.clone(), &dictionary);
+    left.sort();
+    broadcast_left.sort();
+    println!("same records as broadcast: {}", left == broadcast_left);
+
+    let (full, _) = join::full_outer().apply(join::tag(words, dictionary));
+    let unused: Vec<&String> = full.iter().filter(|(_, (position, _))| position.is_none()).map(|(word, _)| word).collect();
+    println!("full outer join: {} records, translations of no word: {:?}", full.len(), unused);
 }
 
 fn concurrent_io_graph() {
@@ -163,35 +659,35 @@ node_1 /                       node_8
         easy_functions::add_one,
         String::from("node 8"),
         vec![],
-    ));
+    ).with_operator("add one"));
 
     let mid_node7 = Arc::new(
         graph::concurrent::ConcurrentGraphNode::new(
             easy_functions::add_one,
             String::from("node 7"),
             vec![Arc::clone(&node8)],
-        ));
+        ).with_operator("add one"));
 
     let mid_node3 = Arc::new(
         graph::concurrent::ConcurrentGraphNode::new(
             easy_functions::add_one,
             String::from("node 3"),
             vec![Arc::clone(&node8)],
-        ));
+        ).with_operator("add one"));
 
     let mid_node5 = Arc::new(
         graph::concurrent::ConcurrentGraphNode::new(
             easy_functions::add_one,
             String::from("node 5"),
             vec![Arc::clone(&mid_node7)],
-            ));
+            ).with_operator("add one"));
 
     let mid_node6 = Arc::new(
         graph::concurrent::ConcurrentGraphNode::new(
             easy_functions::add_one,
             String::from("node 6"),
             vec![Arc::clone(&mid_node7)],
-            )
+            ).with_operator("add one")
     );
 
     let mid_node4 = Arc::new(
@@ -199,7 +695,7 @@ node_1 /                       node_8
             easy_functions::add_one,
             String::from("node 4"),
             vec![Arc::clone(&mid_node5), Arc::clone(&mid_node6)],
-            )
+            ).with_operator("add one")
     );
 
     let mid_node2 = Arc::new(
@@ -207,17 +703,46 @@ node_1 /                       node_8
             easy_functions::add_one,
             String::from("node 2"),
             vec![Arc::clone(&mid_node3)],
-            )
+            ).with_operator("add one")
     );
 
     let node1 = Arc::new(
         graph::concurrent::ConcurrentGraphNode::new(
             easy_functions::add_one,
             String::from("node 1"),
-            vec![Arc::clone(&mid_node2), Arc::clone(&mid_node4)])
+            vec![Arc::clone(&mid_node2), Arc::clone(&mid_node4)]).with_operator("add one")
     );
 
-    let computable_graph = io_graph::IoConcurrentComputeGraph::new(Arc::clone(&node1));
+    let computable_graph = io_graph::IoConcurrentComputeGraph::new(Arc::clone(&node1)).unwrap();
     let results = computable_graph.apply(vec![1.0, 2.0]);
     println!("{:?}", results);
+
+    // every node is the "add one" operator, so branches starting from node 1 collapse into a single chain.
+    let (optimized_graph, report) = io_graph::IoConcurrentComputeGraph::optimized(Arc::clone(&node1)).unwrap();
+    println!("optimized graph: {}", report);
+    println!("{:?}", optimized_graph.apply(vec![1.0, 2.0]));
+
+    let trace = computable_graph.apply_traced(vec![1.0, 2.0]);
+    for (name, records) in trace.nodes.iter() {
+        let outputs: Vec<&Vec<f64>> = records.iter().map(|record| &record.output).collect();
+        println!("traced {}: {:?}", name, outputs);
+    }
+
+    // open in chrome://tracing or https://ui.perfetto.dev to see the segments overlap.
+    let trace_path = std::env::temp_dir().join("io_graph_trace.json");
+    std::fs::write(&trace_path, trace.to_chrome_trace()).unwrap();
+    println!("{} segments, timeline written to {:?}", trace.segments.len(), trace_path);
+
+    // where the io graph forks and joins.
+    for node in computable_graph.view().nodes() {
+        let io = node.io.unwrap();
+        println!("{}: fan in {}, fan out {}, forkable: {}, n_parents: {}", node.name, node.fan_in(), node.fan_out(), io.forkable, io.n_parents);
+    }
+
+    let snapshot = computable_graph.metrics().snapshot();
+    println!("graph calls: {}, node 7 calls: {}, queue wait: {:?}",
+             snapshot.graph.calls, snapshot.nodes["node 7"].calls, snapshot.queue_wait);
+    let metrics_path = std::env::temp_dir().join("io_graph.prom");
+    snapshot.write_prometheus("io graph", metrics_path.to_str().unwrap()).unwrap();
+    println!("metrics written to {:?}", metrics_path);
 }
\ No newline at end of file
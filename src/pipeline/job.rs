@@ -0,0 +1,567 @@
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, BufWriter};
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Instant;
+use log::debug;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+
+use crate::pipeline::map_reduce::MapReduce;
+use crate::pipeline::report::JobReport;
+
+// *******************************
+// jobs.
+// A job chains MapReduce stages: the records a stage reduces to are the input of the stages reading it,
+// as they are (no encoding to bytes in between). Stages start as soon as their input is ready, so stages
+// that do not depend on each other run concurrently.
+// A dataset is moved to the last stage reading it, the other stages reading it map a copy.
+// With a checkpoint directory, the output of every stage is saved once it is done, and a stage that
+// was saved by a previous run, from the same inputs and stage versions, is loaded instead of run again.
+// *******************************
+type Data = Arc<dyn Any + Send + Sync>;
+
+type RunStage = Box<dyn Fn(Data) -> (Data, JobReport) + Send + Sync>;
+type SaveStage = Box<dyn Fn(&Data, &Path) + Send + Sync>;
+type LoadStage = Box<dyn Fn(&Path) -> Data + Send + Sync>;
+
+static JOBS: AtomicUsize = AtomicUsize::new(0);
+
+// the records of an input, or the output of a stage.
+pub struct Dataset<T> {
+    // the job the dataset belongs to.
+    job: usize,
+    id: usize,
+    records: PhantomData<fn() -> T>,
+}
+
+// deriving would require T: Copy.
+impl <T> Clone for Dataset<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl <T> Copy for Dataset<T> {}
+
+// why a dataset could not be added to a job, or read from its output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobError {
+    // names must be unique within a job.
+    DuplicateName(String),
+    // (first name, second name): once sanitized, both names are the same checkpoint file name.
+    CheckpointCollision(String, String),
+    // the dataset was created by another job.
+    ForeignDataset,
+    // the dataset was moved to the stages reading it, see `Job::keep`.
+    NotKept(String),
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobError::DuplicateName(name) => write!(f, "the job already has a dataset named {}", name),
+            JobError::CheckpointCollision(first, second) => {
+                write!(f, "datasets {} and {} would be saved in the same checkpoint, rename one of them", first, second)
+            }
+            JobError::ForeignDataset => write!(f, "the dataset belongs to another job"),
+            JobError::NotKept(name) => write!(f, "dataset {} was moved to the stages reading it, keep it to read it", name),
+        }
+    }
+}
+
+impl Error for JobError {}
+
+pub struct Job {
+    id: usize,
+    nodes: Vec<JobNode>,
+    // the records of every input, by id. the job hands them over when it runs.
+    inputs: Vec<(usize, Data)>,
+    checkpoints: Option<PathBuf>,
+}
+
+struct JobNode {
+    name: String,
+    // set by the caller, to run a stage again when its functions change.
+    version: u32,
+    // in the output of the job, even though stages read it.
+    kept: bool,
+    kind: NodeKind,
+}
+
+enum NodeKind {
+    // the hash of the records.
+    Input(u64),
+    Stage(Stage),
+}
+
+struct Stage {
+    input: usize,
+    run: RunStage,
+    save: SaveStage,
+    load: LoadStage,
+}
+
+// the datasets no stage reads and the kept ones, along with the report of every stage that ran.
+pub struct JobOutput {
+    job: usize,
+    names: Vec<String>,
+    data: HashMap<usize, Data>,
+    reports: HashMap<usize, JobReport>,
+}
+
+// what a run holds: the datasets stages still have to read, and the output.
+struct RunData {
+    // number of stages that did not start yet, by the dataset they read.
+    readers: HashMap<usize, usize>,
+    pending: HashMap<usize, Data>,
+    output: HashMap<usize, Data>,
+}
+
+impl Default for Job {
+    fn default() -> Self {
+        return Job::new();
+    }
+}
+
+impl Job {
+
+    pub fn new() -> Job {
+        return Job { id: JOBS.fetch_add(1, Ordering::Relaxed), nodes: vec![], inputs: vec![], checkpoints: None };
+    }
+
+    // the output of every stage is saved in `dir` (created if needed), as `<stage name>-<lineage>.checkpoint`
+    // (see `lineages`). remove the directory to run every stage again.
+    pub fn set_checkpoints<P: AsRef<Path>>(&mut self, dir: P) {
+        self.checkpoints = Some(dir.as_ref().to_path_buf());
+    }
+
+    // fails if the name is taken (see `JobError`).
+    pub fn input<R: Hash + Send + Sync + 'static>(&mut self, name: &str, records: Vec<R>) -> Result<Dataset<R>, JobError> {
+        let mut hasher = DefaultHasher::new();
+        records.hash(&mut hasher);
+        let dataset = self.push(name, NodeKind::Input(hasher.finish()))?;
+        self.inputs.push((dataset.id, Arc::new(records)));
+        return Ok(dataset);
+    }
+
+    // a stage reducing the records of `input` with `map_reduce`.
+    // fails if the name is taken, or if `input` belongs to another job.
+    pub fn stage<K, V, K2, V2, R>(&mut self, name: &str, input: &Dataset<R>, map_reduce: MapReduce<K, V, K2, V2, R>) -> Result<Dataset<(K2, V2)>, JobError>
+        where K: Hash + Eq + Clone + Send + Sync + 'static,
+              V: Clone + Send + Sync + 'static,
+              K2: Send + Sync + Serialize + DeserializeOwned + 'static,
+              V2: Send + Sync + Serialize + DeserializeOwned + 'static,
+              R: Clone + Send + Sync + 'static {
+        self.check(input)?;
+        let stage = Stage {
+            input: input.id,
+            // the stage owns its input when nothing else holds it, it maps a copy otherwise.
+            run: Box::new(move |input| {
+                let records = input.downcast::<Vec<R>>().unwrap();
+                let records = Arc::try_unwrap(records).unwrap_or_else(|records| records.as_ref().clone());
+                let (output, report) = map_reduce.apply(records);
+                return (Arc::new(output), report);
+            }),
+            save: Box::new(|output, path| {
+                let records = output.downcast_ref::<Vec<(K2, V2)>>().unwrap();
+                // written aside then renamed, so that a checkpoint is never partially written.
+                let tmp = path.with_extension("tmp");
+                bincode::serialize_into(BufWriter::new(File::create(&tmp).unwrap()), records).unwrap();
+                fs::rename(&tmp, path).unwrap();
+            }),
+            load: Box::new(|path| {
+                let records: Vec<(K2, V2)> = bincode::deserialize_from(BufReader::new(File::open(path).unwrap())).unwrap();
+                return Arc::new(records);
+            }),
+        };
+        return self.push(name, NodeKind::Stage(stage));
+    }
+
+    // keeps a dataset read by stages in the output of the job, the last stage reading it then maps a copy.
+    pub fn keep<T>(&mut self, dataset: &Dataset<T>) -> Result<(), JobError> {
+        self.check(dataset)?;
+        self.nodes[dataset.id].kept = true;
+        return Ok(());
+    }
+
+    // the stage is run again, rather than loaded from a checkpoint saved with another version, and so is
+    // every stage downstream of it. bump it when the functions of the stage change.
+    pub fn set_version<T>(&mut self, dataset: &Dataset<T>, version: u32) -> Result<(), JobError> {
+        self.check(dataset)?;
+        self.nodes[dataset.id].version = version;
+        return Ok(());
+    }
+
+    fn check<T>(&self, dataset: &Dataset<T>) -> Result<(), JobError> {
+        if dataset.job != self.id {
+            return Err(JobError::ForeignDataset);
+        }
+        return Ok(());
+    }
+
+    fn push<T>(&mut self, name: &str, kind: NodeKind) -> Result<Dataset<T>, JobError> {
+        if self.nodes.iter().any(|node| node.name == name) {
+            return Err(JobError::DuplicateName(String::from(name)));
+        }
+        if let Some(node) = self.nodes.iter().find(|node| file_name(&node.name) == file_name(name)) {
+            return Err(JobError::CheckpointCollision(node.name.clone(), String::from(name)));
+        }
+        self.nodes.push(JobNode { name: String::from(name), version: 0, kept: false, kind });
+        return Ok(Dataset { job: self.id, id: self.nodes.len() - 1, records: PhantomData });
+    }
+
+    // the lineage of every dataset, by id: derived from its name and version, and from the hash of its
+    // records for an input or the lineage of its input for a stage, so that a stage is not loaded from
+    // a checkpoint saved from other records, or by another version of itself or of a stage upstream.
+    // a stage is always added after its input.
+    fn lineages(&self) -> Vec<Uuid> {
+        let mut lineages: Vec<Uuid> = Vec::with_capacity(self.nodes.len());
+        for node in self.nodes.iter() {
+            let mut bytes = node.name.as_bytes().to_vec();
+            bytes.push(0);
+            bytes.extend_from_slice(&node.version.to_le_bytes());
+            match &node.kind {
+                NodeKind::Input(hash) => bytes.extend_from_slice(&hash.to_le_bytes()),
+                NodeKind::Stage(stage) => bytes.extend_from_slice(lineages[stage.input].as_bytes()),
+            }
+            lineages.push(Uuid::new_v5(&Uuid::NAMESPACE_OID, &bytes));
+        }
+        return lineages;
+    }
+
+    // runs every stage, each on its own thread as soon as its input is ready.
+    // a panic in a stage is propagated once the stages running along with it are done.
+    pub fn run(mut self) -> JobOutput {
+        if let Some(dir) = self.checkpoints.as_ref() {
+            fs::create_dir_all(dir).unwrap();
+        }
+        let mut run = RunData { readers: HashMap::new(), pending: HashMap::new(), output: HashMap::new() };
+        for node in self.nodes.iter() {
+            if let NodeKind::Stage(stage) = &node.kind {
+                *run.readers.entry(stage.input).or_insert(0) += 1;
+            }
+        }
+        for (id, records) in mem::take(&mut self.inputs) {
+            self.ready(id, records, &mut run);
+        }
+
+        let lineages = self.lineages();
+        let mut reports = HashMap::new();
+        let (sender, receiver) = channel();
+        thread::scope(|scope| {
+            let mut started = HashSet::new();
+            let mut running = 0;
+            loop {
+                for (id, node) in self.nodes.iter().enumerate() {
+                    if let NodeKind::Stage(stage) = &node.kind {
+                        if started.contains(&id) || !run.pending.contains_key(&stage.input) {
+                            continue;
+                        }
+                        started.insert(id);
+                        running += 1;
+                        let input = run.take(stage.input);
+                        let sender = sender.clone();
+                        let job = &self;
+                        let lineage = lineages[id];
+                        scope.spawn(move || {
+                            let output = panic::catch_unwind(AssertUnwindSafe(|| job.run_stage(node, lineage, stage, input)));
+                            let _ = sender.send((id, output));
+                        });
+                    }
+                }
+                if running == 0 {
+                    break;
+                }
+                let (id, output) = receiver.recv().unwrap();
+                running -= 1;
+                match output {
+                    Ok((output, report)) => {
+                        self.ready(id, output, &mut run);
+                        reports.extend(report.map(|report| (id, report)));
+                    }
+                    Err(err) => panic::resume_unwind(err),
+                }
+            }
+        });
+        let names = self.nodes.iter().map(|node| node.name.clone()).collect();
+        return JobOutput { job: self.id, names, data: run.output, reports };
+    }
+
+    // a dataset goes to the output when kept or read by no stage, and is held for the stages reading it.
+    fn ready(&self, id: usize, records: Data, run: &mut RunData) {
+        let read = run.readers.contains_key(&id);
+        if self.nodes[id].kept || !read {
+            run.output.insert(id, Arc::clone(&records));
+        }
+        if read {
+            run.pending.insert(id, records);
+        }
+    }
+
+    // a stage loaded from its checkpoint has no report.
+    fn run_stage(&self, node: &JobNode, lineage: Uuid, stage: &Stage, input: Data) -> (Data, Option<JobReport>) {
+        let checkpoint = self.checkpoints.as_ref()
+            .map(|dir| dir.join(format!("{}-{}.checkpoint", file_name(&node.name), lineage.to_simple())));
+        if let Some(path) = checkpoint.as_ref().filter(|path| path.exists()) {
+            debug!("stage {} loaded from {:?}", node.name, path);
+            return ((stage.load)(path), None);
+        }
+        let start = Instant::now();
+        let (output, report) = (stage.run)(input);
+        debug!("stage {} ran in {:?}", node.name, start.elapsed());
+        if let Some(path) = checkpoint.as_ref() {
+            (stage.save)(&output, path);
+        }
+        return (output, Some(report));
+    }
+}
+
+impl RunData {
+    // the run lets go of a dataset once the last stage reading it starts.
+    fn take(&mut self, id: usize) -> Data {
+        let remaining = self.readers.get_mut(&id).unwrap();
+        *remaining -= 1;
+        if *remaining == 0 {
+            return self.pending.remove(&id).unwrap();
+        }
+        return Arc::clone(&self.pending[&id]);
+    }
+}
+
+impl JobOutput {
+
+    // fails for datasets of another job, and for datasets read by stages that were not kept (see `Job::keep`).
+    pub fn get<T: 'static>(&self, dataset: &Dataset<T>) -> Result<&[T], JobError> {
+        if dataset.job != self.job {
+            return Err(JobError::ForeignDataset);
+        }
+        return match self.data.get(&dataset.id) {
+            Some(records) => Ok(records.downcast_ref::<Vec<T>>().unwrap()),
+            None => Err(JobError::NotKept(self.names[dataset.id].clone())),
+        };
+    }
+
+    // None for inputs, for stages loaded from a checkpoint, and for datasets of another job.
+    pub fn report<T>(&self, dataset: &Dataset<T>) -> Option<&JobReport> {
+        if dataset.job != self.job {
+            return None;
+        }
+        return self.reports.get(&dataset.id);
+    }
+}
+
+// stage names are free text, only keep what is safe in a file name.
+fn file_name(name: &str) -> String {
+    return name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::map_reduce::{Emitter, ReduceOutput};
+    use crate::pipeline::word_count::{word_count_emitter, word_count_reducer, letter_count_emitter, top_three_reducer};
+
+    const TEXT: &str = "the cat saw the dog and the dog saw 2 cats";
+
+    type TopThree = MapReduce<String, (i32, String), String, Vec<(String, i32)>, (String, i32)>;
+
+    // text -> word count -> top 3, text -> length. word count is kept.
+    struct TestJob {
+        job: Job,
+        text: Dataset<u8>,
+        counts: Dataset<(String, i32)>,
+        top_words: Dataset<(String, Vec<(String, i32)>)>,
+        length: Dataset<(u8, usize)>,
+    }
+
+    fn length_emitter(raw: Vec<u8>, emitter: &mut Emitter<u8, usize>) {
+        emitter.emit(0, raw.len());
+    }
+
+    fn sum_reducer(key: u8, lengths: &mut dyn Iterator<Item = usize>, output: &mut ReduceOutput<u8, usize>) {
+        output.emit(key, lengths.sum());
+    }
+
+    fn word_count() -> MapReduce<String, i32, String, i32> {
+        return MapReduce::new(word_count_emitter, word_count_reducer);
+    }
+
+    fn top_three() -> TopThree {
+        let mut top_three = MapReduce::new(letter_count_emitter, top_three_reducer);
+        top_three.set_sorted();
+        top_three.set_value_order(|(count_a, word_a), (count_b, word_b)| count_b.cmp(count_a).then_with(|| word_a.cmp(word_b)));
+        return top_three;
+    }
+
+    fn build(text: &str, checkpoints: Option<&Path>) -> TestJob {
+        let mut job = Job::new();
+        if let Some(dir) = checkpoints {
+            job.set_checkpoints(dir);
+        }
+        let text = job.input("text", text.as_bytes().to_vec()).unwrap();
+        let counts = job.stage("word count", &text, word_count()).unwrap();
+        job.keep(&counts).unwrap();
+        let top_words = job.stage("top 3", &counts, top_three()).unwrap();
+        let length = job.stage("length", &text, MapReduce::new(length_emitter, sum_reducer)).unwrap();
+        return TestJob { job, text, counts, top_words, length };
+    }
+
+    #[test]
+    fn stages_read_the_output_of_their_input() {
+        let TestJob { job, counts, top_words, length, .. } = build(TEXT, None);
+        let output = job.run();
+        let mut counts = output.get(&counts).unwrap().to_vec();
+        counts.sort();
+        assert_eq!(counts, vec![
+            (String::from("2"), 1), (String::from("and"), 1), (String::from("cat"), 1), (String::from("cats"), 1),
+            (String::from("dog"), 2), (String::from("saw"), 2), (String::from("the"), 3),
+        ]);
+        assert_eq!(output.get(&top_words).unwrap(), &[
+            (String::from("2"), vec![(String::from("2"), 1)]),
+            (String::from("a"), vec![(String::from("and"), 1)]),
+            (String::from("c"), vec![(String::from("cat"), 1), (String::from("cats"), 1)]),
+            (String::from("d"), vec![(String::from("dog"), 2)]),
+            (String::from("s"), vec![(String::from("saw"), 2)]),
+            (String::from("t"), vec![(String::from("the"), 3)]),
+        ]);
+        assert_eq!(output.get(&length).unwrap(), &[(0, TEXT.len())]);
+        assert!(output.report(&top_words).is_some());
+    }
+
+    #[test]
+    fn datasets_read_by_stages_are_only_kept_on_demand() {
+        let TestJob { job, text, .. } = build(TEXT, None);
+        let output = job.run();
+        assert_eq!(output.get(&text).err(), Some(JobError::NotKept(String::from("text"))));
+        assert!(output.report(&text).is_none());
+    }
+
+    #[test]
+    fn names_must_be_unique_and_make_distinct_files() {
+        let mut job = Job::new();
+        let text = job.input("text", TEXT.as_bytes().to_vec()).unwrap();
+        assert_eq!(job.input("text", vec![1]).err(), Some(JobError::DuplicateName(String::from("text"))));
+        job.stage("top 3", &text, word_count()).unwrap();
+        assert_eq!(job.stage("top_3", &text, word_count()).err(),
+                   Some(JobError::CheckpointCollision(String::from("top 3"), String::from("top_3"))));
+    }
+
+    #[test]
+    fn datasets_of_another_job_are_rejected() {
+        let TestJob { job: other, counts, .. } = build(TEXT, None);
+        let mut job = Job::new();
+        assert_eq!(job.stage("top 3", &counts, top_three()).err(), Some(JobError::ForeignDataset));
+        assert_eq!(job.keep(&counts).err(), Some(JobError::ForeignDataset));
+        let output = job.run();
+        assert_eq!(output.get(&counts).err(), Some(JobError::ForeignDataset));
+        // the same dataset in its own job.
+        assert!(other.run().get(&counts).is_ok());
+    }
+
+    #[test]
+    fn checkpoints_are_keyed_by_lineage() {
+        let dir = std::env::temp_dir().join(format!("job_checkpoints_{}", std::process::id()));
+        let TestJob { job, counts, top_words, .. } = build(TEXT, Some(&dir));
+        let first = job.run();
+        let expected = first.get(&top_words).unwrap().to_vec();
+
+        // the same job loads every stage.
+        let TestJob { job, counts: counts_again, top_words: top_words_again, .. } = build(TEXT, Some(&dir));
+        let second = job.run();
+        assert!(second.report(&counts_again).is_none());
+        assert!(second.report(&top_words_again).is_none());
+        assert_eq!(second.get(&top_words_again).unwrap(), expected.as_slice());
+        assert_eq!(second.get(&counts_again).unwrap(), first.get(&counts).unwrap());
+
+        // a stage reading another input is run again, and so is every stage downstream of it.
+        let mut job = Job::new();
+        job.set_checkpoints(&dir);
+        let words = job.input("words", TEXT.as_bytes().to_vec()).unwrap();
+        let counts = job.stage("word count", &words, word_count()).unwrap();
+        let top_words = job.stage("top 3", &counts, top_three()).unwrap();
+        let third = job.run();
+        assert!(third.report(&top_words).is_some());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 5);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stages_run_again_when_their_input_changes() {
+        let dir = std::env::temp_dir().join(format!("job_checkpoints_input_{}", std::process::id()));
+        build(TEXT, Some(&dir)).job.run();
+
+        let TestJob { job, counts, top_words, .. } = build("the cat saw the dog", Some(&dir));
+        let output = job.run();
+        assert!(output.report(&counts).is_some());
+        assert!(output.report(&top_words).is_some());
+        let mut counts = output.get(&counts).unwrap().to_vec();
+        counts.sort();
+        assert_eq!(counts, vec![(String::from("cat"), 1), (String::from("dog"), 1), (String::from("saw"), 1), (String::from("the"), 2)]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stages_run_again_when_their_version_changes() {
+        let dir = std::env::temp_dir().join(format!("job_checkpoints_version_{}", std::process::id()));
+        build(TEXT, Some(&dir)).job.run();
+
+        // stages upstream of the new version are loaded, the stage and the stages downstream of it run.
+        let TestJob { mut job, text, counts, top_words, length } = build(TEXT, Some(&dir));
+        job.set_version(&counts, 1).unwrap();
+        let output = job.run();
+        assert!(output.report(&text).is_none());
+        assert!(output.report(&length).is_none());
+        assert!(output.report(&counts).is_some());
+        assert!(output.report(&top_words).is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // counts its clones, only the test below clones it.
+    #[derive(Hash)]
+    struct Counted;
+
+    static CLONES: AtomicUsize = AtomicUsize::new(0);
+
+    impl Clone for Counted {
+        fn clone(&self) -> Self {
+            CLONES.fetch_add(1, Ordering::Relaxed);
+            return Counted;
+        }
+    }
+
+    fn counted_emitter(records: Vec<Counted>, emitter: &mut Emitter<u8, usize>) {
+        emitter.emit(0, records.len());
+    }
+
+    #[test]
+    fn the_last_reader_maps_the_input_in_place() {
+        let run = |keep: bool| {
+            let mut job = Job::new();
+            let records = job.input("records", (0..1000).map(|_| Counted).collect()).unwrap();
+            if keep {
+                job.keep(&records).unwrap();
+            }
+            let length = job.stage("length", &records, MapReduce::new(counted_emitter, sum_reducer)).unwrap();
+            let output = job.run();
+            assert_eq!(output.get(&length).unwrap(), &[(0, 1000)]);
+            return CLONES.swap(0, Ordering::Relaxed);
+        };
+        assert_eq!(run(false), 0);
+        // the stage maps a copy of a kept input.
+        assert_eq!(run(true), 1000);
+    }
+}
@@ -0,0 +1,274 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::hash::Hash;
+use rayon::prelude::*;
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+
+use crate::pipeline::map_reduce::{Emitter, MapReduce, ReduceLikeFunc, ReduceOutput};
+
+// *******************************
+// joins.
+// A reduce-side join is a MapReduce over the records of both sides, tagged by the side they come from:
+// the shuffle brings the records of a key together, and the reducer pairs them. The values of a key are
+// gathered in memory before being reduced (even when the partition spilled), then sorted left first,
+// so a key costs memory in proportion to its records on both sides, and a hot key must fit in memory.
+// A broadcast join holds the smaller side in memory and looks every record of the larger side up in it,
+// without shuffling anything: a better fit when a hot key is rare on one of the sides.
+// *******************************
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Tagged<A, B> {
+    Left(A),
+    Right(B),
+}
+
+// a join reading the tagged records of both sides (see `tag`), into records of type O.
+pub type Join<K, A, B, O> = MapReduce<K, Tagged<A, B>, K, O, Tagged<(K, A), (K, B)>>;
+
+// the input of a reduce-side join.
+pub fn tag<K, A, B>(left: Vec<(K, A)>, right: Vec<(K, B)>) -> Vec<Tagged<(K, A), (K, B)>> {
+    return left.into_iter().map(Tagged::Left).chain(right.into_iter().map(Tagged::Right)).collect();
+}
+
+// every pair of records of the same key.
+pub fn inner<K, A, B>() -> Join<K, A, B, (A, B)>
+    where K: Hash + Eq + Ord + Clone + Send + Serialize + DeserializeOwned + 'static,
+          A: Clone + Send + Serialize + DeserializeOwned + 'static,
+          B: Clone + Send + Serialize + DeserializeOwned + 'static {
+    return join(inner_reducer);
+}
+
+// same as `inner`, along with the left records no right record matched.
+pub fn left<K, A, B>() -> Join<K, A, B, (A, Option<B>)>
+    where K: Hash + Eq + Ord + Clone + Send + Serialize + DeserializeOwned + 'static,
+          A: Clone + Send + Serialize + DeserializeOwned + 'static,
+          B: Clone + Send + Serialize + DeserializeOwned + 'static {
+    return join(left_reducer);
+}
+
+// same as `left`, along with the right records no left record matched.
+pub fn full_outer<K, A, B>() -> Join<K, A, B, (Option<A>, Option<B>)>
+    where K: Hash + Eq + Ord + Clone + Send + Serialize + DeserializeOwned + 'static,
+          A: Clone + Send + Serialize + DeserializeOwned + 'static,
+          B: Clone + Send + Serialize + DeserializeOwned + 'static {
+    return join(full_outer_reducer);
+}
+
+fn join<K, A, B, O>(reducer: ReduceLikeFunc<K, Tagged<A, B>, K, O>) -> Join<K, A, B, O>
+    where K: Hash + Eq + Ord + Clone + Send + Serialize + DeserializeOwned + 'static,
+          A: Clone + Send + Serialize + DeserializeOwned + 'static,
+          B: Clone + Send + Serialize + DeserializeOwned + 'static,
+          O: Send {
+    let mut join = MapReduce::new(by_key, reducer);
+    join.set_value_order(left_first);
+    return join;
+}
+
+fn by_key<K, A, B>(input: Vec<Tagged<(K, A), (K, B)>>, emitter: &mut Emitter<K, Tagged<A, B>>)
+    where K: Hash + Eq {
+    for record in input {
+        match record {
+            Tagged::Left((k, a)) => emitter.emit(k, Tagged::Left(a)),
+            Tagged::Right((k, b)) => emitter.emit(k, Tagged::Right(b)),
+        }
+    }
+}
+
+// the sort is stable: the records of a side keep their order.
+fn left_first<A, B>(a: &Tagged<A, B>, b: &Tagged<A, B>) -> Ordering {
+    return match (a, b) {
+        (Tagged::Left(_), Tagged::Right(_)) => Ordering::Less,
+        (Tagged::Right(_), Tagged::Left(_)) => Ordering::Greater,
+        _ => Ordering::Equal,
+    };
+}
+
+// reads the left records of a key, then calls `matched` with every right record and the left records,
+// and returns the left records, along with whether any right record matched them.
+fn pair<A, B, F>(values: &mut dyn Iterator<Item = Tagged<A, B>>, mut matched: F) -> (Vec<A>, bool)
+    where F: FnMut(&[A], B) {
+    let mut lefts = vec![];
+    let mut any_right = false;
+    for value in values {
+        match value {
+            Tagged::Left(a) => lefts.push(a),
+            Tagged::Right(b) => {
+                any_right = true;
+                matched(&lefts, b);
+            }
+        }
+    }
+    return (lefts, any_right);
+}
+
+fn inner_reducer<K: Clone, A: Clone, B: Clone>(key: K, values: &mut dyn Iterator<Item = Tagged<A, B>>, output: &mut ReduceOutput<K, (A, B)>) {
+    pair(values, |lefts, b| for a in lefts {
+        output.emit(key.clone(), (a.clone(), b.clone()));
+    });
+}
+
+fn left_reducer<K: Clone, A: Clone, B: Clone>(key: K, values: &mut dyn Iterator<Item = Tagged<A, B>>, output: &mut ReduceOutput<K, (A, Option<B>)>) {
+    let (lefts, any_right) = pair(values, |lefts, b| for a in lefts {
+        output.emit(key.clone(), (a.clone(), Some(b.clone())));
+    });
+    if !any_right {
+        for a in lefts {
+            output.emit(key.clone(), (a, None));
+        }
+    }
+}
+
+fn full_outer_reducer<K: Clone, A: Clone, B: Clone>(key: K, values: &mut dyn Iterator<Item = Tagged<A, B>>, output: &mut ReduceOutput<K, (Option<A>, Option<B>)>) {
+    let (lefts, any_right) = pair(values, |lefts, b| {
+        if lefts.is_empty() {
+            output.emit(key.clone(), (None, Some(b)));
+        } else {
+            for a in lefts {
+                output.emit(key.clone(), (Some(a.clone()), Some(b.clone())));
+            }
+        }
+    });
+    if !any_right {
+        for a in lefts {
+            output.emit(key.clone(), (Some(a), None));
+        }
+    }
+}
+
+// joins every record of `large` with the records of `small` of the same key, in parallel on the rayon pool.
+// the output is in the order of `large`, then of `small`. A full outer join needs the unmatched records
+// of both sides, use a reduce-side join instead.
+// not a MapReduce: map functions are fn pointers, which cannot hold `small`, and there is nothing to shuffle
+// or reduce. so there are no splits to set, no progress and no `JobReport`.
+pub fn broadcast_inner<K, A, B>(large: Vec<(K, A)>, small: &[(K, B)]) -> Vec<(K, (A, B))>
+    where K: Hash + Eq + Clone + Send + Sync, A: Clone + Send, B: Clone + Sync + Send {
+    let small = broadcast(small);
+    return large.into_par_iter()
+        .map(|(k, a)| match small.get(&k) {
+            Some(bs) => bs.iter().map(|b| (k.clone(), (a.clone(), (*b).clone()))).collect(),
+            None => vec![],
+        })
+        .flatten()
+        .collect();
+}
+
+// same as `broadcast_inner`, along with the records of `large` no record of `small` matched.
+pub fn broadcast_left<K, A, B>(large: Vec<(K, A)>, small: &[(K, B)]) -> Vec<(K, (A, Option<B>))>
+    where K: Hash + Eq + Clone + Send + Sync, A: Clone + Send, B: Clone + Sync + Send {
+    let small = broadcast(small);
+    return large.into_par_iter()
+        .map(|(k, a)| match small.get(&k) {
+            Some(bs) => bs.iter().map(|b| (k.clone(), (a.clone(), Some((*b).clone())))).collect(),
+            None => vec![(k, (a, None))],
+        })
+        .flatten()
+        .collect();
+}
+
+fn broadcast<K: Hash + Eq, B>(small: &[(K, B)]) -> HashMap<&K, Vec<&B>> {
+    let mut by_key: HashMap<&K, Vec<&B>> = HashMap::new();
+    for (k, b) in small {
+        by_key.entry(k).or_default().push(b);
+    }
+    return by_key;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records<V: Clone>(records: &[(u32, V)]) -> Vec<(u32, V)> {
+        return records.to_vec();
+    }
+
+    fn sorted<T: PartialOrd>(mut output: Vec<T>) -> Vec<T> {
+        output.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        return output;
+    }
+
+    // the records of the right side.
+    type Records = Vec<(u32, i32)>;
+
+    // 1 has a left record only, 4 a right record only, 3 is skewed on the left and matched twice.
+    fn sides() -> (Vec<(u32, char)>, Records) {
+        let mut left = records(&[(1, 'a'), (2, 'b'), (3, 'x')]);
+        left.extend((1..1000).map(|_| (3, 'c')));
+        let right = records(&[(2, 20), (3, 30), (4, 40), (3, 31)]);
+        return (left, right);
+    }
+
+    // the records a key is expected to join to, per pair of left and right records.
+    fn pairs_of(left: &[(u32, char)], right: &[(u32, i32)]) -> Vec<(u32, (char, i32))> {
+        let mut pairs = vec![];
+        for (k, a) in left {
+            for (_, b) in right.iter().filter(|(other, _)| other == k) {
+                pairs.push((*k, (*a, *b)));
+            }
+        }
+        return pairs;
+    }
+
+    #[test]
+    fn inner_pairs_every_record_of_a_key() {
+        let (left, right) = sides();
+        let mut join = inner();
+        join.set_record_splits(4);
+        join.set_partitions(3);
+        let (output, report) = join.apply(tag(left.clone(), right.clone()));
+        assert_eq!(output.len(), 1 + 2 * 1000);
+        assert_eq!(sorted(output), sorted(pairs_of(&left, &right)));
+        assert_eq!(report.distinct_keys, 4);
+    }
+
+    #[test]
+    fn left_keeps_the_unmatched_left_records() {
+        let (left, right) = sides();
+        let mut join = super::left();
+        join.set_record_splits(3);
+        let (output, _) = join.apply(tag(left.clone(), right.clone()));
+        let mut expected: Vec<_> = pairs_of(&left, &right).into_iter().map(|(k, (a, b))| (k, (a, Some(b)))).collect();
+        expected.push((1, ('a', None)));
+        assert_eq!(sorted(output), sorted(expected));
+    }
+
+    #[test]
+    fn full_outer_keeps_the_unmatched_records_of_both_sides() {
+        let (left, right) = sides();
+        let mut join = full_outer();
+        join.set_partitions(2);
+        // the skewed key spills, its values are merged back before being paired.
+        join.set_spill_threshold(100);
+        join.set_record_splits(2);
+        let (output, report) = join.apply(tag(left.clone(), right.clone()));
+        assert!(report.spilled_runs > 0);
+        let mut expected: Vec<_> = pairs_of(&left, &right).into_iter().map(|(k, (a, b))| (k, (Some(a), Some(b)))).collect();
+        expected.push((1, (Some('a'), None)));
+        expected.push((4, (None, Some(40))));
+        assert_eq!(sorted(output), sorted(expected));
+    }
+
+    #[test]
+    fn right_records_come_in_their_order() {
+        let left = records(&[(1, 'a'), (1, 'b')]);
+        let right = records(&[(1, 3), (1, 1), (1, 2)]);
+        let (output, _) = inner().apply(tag(left, right));
+        let bs: Vec<i32> = output.iter().map(|(_, (_, b))| *b).collect();
+        assert_eq!(bs, vec![3, 3, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn broadcast_joins_match_reduce_side_joins() {
+        let (left, right) = sides();
+        let broadcast = broadcast_inner(left.clone(), &right);
+        // in the order of the large side, then of the small side.
+        assert_eq!(broadcast, pairs_of(&left, &right));
+        let (reduce_side, _) = inner().apply(tag(left.clone(), right.clone()));
+        assert_eq!(sorted(broadcast), sorted(reduce_side));
+
+        let broadcast = broadcast_left(left.clone(), &right);
+        assert_eq!(broadcast.len(), 2 + 2 * 1000);
+        assert_eq!(broadcast[0], (1, ('a', None)));
+        let (reduce_side, _) = super::left().apply(tag(left, right));
+        assert_eq!(sorted(broadcast), sorted(reduce_side));
+    }
+}
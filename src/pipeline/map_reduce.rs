@@ -1,11 +1,28 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry;
 use std::hash::{Hash};
-use std::cell::{RefCell, Ref};
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::time::Instant;
+use rayon::prelude::*;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::pipeline::partitioner::{Partitioner, HashPartitioner};
+use crate::pipeline::spill::{Merge, Run, SortedRun};
+use crate::pipeline::report::{self, JobReport, Phase, Progress};
 
 
 // map stuff
+// records are pushed into the emitter as they are read, rather than returned at once.
+// the input is bytes, or the records of a previous job (see `job`).
+pub type EmitLikeFunc<K, V, R = u8> = fn(input: Vec<R>, emitter: &mut Emitter<K, V>);
+
+// map functions returning keyables are still accepted (see `MapReduce::from_keyable`).
 pub type MapStageOutput<K, V> = Vec<Box<dyn Keyable<K, V>>>;
-pub type MmapLikeFunc<K, V> = fn(bytes: Vec<u8>) -> MapStageOutput<K, V>;
+pub type MmapLikeFunc<K, V, R = u8> = fn(input: Vec<R>) -> MapStageOutput<K, V>;
 
 // for shuffle purpose
 pub trait Keyable<K: Sized + Hash + Eq, V: ?Sized> {
@@ -13,49 +30,913 @@ pub trait Keyable<K: Sized + Hash + Eq, V: ?Sized> {
     fn get_value(&self) -> &V;
 }
 
+// collects the records of a split. When the job has a combiner, values are grouped by key as they are
+// emitted, and the values of a key are combined once there are `COMBINE_AT` of them, so that a split holds
+// less than `COMBINE_AT` values per distinct key.
+// When the job spills, a split writes what it holds to disk past the threshold, as it is emitted.
+pub struct Emitter<K, V> {
+    records: Vec<(K, V)>,
+    // values by key, only used with a combiner.
+    grouped: HashMap<K, Vec<V>>,
+    combiner: Option<ReduceLikeFunc<K, V, K, V>>,
+    // records held since the last spill, once combined.
+    held: usize,
+    emitted: u64,
+    counters: BTreeMap<String, u64>,
+    spill: Option<Spill<K, V>>,
+}
+
+// values of a key held by a split before they are combined.
+const COMBINE_AT: usize = 8;
+
+// what a split spills: past `threshold` records held, they are combined (when the job has a combiner),
+// and written as a run sorted by key per partition.
+struct Spill<K, V> {
+    threshold: usize,
+    partitions: usize,
+    partitioner: Arc<dyn Partitioner<K>>,
+    compare: Compare<K>,
+    // `Emitter::spill`, which needs bounds `emit` does not.
+    flush: fn(&mut Emitter<K, V>),
+    // by partition, in the order they were written.
+    runs: Vec<Vec<SortedRun<K, V>>>,
+    records: u64,
+}
+
+impl <K: Hash + Eq, V> Emitter<K, V> {
+
+    fn new(combiner: Option<ReduceLikeFunc<K, V, K, V>>) -> Emitter<K, V> {
+        return Emitter {
+            records: vec![],
+            grouped: HashMap::new(),
+            combiner,
+            held: 0,
+            emitted: 0,
+            counters: BTreeMap::new(),
+            spill: None,
+        };
+    }
+
+    pub fn emit(&mut self, key: K, value: V) {
+        self.emitted += 1;
+        self.held += 1;
+        match self.combiner {
+            Some(combiner) => match self.grouped.entry(key) {
+                Entry::Occupied(mut values) => {
+                    values.get_mut().push(value);
+                    if values.get().len() >= COMBINE_AT {
+                        let (key, values) = values.remove_entry();
+                        self.combine(combiner, key, values);
+                    }
+                }
+                Entry::Vacant(values) => {
+                    values.insert(vec![value]);
+                }
+            },
+            None => self.records.push((key, value)),
+        }
+        if let Some(flush) = self.spill.as_ref().filter(|spill| self.held > spill.threshold).map(|spill| spill.flush) {
+            flush(self);
+        }
+    }
+
+    // adds `amount` to a user counter, reported in `JobReport::counters`.
+    pub fn increment(&mut self, counter: &str, amount: u64) {
+        report::increment(&mut self.counters, counter, amount);
+    }
+
+    // the combined values of `key` are held in place of `values`.
+    fn combine(&mut self, combiner: ReduceLikeFunc<K, V, K, V>, key: K, values: Vec<V>) {
+        self.held -= values.len();
+        let mut output = ReduceOutput::new();
+        combiner(key, &mut values.into_iter(), &mut output);
+        report::add_counters(&mut self.counters, output.counters);
+        self.held += output.records.len();
+        for (key, value) in output.records {
+            self.grouped.entry(key).or_default().push(value);
+        }
+    }
+}
+
+// collects the records of a reducer (or of a combiner). Records are not grouped, so they can be of any type.
+pub struct ReduceOutput<K, V> {
+    records: Vec<(K, V)>,
+    counters: BTreeMap<String, u64>,
+}
+
+impl <K, V> ReduceOutput<K, V> {
+
+    fn new() -> ReduceOutput<K, V> {
+        return ReduceOutput { records: vec![], counters: BTreeMap::new() };
+    }
+
+    pub fn emit(&mut self, key: K, value: V) {
+        self.records.push((key, value));
+    }
+
+    // adds `amount` to a user counter, reported in `JobReport::counters`.
+    pub fn increment(&mut self, counter: &str, amount: u64) {
+        report::increment(&mut self.counters, counter, amount);
+    }
+}
+
+// what a split mapped to, once combined: the records it still holds, and the runs it spilled by partition.
+struct Split<K, V> {
+    records: Vec<(K, V)>,
+    runs: Vec<Vec<SortedRun<K, V>>>,
+    emitted: u64,
+    shuffled: u64,
+    counters: BTreeMap<String, u64>,
+}
+
+impl <K: Hash + Eq + Clone, V> Emitter<K, V> {
+
+    // the records held, combined.
+    fn take(&mut self) -> Vec<(K, V)> {
+        self.held = 0;
+        return match self.combiner {
+            Some(combiner) => {
+                let combined = reduce(mem::take(&mut self.grouped), combiner, None, false);
+                report::add_counters(&mut self.counters, combined.counters);
+                combined.records
+            }
+            None => mem::take(&mut self.records),
+        };
+    }
+
+    fn finish(mut self) -> Split<K, V> {
+        let records = self.take();
+        let (runs, spilled) = match self.spill {
+            Some(spill) => (spill.runs, spill.records),
+            None => (vec![], 0),
+        };
+        let shuffled = records.len() as u64 + spilled;
+        return Split { records, runs, emitted: self.emitted, shuffled, counters: self.counters };
+    }
+}
+
+impl <K, V> Emitter<K, V>
+    where K: Hash + Eq + Ord + Clone + Serialize + DeserializeOwned, V: Serialize + DeserializeOwned {
+
+    fn spill_to(&mut self, threshold: usize, partitions: usize, partitioner: Arc<dyn Partitioner<K>>, compare: Compare<K>) {
+        let runs = (0..partitions).map(|_| vec![]).collect();
+        self.spill = Some(Spill { threshold, partitions, partitioner, compare, flush: Emitter::spill, runs, records: 0 });
+    }
+
+    fn spill(&mut self) {
+        let records = self.take();
+        let spill = self.spill.as_mut().unwrap();
+        let mut partitions: Vec<Vec<(K, V)>> = (0..spill.partitions).map(|_| vec![]).collect();
+        for (k, v) in records {
+            partitions[partition_of(spill.partitioner.as_ref(), &k, spill.partitions)].push((k, v));
+        }
+        for (mut records, runs) in partitions.into_iter().zip(spill.runs.iter_mut()) {
+            if !records.is_empty() {
+                spill.records += records.len() as u64;
+                runs.push(SortedRun::write(&mut records, spill.compare));
+            }
+        }
+    }
+}
+
+enum Mapper<K, V, R> {
+    Emit(EmitLikeFunc<K, V, R>),
+    Keyable(MmapLikeFunc<K, V, R>),
+}
+
+// fn pointers are Copy whatever K, V and R are, deriving would require them to be Copy.
+impl <K, V, R> Clone for Mapper<K, V, R> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl <K, V, R> Copy for Mapper<K, V, R> {}
+
 // reduce stuff
-pub type ReduceLikeFunc<K, V> = fn(k: K, vs: Ref<Vec<V>>) -> (K, V);
+// orders keys, or the values of a key. Must be a total order, only equal keys compare as equal.
+pub type Compare<T> = fn(a: &T, b: &T) -> Ordering;
+
+// reads the values of a key, and emits any number of records, of any type.
+pub type ReduceLikeFunc<K, V, K2, V2> = fn(key: K, values: &mut dyn Iterator<Item = V>, output: &mut ReduceOutput<K2, V2>);
 
-pub struct MapReduce<K, V> {
-    pub map_func: MmapLikeFunc<K, V>,
-    pub reduce_func: ReduceLikeFunc<K, V>,
+// reads records of type R (bytes by default).
+pub struct MapReduce<K, V, K2, V2, R = u8> {
+    mapper: Mapper<K, V, R>,
+    pub reduce_func: ReduceLikeFunc<K, V, K2, V2>,
+    // the input is cut into `splits` chunks mapped in parallel, a chunk only ends on a `delimiter` if any.
+    splits: usize,
+    delimiter: Option<Delimiter<R>>,
+    // reduces the output of every split on its own before the shuffle.
+    combiner: Option<ReduceLikeFunc<K, V, K, V>>,
+    // keys are shuffled to `partitions` partitions, reduced in parallel.
+    partitions: usize,
+    partitioner: Arc<dyn Partitioner<K>>,
+    // every split spills what it holds to disk past a threshold.
+    spill: Option<SpillConfig<K, V>>,
+    // keys are reduced in this order, their values in `value_order`.
+    key_order: Option<Compare<K>>,
+    value_order: Option<Compare<V>>,
+    // called from the threads running the job, as splits and partitions are done.
+    progress: Option<Box<dyn Fn(Progress) + Send + Sync>>,
 }
 
-impl<K: Sized + Hash + Eq + Clone, V: Clone> MapReduce<K, V> {
+// set by `set_spill_threshold`, along with the functions spilling and merging runs, which need bounds
+// (an order, and serde) the rest of the job does not.
+struct SpillConfig<K, V> {
+    threshold: usize,
+    // runs are sorted in this order, unless keys are ordered.
+    compare: Compare<K>,
+    // `Emitter::spill_to`.
+    start: StartSpill<K, V>,
+    // `Merge::new`.
+    merge: MergeRuns<K, V>,
+}
+
+type StartSpill<K, V> = fn(emitter: &mut Emitter<K, V>, threshold: usize, partitions: usize, partitioner: Arc<dyn Partitioner<K>>, compare: Compare<K>);
+type MergeRuns<K, V> = fn(runs: Vec<Run<K, V>>, compare: Compare<K>) -> Box<dyn Iterator<Item = (K, Vec<V>)>>;
+
+// set by `set_splits`, along with how records are compared to it, so that only jobs cutting their
+// input on a delimiter need `R: PartialEq`.
+struct Delimiter<R> {
+    record: R,
+    equals: fn(a: &R, b: &R) -> bool,
+}
+
+// the shuffled records of a partition.
+enum Shuffled<K, V> {
+    Grouped(HashMap<K, Vec<V>>),
+    // the runs of every split, spilled to disk or still in memory, in split order.
+    Spilled(Vec<Run<K, V>>),
+}
+
+impl<K, V, K2, V2, R> MapReduce<K, V, K2, V2, R>
+    where K: Sized + Hash + Eq + Clone + Send,
+          V: Clone + Send,
+          K2: Send,
+          V2: Send,
+          R: Send {
+
+    pub fn new(map_func: EmitLikeFunc<K, V, R>, reduce_func: ReduceLikeFunc<K, V, K2, V2>) -> MapReduce<K, V, K2, V2, R> {
+        return MapReduce::with_mapper(Mapper::Emit(map_func), reduce_func);
+    }
+
+    // adapter for map functions returning keyables: every keyable is emitted, then dropped.
+    pub fn from_keyable(map_func: MmapLikeFunc<K, V, R>, reduce_func: ReduceLikeFunc<K, V, K2, V2>) -> MapReduce<K, V, K2, V2, R> {
+        return MapReduce::with_mapper(Mapper::Keyable(map_func), reduce_func);
+    }
+
+    fn with_mapper(mapper: Mapper<K, V, R>, reduce_func: ReduceLikeFunc<K, V, K2, V2>) -> MapReduce<K, V, K2, V2, R> {
+        return MapReduce {
+            mapper,
+            reduce_func,
+            splits: 1,
+            delimiter: None,
+            combiner: None,
+            partitions: 1,
+            partitioner: Arc::new(HashPartitioner),
+            spill: None,
+            key_order: None,
+            value_order: None,
+            progress: None,
+        };
+    }
 
-    pub fn apply(&self, bytes: Vec<u8>) -> Vec<(K, V)> {
-        let map_output = self.apply_map(bytes);
-        let shuffle_output = self.apply_shuffle(map_output);
-        let reduce_output = self.apply_reduce(shuffle_output);
-        return reduce_output;
+    // maps `splits` chunks of the input in parallel (on the rayon pool), cut on `delimiter`.
+    // the delimiter between two chunks is dropped: the map function must split records on it,
+    // so that the output is the same as mapping the whole input at once.
+    pub fn set_splits(&mut self, splits: usize, delimiter: R) where R: PartialEq {
+        self.splits = splits.max(1);
+        self.delimiter = Some(Delimiter::new(delimiter));
     }
 
-    fn apply_map(&self, bytes: Vec<u8>) -> MapStageOutput<K, V> {
-        let f = self.map_func;
-        return f(bytes);
+    // same as `set_splits`, for inputs where every element is a record (e.g. the output of a previous job).
+    pub fn set_record_splits(&mut self, splits: usize) {
+        self.splits = splits.max(1);
+        self.delimiter = None;
     }
 
-    fn apply_shuffle(&self, map_outputs: MapStageOutput<K, V>) -> HashMap<K, RefCell<Vec<V>>> {
-        let mut k_to_values: HashMap<K, RefCell<Vec<V>>> = HashMap::new();
-        for keyable in map_outputs.iter() {
-            let k = keyable.get_key();
-            let element = keyable.get_value();
-            if !k_to_values.contains_key(&k) {
-                k_to_values.insert(k.clone(), RefCell::new(vec![]));
+    // pre-aggregates the values of every key within each split as they are emitted, so that only one record
+    // per key and split is shuffled. The combiner must take its own output as input (e.g. a sum), since it
+    // may run several times on a key; the reducer reads its output.
+    pub fn set_combiner(&mut self, combiner: ReduceLikeFunc<K, V, K, V>) {
+        self.combiner = Some(combiner);
+    }
+
+    // reduces `partitions` partitions in parallel, keys are hashed to a partition unless `set_partitioner` is called.
+    pub fn set_partitions(&mut self, partitions: usize) {
+        self.partitions = partitions.max(1);
+    }
+
+    // e.g. a range partitioner, so that partitions hold contiguous keys.
+    pub fn set_partitioner<P: Partitioner<K> + 'static>(&mut self, partitioner: P) {
+        self.partitioner = Arc::new(partitioner);
+    }
+
+    // same as `set_sorted`, ordering keys with `compare`.
+    pub fn set_key_order(&mut self, compare: Compare<K>) {
+        self.key_order = Some(compare);
+    }
+
+    // secondary sort: the values of a key are sorted with `compare` before being reduced.
+    // Otherwise they come in the order they were emitted, which depends on the splits.
+    pub fn set_value_order(&mut self, compare: Compare<V>) {
+        self.value_order = Some(compare);
+    }
+
+    // e.g. to log or display how far a long job is.
+    pub fn set_progress<F: Fn(Progress) + Send + Sync + 'static>(&mut self, progress: F) {
+        self.progress = Some(Box::new(progress));
+    }
+
+    // the output, along with what the job read, shuffled and wrote, and its counters.
+    pub fn apply(&self, input: Vec<R>) -> (Vec<(K2, V2)>, JobReport) {
+        let (reduced, report) = self.run(input);
+        let output = match self.key_order {
+            Some(compare) if reduced.len() > 1 => merge_by_key(reduced, compare),
+            _ => reduced.into_iter().flat_map(|partition| partition.records).collect(),
+        };
+        return (output, report);
+    }
+
+    // same as `apply`, with the output of every partition on its own (sorted within the partition when ordered).
+    pub fn apply_partitioned(&self, input: Vec<R>) -> (Vec<Vec<(K2, V2)>>, JobReport) {
+        let (reduced, report) = self.run(input);
+        return (reduced.into_iter().map(|partition| partition.records).collect(), report);
+    }
+
+    fn run(&self, input: Vec<R>) -> (Vec<Reduced<K, K2, V2>>, JobReport) {
+        let mut report = JobReport { input_records: input.len() as u64, ..JobReport::default() };
+
+        let start = Instant::now();
+        let splits = self.apply_map(input);
+        report.map_duration = start.elapsed();
+        report.splits = splits.len() as u64;
+        for split in splits.iter() {
+            report.map_output_records += split.emitted;
+            report.shuffled_records += split.shuffled;
+        }
+
+        let start = Instant::now();
+        let shuffled = self.apply_shuffle(splits, &mut report);
+        report.shuffle_duration = start.elapsed();
+
+        let start = Instant::now();
+        let mut reduced = self.apply_reduce(shuffled);
+        report.reduce_duration = start.elapsed();
+        for partition in reduced.iter_mut() {
+            report.distinct_keys += partition.groups;
+            report.reduce_output_records += partition.records.len() as u64;
+            report::add_counters(&mut report.counters, mem::take(&mut partition.counters));
+        }
+        return (reduced, report);
+    }
+
+    // records come out in input order, whatever order the chunks were mapped in.
+    fn apply_map(&self, input: Vec<R>) -> Vec<Split<K, V>> {
+        let mapper = self.mapper;
+        let combiner = self.combiner;
+        let spill = self.spill.as_ref().map(|spill| (spill.start, spill.threshold, self.key_order.unwrap_or(spill.compare)));
+        let (partitions, partitioner) = (self.partitions, &self.partitioner);
+        let chunks = split_records(input, self.splits, self.delimiter.as_ref());
+        let progress = self.progress.as_deref();
+        let total = chunks.len();
+        let done = AtomicUsize::new(0);
+        return chunks.into_par_iter()
+            .map(|chunk| {
+                let mut emitter = Emitter::new(combiner);
+                if let Some((start, threshold, compare)) = spill {
+                    start(&mut emitter, threshold, partitions, Arc::clone(partitioner), compare);
+                }
+                match mapper {
+                    Mapper::Emit(f) => f(chunk, &mut emitter),
+                    // keyables are not Send, they are read on the thread that mapped them.
+                    Mapper::Keyable(f) => for keyable in f(chunk) {
+                        emitter.emit(keyable.get_key(), keyable.get_value().clone());
+                    },
+                }
+                let split = emitter.finish();
+                notify(progress, Phase::Map, done.fetch_add(1, AtomicOrdering::Relaxed) + 1, total);
+                return split;
+            })
+            .collect();
+    }
+
+    // the output of a split is dropped once shuffled. A partition that spilled reads the runs of every split
+    // in split order, so that the values of a key keep the order they were emitted in.
+    fn apply_shuffle(&self, splits: Vec<Split<K, V>>, report: &mut JobReport) -> Vec<Shuffled<K, V>> {
+        let mut partitions: Vec<Vec<Run<K, V>>> = (0..self.partitions).map(|_| vec![]).collect();
+        let total = splits.len();
+        for (i, split) in splits.into_iter().enumerate() {
+            report::add_counters(&mut report.counters, split.counters);
+            let mut records: Vec<Vec<(K, V)>> = (0..self.partitions).map(|_| vec![]).collect();
+            for (k, v) in split.records {
+                records[partition_of(self.partitioner.as_ref(), &k, self.partitions)].push((k, v));
             }
-            k_to_values.get(&k).unwrap().borrow_mut().push(element.clone());
+            let mut runs = split.runs;
+            runs.resize_with(self.partitions, Vec::new);
+            for ((partition, runs), records) in partitions.iter_mut().zip(runs).zip(records) {
+                report.spilled_runs += runs.len() as u64;
+                partition.extend(runs.into_iter().map(Run::Disk));
+                if !records.is_empty() {
+                    partition.push(Run::Memory(records));
+                }
+            }
+            notify(self.progress.as_deref(), Phase::Shuffle, i + 1, total);
         }
+        return partitions.into_iter().map(|runs| {
+            if runs.iter().any(|run| matches!(run, Run::Disk(_))) {
+                return Shuffled::Spilled(runs);
+            }
+            return Shuffled::Grouped(group_by_key(runs.into_iter().flat_map(|run| match run {
+                Run::Memory(records) => records,
+                Run::Disk(_) => unreachable!("the partition did not spill"),
+            })));
+        }).collect();
+    }
 
-        return k_to_values;
+    fn apply_reduce(&self, partitions: Vec<Shuffled<K, V>>) -> Vec<Reduced<K, K2, V2>> {
+        let f = self.reduce_func;
+        let key_order = self.key_order;
+        let value_order = self.value_order;
+        let spill = self.spill.as_ref();
+        let progress = self.progress.as_deref();
+        let total = partitions.len();
+        let done = AtomicUsize::new(0);
+        return partitions.into_par_iter().map(|shuffled| match (shuffled, key_order) {
+            (Shuffled::Grouped(grouped_by_key), None) => reduce(grouped_by_key, f, value_order, false),
+            (Shuffled::Grouped(grouped_by_key), Some(compare)) => {
+                let mut sorted: Vec<(K, Vec<V>)> = grouped_by_key.into_iter().collect();
+                sorted.sort_by(|(k1, _), (k2, _)| compare(k1, k2));
+                reduce(sorted, f, value_order, true)
+            }
+            (Shuffled::Spilled(runs), key_order) => {
+                // only jobs that spill have spilled partitions.
+                let spill = spill.unwrap();
+                let merge = (spill.merge)(runs, key_order.unwrap_or(spill.compare));
+                reduce(merge, f, value_order, key_order.is_some())
+            }
+        }).inspect(|_| notify(progress, Phase::Reduce, done.fetch_add(1, AtomicOrdering::Relaxed) + 1, total)).collect();
     }
+}
 
-    fn apply_reduce(&self, grouped_by_key: HashMap<K, RefCell<Vec<V>>>) -> Vec<(K, V)> {
-        let mut reduce_output = Vec::new();
-        for (k, vs) in grouped_by_key.iter() {
-            let f = self.reduce_func;
-            let (new_k, new_v) = f(k.clone(), vs.borrow());
-            reduce_output.push((new_k, new_v));
+impl<K, V, K2, V2, R> MapReduce<K, V, K2, V2, R>
+    where K: Hash + Eq + Ord + Clone + Send + Serialize + DeserializeOwned + 'static,
+          V: Send + Serialize + DeserializeOwned + 'static {
+
+    // once a split holds more than `records` records (after the combiner, if any), it writes them to temporary
+    // files as they are mapped, a file per partition sorted by key. A partition that spilled is reduced in key order,
+    // merging its files back one key at a time.
+    pub fn set_spill_threshold(&mut self, records: usize) {
+        self.spill = Some(SpillConfig {
+            threshold: records.max(1),
+            compare: K::cmp,
+            start: Emitter::spill_to,
+            merge: |runs, compare| Box::new(Merge::new(runs, compare)),
+        });
+    }
+}
+
+impl<K: Ord, V, K2, V2, R> MapReduce<K, V, K2, V2, R> {
+
+    // keys are reduced in ascending order, and the output comes out in the order of the keys it was reduced from.
+    // Without an order, keys are reduced in no particular order.
+    pub fn set_sorted(&mut self) {
+        self.key_order = Some(K::cmp);
+    }
+}
+
+fn notify(progress: Option<&(dyn Fn(Progress) + Send + Sync)>, phase: Phase, done: usize, total: usize) {
+    if let Some(progress) = progress {
+        progress(Progress { phase, done, total });
+    }
+}
+
+fn partition_of<K>(partitioner: &dyn Partitioner<K>, key: &K, partitions: usize) -> usize {
+    let partition = partitioner.partition(key, partitions);
+    assert!(partition < partitions, "partition {} out of {} partitions", partition, partitions);
+    return partition;
+}
+
+fn group_by_key<K: Hash + Eq, V, I: IntoIterator<Item = (K, V)>>(records: I) -> HashMap<K, Vec<V>> {
+    let mut k_to_values: HashMap<K, Vec<V>> = HashMap::new();
+    for (k, element) in records {
+        k_to_values.entry(k).or_default().push(element);
+    }
+
+    return k_to_values;
+}
+
+// the output of a partition, along with the key every record was reduced from when keys are ordered:
+// `keys` holds every key, and the end of its records.
+struct Reduced<K, K2, V2> {
+    records: Vec<(K2, V2)>,
+    keys: Vec<(K, usize)>,
+    // number of keys reduced.
+    groups: u64,
+    counters: BTreeMap<String, u64>,
+}
+
+// keys and values are moved to the reducer.
+fn reduce<K: Clone, V, K2, V2, G>(grouped_by_key: G,
+                                  f: ReduceLikeFunc<K, V, K2, V2>,
+                                  value_order: Option<Compare<V>>,
+                                  keep_keys: bool) -> Reduced<K, K2, V2>
+    where G: IntoIterator<Item = (K, Vec<V>)> {
+    let mut output = ReduceOutput::new();
+    let mut keys = vec![];
+    let mut groups = 0;
+    for (k, mut vs) in grouped_by_key {
+        groups += 1;
+        if let Some(compare) = value_order {
+            vs.sort_by(compare);
+        }
+        if keep_keys {
+            f(k.clone(), &mut vs.into_iter(), &mut output);
+            keys.push((k, output.records.len()));
+        } else {
+            f(k, &mut vs.into_iter(), &mut output);
         }
-        return reduce_output;
     }
-}
\ No newline at end of file
+    return Reduced { records: output.records, keys, groups, counters: output.counters };
+}
+
+// merges the output of partitions reduced in key order.
+fn merge_by_key<K, K2, V2>(partitions: Vec<Reduced<K, K2, V2>>, compare: Compare<K>) -> Vec<(K2, V2)> {
+    let mut output = Vec::with_capacity(partitions.iter().map(|partition| partition.records.len()).sum());
+    let mut keys = vec![];
+    let mut records = vec![];
+    for partition in partitions {
+        keys.push(partition.keys);
+        records.push(partition.records.into_iter());
+    }
+    // the next key of every partition.
+    let mut cursors = vec![0; keys.len()];
+    // there are few partitions, the next key is looked for among all of them.
+    while let Some(next) = (0..keys.len())
+        .filter(|i| cursors[*i] < keys[*i].len())
+        .min_by(|i, j| compare(&keys[*i][cursors[*i]].0, &keys[*j][cursors[*j]].0)) {
+        let start = if cursors[next] == 0 { 0 } else { keys[next][cursors[next] - 1].1 };
+        let end = keys[next][cursors[next]].1;
+        output.extend(records[next].by_ref().take(end - start));
+        cursors[next] += 1;
+    }
+    return output;
+}
+
+impl <R: PartialEq> Delimiter<R> {
+    fn new(record: R) -> Delimiter<R> {
+        return Delimiter { record, equals: R::eq };
+    }
+}
+
+// cuts `input` in about `splits` chunks of the same size, each ending right before a delimiter (or at the end).
+// empty records are kept: a trailing delimiter is followed by an empty chunk, like `split` would.
+// without a delimiter, chunks end anywhere.
+fn split_records<R>(input: Vec<R>, splits: usize, delimiter: Option<&Delimiter<R>>) -> Vec<Vec<R>> {
+    if splits <= 1 {
+        return vec![input];
+    }
+    let target = input.len().div_ceil(splits).max(1);
+    let mut bounds = vec![];
+    let mut start = 0;
+    loop {
+        let mut end = (start + target).min(input.len());
+        if let Some(delimiter) = delimiter {
+            while end < input.len() && !(delimiter.equals)(&input[end], &delimiter.record) {
+                end += 1;
+            }
+        }
+        bounds.push((start, end));
+        if end >= input.len() {
+            break;
+        }
+        start = if delimiter.is_some() { end + 1 } else { end };
+    }
+    // the records are moved to their chunk, the delimiters between chunks are dropped.
+    let mut records = input.into_iter();
+    let mut read = 0;
+    return bounds.into_iter().map(|(start, end)| {
+        records.by_ref().take(start - read).for_each(drop);
+        read = end;
+        return records.by_ref().take(end - start).collect();
+    }).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::word_count::{word_count_mapper, word_count_emitter, word_length_emitter, word_count_reducer, first_letter_emitter, word_list_reducer, by_length, AlphabetPartitioner};
+
+    const TEXT: &str = "the cat saw the dog and the dog saw 2 cats";
+
+    fn sorted<T: Ord>(mut output: Vec<T>) -> Vec<T> {
+        output.sort();
+        return output;
+    }
+
+    #[test]
+    fn chunks_end_on_a_delimiter() {
+        let space = Delimiter::new(b' ');
+        let chunks = split_records(b"ab cd ef gh".to_vec(), 3, Some(&space));
+        assert_eq!(chunks, vec![b"ab cd".to_vec(), b"ef gh".to_vec()]);
+        // a trailing delimiter is followed by an empty chunk.
+        assert_eq!(split_records(b"ab ".to_vec(), 2, Some(&space)), vec![b"ab".to_vec(), vec![]]);
+    }
+
+    #[test]
+    fn chunks_without_a_delimiter_end_anywhere() {
+        assert_eq!(split_records(vec![1, 2, 3, 4, 5], 2, None), vec![vec![1, 2, 3], vec![4, 5]]);
+        assert_eq!(split_records(vec![1, 2], 5, None), vec![vec![1], vec![2]]);
+        assert_eq!(split_records(Vec::<u8>::new(), 1, None), vec![Vec::<u8>::new()]);
+    }
+
+    #[test]
+    fn parallel_map_matches_a_single_split() {
+        let single = MapReduce::new(word_count_emitter, word_count_reducer);
+        let (expected, _) = single.apply(TEXT.as_bytes().to_vec());
+        for splits in 2..8 {
+            let mut map_reduce = MapReduce::new(word_count_emitter, word_count_reducer);
+            map_reduce.set_splits(splits, b' ');
+            let (output, report) = map_reduce.apply(TEXT.as_bytes().to_vec());
+            assert_eq!(sorted(output), sorted(expected.clone()));
+            assert!(report.splits > 1);
+        }
+    }
+
+    #[test]
+    fn combiner_only_shuffles_distinct_keys_per_split() {
+        let (expected, _) = MapReduce::new(word_count_emitter, word_count_reducer).apply(TEXT.as_bytes().to_vec());
+        let mut map_reduce = MapReduce::new(word_count_emitter, word_count_reducer);
+        map_reduce.set_splits(2, b' ');
+        map_reduce.set_combiner(word_count_reducer);
+        let (output, report) = map_reduce.apply(TEXT.as_bytes().to_vec());
+        assert_eq!(sorted(output), sorted(expected));
+        assert_eq!(report.map_output_records, 11);
+        // "the cat saw the dog and" / "the dog saw 2 cats"
+        assert_eq!(report.shuffled_records, 5 + 5);
+        assert_eq!(report.counters["numbers"], 1);
+    }
+
+    #[test]
+    fn combiner_runs_as_values_are_emitted() {
+        let mut emitter: Emitter<String, i32> = Emitter::new(Some(word_count_reducer));
+        for i in 0..1000 {
+            emitter.emit(String::from(if i % 2 == 0 { "even" } else { "odd" }), 1);
+            assert!(emitter.grouped.values().all(|values| values.len() < COMBINE_AT));
+            assert!(emitter.held < 2 * COMBINE_AT);
+        }
+        let split = emitter.finish();
+        assert_eq!(sorted(split.records), vec![(String::from("even"), 500), (String::from("odd"), 500)]);
+        assert_eq!((split.emitted, split.shuffled), (1000, 2));
+    }
+
+    #[test]
+    fn combined_records_are_held_towards_the_spill_threshold() {
+        // a single word repeated: once combined, the split never holds more than `COMBINE_AT` records.
+        let text = vec!["de"; 1000].join(" ");
+        let mut map_reduce = MapReduce::new(word_count_emitter, word_count_reducer);
+        map_reduce.set_combiner(word_count_reducer);
+        map_reduce.set_spill_threshold(COMBINE_AT);
+        let (output, report) = map_reduce.apply(text.into_bytes());
+        assert_eq!(output, vec![(String::from("de"), 1000)]);
+        assert_eq!(report.spilled_runs, 0);
+    }
+
+    #[test]
+    fn every_key_is_reduced_in_a_single_partition() {
+        let (expected, _) = MapReduce::new(word_count_emitter, word_count_reducer).apply(TEXT.as_bytes().to_vec());
+        let mut map_reduce = MapReduce::new(word_count_emitter, word_count_reducer);
+        map_reduce.set_splits(3, b' ');
+        map_reduce.set_partitions(4);
+        let (partitions, report) = map_reduce.apply_partitioned(TEXT.as_bytes().to_vec());
+        assert_eq!(partitions.len(), 4);
+        assert_eq!(report.distinct_keys, 7);
+        for (i, partition) in partitions.iter().enumerate() {
+            for (word, _) in partition {
+                assert_eq!(HashPartitioner.partition(word, 4), i);
+            }
+        }
+        assert_eq!(sorted(partitions.into_iter().flatten().collect()), sorted(expected));
+    }
+
+    #[test]
+    fn range_partitions_are_in_key_order() {
+        let mut map_reduce = MapReduce::new(word_count_emitter, word_count_reducer);
+        map_reduce.set_partitions(2);
+        map_reduce.set_partitioner(AlphabetPartitioner);
+        map_reduce.set_sorted();
+        let (partitions, _) = map_reduce.apply_partitioned(TEXT.as_bytes().to_vec());
+        let words: Vec<Vec<&str>> = partitions.iter().map(|partition| partition.iter().map(|(word, _)| word.as_str()).collect()).collect();
+        assert_eq!(words, vec![vec!["2", "and", "cat", "cats", "dog"], vec!["saw", "the"]]);
+
+        let (output, _) = map_reduce.apply(TEXT.as_bytes().to_vec());
+        assert_eq!(output, sorted(output.clone()));
+    }
+
+    struct OutOfRange;
+
+    impl Partitioner<String> for OutOfRange {
+        fn partition(&self, _key: &String, partitions: usize) -> usize {
+            return partitions;
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "out of 2 partitions")]
+    fn partitions_out_of_range_are_rejected() {
+        let mut map_reduce = MapReduce::new(word_count_emitter, word_count_reducer);
+        map_reduce.set_partitions(2);
+        map_reduce.set_partitioner(OutOfRange);
+        map_reduce.apply(TEXT.as_bytes().to_vec());
+    }
+
+    #[test]
+    fn splits_spill_as_they_map() {
+        let text = TEXT.repeat(10);
+        let (expected, expected_report) = MapReduce::new(word_count_emitter, word_count_reducer).apply(text.as_bytes().to_vec());
+        for combined in [false, true] {
+            let mut map_reduce = MapReduce::new(word_count_emitter, word_count_reducer);
+            map_reduce.set_splits(4, b' ');
+            map_reduce.set_partitions(3);
+            map_reduce.set_spill_threshold(8);
+            if combined {
+                map_reduce.set_combiner(word_count_reducer);
+            }
+            let (output, report) = map_reduce.apply(text.as_bytes().to_vec());
+            assert_eq!(sorted(output), sorted(expected.clone()));
+            assert!(report.spilled_runs > 0);
+            assert_eq!(report.distinct_keys, expected_report.distinct_keys);
+            if !combined {
+                // every record is shuffled, from disk or from memory.
+                assert_eq!(report.shuffled_records, report.map_output_records);
+            }
+        }
+    }
+
+    #[test]
+    fn spilled_values_keep_their_order() {
+        let text = "b3 a1 b1 a2 b2 a3 a1 b4";
+        let (expected, _) = MapReduce::new(first_letter_emitter, word_list_reducer).apply(text.as_bytes().to_vec());
+        let mut map_reduce = MapReduce::new(first_letter_emitter, word_list_reducer);
+        map_reduce.set_splits(3, b' ');
+        map_reduce.set_spill_threshold(1);
+        let (output, report) = map_reduce.apply(text.as_bytes().to_vec());
+        let output = sorted(output);
+        assert_eq!(output, vec![
+            (String::from("a"), vec![String::from("a1"), String::from("a2"), String::from("a3")]),
+            (String::from("b"), vec![String::from("b3"), String::from("b1"), String::from("b2"), String::from("b4")]),
+        ]);
+        assert_eq!(output, sorted(expected));
+        assert!(report.spilled_runs >= 3);
+    }
+
+    #[test]
+    fn keyable_mappers_match_emitters() {
+        let (expected, _) = MapReduce::new(word_count_emitter, word_count_reducer).apply(TEXT.as_bytes().to_vec());
+        let mut map_reduce = MapReduce::from_keyable(word_count_mapper, word_count_reducer);
+        map_reduce.set_splits(3, b' ');
+        let (output, report) = map_reduce.apply(TEXT.as_bytes().to_vec());
+        assert_eq!(sorted(output), sorted(expected));
+        assert_eq!(report.map_output_records, 11);
+    }
+
+    #[test]
+    fn emitter_counts_records_and_counters() {
+        let mut emitter: Emitter<String, i32> = Emitter::new(None);
+        word_count_emitter(b"1 a 22 a".to_vec(), &mut emitter);
+        emitter.increment("other", 3);
+        let split = emitter.finish();
+        assert_eq!(split.records.len(), 4);
+        assert_eq!((split.emitted, split.shuffled), (4, 4));
+        assert_eq!(split.counters, vec![(String::from("numbers"), 2), (String::from("other"), 3)].into_iter().collect());
+
+        let mut emitter: Emitter<String, i32> = Emitter::new(Some(word_count_reducer));
+        word_count_emitter(b"1 a 22 a".to_vec(), &mut emitter);
+        let split = emitter.finish();
+        assert_eq!(sorted(split.records), vec![(String::from("1"), 1), (String::from("22"), 1), (String::from("a"), 2)]);
+        assert_eq!((split.emitted, split.shuffled), (4, 3));
+    }
+
+    #[test]
+    fn counters_of_every_split_and_partition_are_summed() {
+        for splits in 1..5 {
+            let mut map_reduce = MapReduce::new(word_count_emitter, word_count_reducer);
+            map_reduce.set_splits(splits, b' ');
+            map_reduce.set_partitions(3);
+            let (_, report) = map_reduce.apply(b"1 2 the 33 cat 4".to_vec());
+            assert_eq!(report.input_records, 16);
+            assert_eq!(report.counters["numbers"], 4);
+        }
+        let mut map_reduce = MapReduce::new(word_length_emitter, by_mean_length);
+        map_reduce.set_partitions(4);
+        let (_, report) = map_reduce.apply(TEXT.as_bytes().to_vec());
+        assert_eq!(report.counters["single words"], 2);
+    }
+
+    #[test]
+    fn progress_is_reported_for_every_split_and_partition() {
+        let progress = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut map_reduce = MapReduce::new(word_count_emitter, word_count_reducer);
+        map_reduce.set_splits(3, b' ');
+        map_reduce.set_partitions(2);
+        let seen = Arc::clone(&progress);
+        map_reduce.set_progress(move |progress| seen.lock().unwrap().push((progress.phase, progress.done, progress.total)));
+        map_reduce.apply(TEXT.as_bytes().to_vec());
+        let progress = progress.lock().unwrap().clone();
+        let of = |phase: Phase| -> Vec<(usize, usize)> {
+            return sorted(progress.iter().filter(|(p, _, _)| *p == phase).map(|(_, done, total)| (*done, *total)).collect());
+        };
+        assert_eq!(of(Phase::Map), vec![(1, 3), (2, 3), (3, 3)]);
+        assert_eq!(of(Phase::Shuffle), vec![(1, 3), (2, 3), (3, 3)]);
+        assert_eq!(of(Phase::Reduce), vec![(1, 2), (2, 2)]);
+        // the phases follow each other.
+        let phases: Vec<Phase> = progress.iter().map(|(phase, _, _)| *phase).collect();
+        assert_eq!(phases, [vec![Phase::Map; 3], vec![Phase::Shuffle; 3], vec![Phase::Reduce; 2]].concat());
+    }
+
+    // keyed by the mean length of the words starting with a letter: f64 is neither Hash, Eq nor Ord.
+    fn by_mean_length(letter: String, lengths: &mut dyn Iterator<Item = i32>, output: &mut ReduceOutput<f64, String>) {
+        let lengths: Vec<i32> = lengths.collect();
+        if lengths.len() < 2 {
+            output.increment("single words", 1);
+            return;
+        }
+        output.emit(lengths.iter().sum::<i32>() as f64 / lengths.len() as f64, letter);
+    }
+
+    // neither ordered nor serializable.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    struct Letter(char);
+
+    fn letter_emitter(raw: Vec<u8>, emitter: &mut Emitter<Letter, i32>) {
+        for word in std::str::from_utf8(&raw).unwrap().split_whitespace() {
+            emitter.emit(Letter(word.chars().next().unwrap()), 1);
+        }
+    }
+
+    fn letter_reducer(letter: Letter, counts: &mut dyn Iterator<Item = i32>, output: &mut ReduceOutput<char, i32>) {
+        output.emit(letter.0, counts.sum());
+    }
+
+    #[test]
+    fn keys_only_need_to_be_hashed_unless_spilled_or_sorted() {
+        let mut map_reduce = MapReduce::new(letter_emitter, letter_reducer);
+        map_reduce.set_splits(3, b' ');
+        map_reduce.set_partitions(2);
+        map_reduce.set_combiner(|letter, counts, output| output.emit(letter, counts.sum()));
+        let (output, _) = map_reduce.apply(TEXT.as_bytes().to_vec());
+        assert_eq!(sorted(output), vec![('2', 1), ('a', 1), ('c', 2), ('d', 2), ('s', 2), ('t', 3)]);
+    }
+
+    #[test]
+    fn reducers_emit_any_type() {
+        let mut map_reduce = MapReduce::new(word_length_emitter, by_mean_length);
+        map_reduce.set_partitions(2);
+        map_reduce.set_sorted();
+        let (output, report) = map_reduce.apply(TEXT.as_bytes().to_vec());
+        assert_eq!(output, vec![(3.5, String::from("c")), (3.0, String::from("d")), (3.0, String::from("s")), (3.0, String::from("t"))]);
+        assert_eq!(report.counters["single words"], 2);
+        assert_eq!(report.reduce_output_records, 4);
+    }
+
+    #[test]
+    fn sorted_output_does_not_depend_on_partitions() {
+        let (expected, _) = MapReduce::new(word_count_emitter, word_count_reducer).apply(TEXT.as_bytes().to_vec());
+        for partitions in 1..5 {
+            let mut map_reduce = MapReduce::new(word_count_emitter, word_count_reducer);
+            map_reduce.set_splits(3, b' ');
+            map_reduce.set_partitions(partitions);
+            map_reduce.set_sorted();
+            assert_eq!(map_reduce.apply(TEXT.as_bytes().to_vec()).0, sorted(expected.clone()));
+        }
+    }
+
+    #[test]
+    fn keys_follow_a_custom_order() {
+        let mut map_reduce = MapReduce::new(word_count_emitter, word_count_reducer);
+        map_reduce.set_partitions(3);
+        map_reduce.set_key_order(by_length);
+        let (output, _) = map_reduce.apply(TEXT.as_bytes().to_vec());
+        let words: Vec<&str> = output.iter().map(|(word, _)| word.as_str()).collect();
+        assert_eq!(words, vec!["2", "and", "cat", "dog", "saw", "the", "cats"]);
+        // the same order when partitions spill.
+        map_reduce.set_spill_threshold(2);
+        assert_eq!(map_reduce.apply(TEXT.as_bytes().to_vec()).0, output);
+    }
+
+    #[test]
+    fn values_are_sorted_before_being_reduced() {
+        let mut map_reduce = MapReduce::new(first_letter_emitter, word_list_reducer);
+        map_reduce.set_splits(3, b' ');
+        map_reduce.set_sorted();
+        map_reduce.set_value_order(by_length);
+        let (output, _) = map_reduce.apply(b"cats saw the cat sad dog".to_vec());
+        assert_eq!(output, vec![
+            (String::from("c"), vec![String::from("cat"), String::from("cats")]),
+            (String::from("d"), vec![String::from("dog")]),
+            (String::from("s"), vec![String::from("sad"), String::from("saw")]),
+            (String::from("t"), vec![String::from("the")]),
+        ]);
+    }
+}
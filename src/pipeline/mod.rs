@@ -1,6 +1,11 @@
 pub mod word_count;
 pub mod par_map;
 pub mod map_reduce;
+pub mod partitioner;
+pub mod spill;
+pub mod job;
+pub mod report;
+pub mod join;
 
 // map stuff
 pub type MapLikeFunc = fn(xs: Vec<u8>) -> Vec<u8>;
@@ -4,6 +4,7 @@ use std::sync::Arc;
 use std::thread::{JoinHandle, spawn};
 
 pub use crate::pipeline::MapLikeFunc;
+use crate::io::Channel;
 
 pub struct MapLikeSeq {
     pub fs: Arc<Vec<MapLikeFunc>>,
@@ -16,6 +17,7 @@ impl MapLikeSeq {
         return MapLikeSeq{ fs: Arc::new(funcs) };
     }
 
+    #[allow(dead_code)]
     pub fn apply(&self, datum: Vec<u8>) -> Vec<u8> {
         let mut dat = datum.clone();
         let fs = Arc::clone(&self.fs);
@@ -38,6 +40,7 @@ impl MapLikeSeq {
         });
     }
 
+    #[allow(dead_code)]
     pub fn map_async(&self, data: Vec<Vec<u8>>) -> Vec<JoinHandle<Vec<u8>>> {
         let mut handles = vec![];
         for datum in data {
@@ -47,8 +50,8 @@ impl MapLikeSeq {
     }
 
     pub fn compute_async(&self,
-                         work_sender: Sender<Vec<u8>>,
-                         work_receiver: Receiver<Vec<u8>>) -> (Sender<JoinHandle<Vec<u8>>>, Receiver<JoinHandle<Vec<u8>>>) {
+                         _work_sender: Sender<Vec<u8>>,
+                         work_receiver: Receiver<Vec<u8>>) -> Channel<JoinHandle<Vec<u8>>> {
         let (io_out_sender, io_out_receiver) = channel();
         loop {
             let result = work_receiver.recv_timeout(Duration::from_millis(100));
@@ -56,7 +59,7 @@ impl MapLikeSeq {
                 break;
             }
             let work = result.unwrap();
-            io_out_sender.send(self.apply_async(work));
+            io_out_sender.send(self.apply_async(work)).unwrap();
         }
 
         return (io_out_sender, io_out_receiver);
@@ -0,0 +1,48 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+// *******************************
+// partitioner.
+// Decides which reduce partition a key goes to. Every record of a key goes to the same partition,
+// so each partition can be reduced (and written) on its own.
+// *******************************
+pub trait Partitioner<K>: Send + Sync {
+    // must be lower than `partitions`.
+    fn partition(&self, key: &K, partitions: usize) -> usize;
+}
+
+// spreads keys evenly, the same key goes to the same partition from one run to the next.
+pub struct HashPartitioner;
+
+impl <K: Hash> Partitioner<K> for HashPartitioner {
+    fn partition(&self, key: &K, partitions: usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        return (hasher.finish() % partitions as u64) as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_partitions_are_in_range_and_stable() {
+        let keys: Vec<String> = (0..1000).map(|i| format!("key {}", i)).collect();
+        for partitions in 1..6 {
+            let first: Vec<usize> = keys.iter().map(|key| HashPartitioner.partition(key, partitions)).collect();
+            assert!(first.iter().all(|partition| *partition < partitions));
+            let second: Vec<usize> = keys.iter().map(|key| HashPartitioner.partition(key, partitions)).collect();
+            assert_eq!(first, second);
+        }
+    }
+
+    #[test]
+    fn hash_partitions_are_spread() {
+        let mut sizes = [0; 4];
+        for i in 0..4000u32 {
+            sizes[HashPartitioner.partition(&i, 4)] += 1;
+        }
+        assert!(sizes.iter().all(|size| *size > 800), "{:?}", sizes);
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+
+// *******************************
+// job reports.
+// What a MapReduce run read, shuffled and wrote, along with the counters the map, combine and reduce
+// functions incremented (see `Emitter::increment`). Counters are kept by every split and partition,
+// and summed once they are done, so that counting never contends between threads.
+// *******************************
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JobReport {
+    // records read: bytes, for a job reading bytes.
+    pub input_records: u64,
+    pub splits: u64,
+    pub map_output_records: u64,
+    // what is left of the map output once combined.
+    pub shuffled_records: u64,
+    pub spilled_runs: u64,
+    pub distinct_keys: u64,
+    pub reduce_output_records: u64,
+    // user counters, by name.
+    pub counters: BTreeMap<String, u64>,
+    pub map_duration: Duration,
+    pub shuffle_duration: Duration,
+    pub reduce_duration: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Map,
+    Shuffle,
+    Reduce,
+}
+
+// `done` splits (map, shuffle) or partitions (reduce) out of `total`.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub phase: Phase,
+    pub done: usize,
+    pub total: usize,
+}
+
+pub (in crate::pipeline) fn add_counters<C: IntoIterator<Item = (String, u64)>>(into: &mut BTreeMap<String, u64>, counters: C) {
+    for (name, amount) in counters {
+        *into.entry(name).or_insert(0) += amount;
+    }
+}
+
+
+pub (in crate::pipeline) fn increment(counters: &mut BTreeMap<String, u64>, counter: &str, amount: u64) {
+    match counters.get_mut(counter) {
+        Some(count) => *count += amount,
+        None => {
+            counters.insert(String::from(counter), amount);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::pipeline::map_reduce::Compare;
+
+// *******************************
+// spill to disk.
+// When a map task holds more than a threshold, the records it holds are sorted by key and written to a
+// temporary file (a run) per partition. Reducers read their runs back with a k-way merge, one key at a time.
+// *******************************
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+// a file of records sorted by key, removed on drop.
+pub (in crate::pipeline) struct SortedRun<K, V> {
+    path: PathBuf,
+    len: u64,
+    records: PhantomData<(K, V)>,
+}
+
+impl <K: Ord + Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> SortedRun<K, V> {
+
+    // sorts `records` by key (values of the same key keep their order) and writes them, `records` ends up empty.
+    pub (in crate::pipeline) fn write(records: &mut Vec<(K, V)>, compare: Compare<K>) -> SortedRun<K, V> {
+        records.sort_by(|(k1, _), (k2, _)| compare(k1, k2));
+        let run = RUNS.fetch_add(1, AtomicOrdering::Relaxed);
+        let path = std::env::temp_dir().join(format!("map_reduce_{}_{}.run", process::id(), run));
+        let mut writer = BufWriter::new(File::create(&path).unwrap());
+        for record in records.iter() {
+            bincode::serialize_into(&mut writer, record).unwrap();
+        }
+        let len = records.len() as u64;
+        records.clear();
+        return SortedRun { path, len, records: PhantomData };
+    }
+
+    // the file is removed once the reader is dropped.
+    fn into_reader(self) -> RunReader<K, V> {
+        return RunReader {
+            reader: BufReader::new(File::open(&self.path).unwrap()),
+            remaining: self.len,
+            _run: self,
+        };
+    }
+}
+
+impl <K, V> Drop for SortedRun<K, V> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// the records of a partition: sorted by key on disk, or still in memory in the order they were emitted.
+pub (in crate::pipeline) enum Run<K, V> {
+    Disk(SortedRun<K, V>),
+    Memory(Vec<(K, V)>),
+}
+
+struct RunReader<K, V> {
+    reader: BufReader<File>,
+    remaining: u64,
+    _run: SortedRun<K, V>,
+}
+
+impl <K: DeserializeOwned, V: DeserializeOwned> Iterator for RunReader<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        return Some(bincode::deserialize_from(&mut self.reader).unwrap());
+    }
+}
+
+// the next record of a source, ordered by key then by source, so that the values of a key
+// come out in the order the sources were written.
+struct Head<K, V> {
+    key: K,
+    value: V,
+    source: usize,
+    compare: Compare<K>,
+}
+
+impl <K, V> Ord for Head<K, V> {
+    // BinaryHeap pops the greatest first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        return (self.compare)(&other.key, &self.key).then_with(|| other.source.cmp(&self.source));
+    }
+}
+
+impl <K, V> PartialOrd for Head<K, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl <K, V> PartialEq for Head<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl <K, V> Eq for Head<K, V> {}
+
+// merges the runs into the values of every key, in key order. the values of a key come in the order of the runs.
+pub (in crate::pipeline) struct Merge<K, V> {
+    // the files are removed once merged.
+    sources: Vec<Box<dyn Iterator<Item = (K, V)> + Send>>,
+    heads: BinaryHeap<Head<K, V>>,
+    compare: Compare<K>,
+}
+
+impl <K, V> Merge<K, V>
+    where K: Ord + Serialize + DeserializeOwned + Send + 'static, V: Serialize + DeserializeOwned + Send + 'static {
+
+    // the runs on disk must have been sorted with `compare`, the ones in memory are sorted here.
+    pub (in crate::pipeline) fn new(runs: Vec<Run<K, V>>, compare: Compare<K>) -> Merge<K, V> {
+        let sources = runs.into_iter().map(|run| match run {
+            Run::Disk(run) => Box::new(run.into_reader()) as Box<dyn Iterator<Item = (K, V)> + Send>,
+            Run::Memory(mut records) => {
+                records.sort_by(|(k1, _), (k2, _)| compare(k1, k2));
+                Box::new(records.into_iter())
+            }
+        }).collect();
+        let mut merge = Merge { sources, heads: BinaryHeap::new(), compare };
+        for source in 0..merge.sources.len() {
+            merge.advance(source);
+        }
+        return merge;
+    }
+
+    fn advance(&mut self, source: usize) {
+        if let Some((key, value)) = self.sources[source].next() {
+            self.heads.push(Head { key, value, source, compare: self.compare });
+        }
+    }
+}
+
+impl <K, V> Iterator for Merge<K, V>
+    where K: Ord + Serialize + DeserializeOwned + Send + 'static, V: Serialize + DeserializeOwned + Send + 'static {
+    type Item = (K, Vec<V>);
+
+    fn next(&mut self) -> Option<(K, Vec<V>)> {
+        let first = self.heads.pop()?;
+        self.advance(first.source);
+        let key = first.key;
+        let mut values = vec![first.value];
+        while self.heads.peek().is_some_and(|head| head.key == key) {
+            let head = self.heads.pop().unwrap();
+            self.advance(head.source);
+            values.push(head.value);
+        }
+        return Some((key, values));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(keys: &[(u32, &str)]) -> Vec<(u32, String)> {
+        return keys.iter().map(|(k, v)| (*k, String::from(*v))).collect();
+    }
+
+    #[test]
+    fn runs_are_written_sorted_and_removed_once_read() {
+        let mut written = records(&[(3, "c"), (1, "a"), (3, "d"), (2, "b")]);
+        let run = SortedRun::write(&mut written, u32::cmp);
+        assert!(written.is_empty());
+        let path = run.path.clone();
+        let reader = run.into_reader();
+        assert_eq!(reader.collect::<Vec<_>>(), records(&[(1, "a"), (2, "b"), (3, "c"), (3, "d")]));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn merge_groups_keys_in_order_and_values_in_run_order() {
+        let disk = SortedRun::write(&mut records(&[(2, "first"), (1, "first")]), u32::cmp);
+        let memory = records(&[(3, "second"), (2, "second")]);
+        let last = SortedRun::write(&mut records(&[(2, "third")]), u32::cmp);
+        let merged: Vec<(u32, Vec<String>)> = Merge::new(vec![Run::Disk(disk), Run::Memory(memory), Run::Disk(last)], u32::cmp).collect();
+        assert_eq!(merged, vec![
+            (1, vec![String::from("first")]),
+            (2, vec![String::from("first"), String::from("second"), String::from("third")]),
+            (3, vec![String::from("second")]),
+        ]);
+    }
+
+    #[test]
+    fn merge_follows_the_given_order() {
+        let run = SortedRun::write(&mut records(&[(1, "a"), (3, "c")]), |a: &u32, b: &u32| b.cmp(a));
+        let keys: Vec<u32> = Merge::new(vec![Run::Disk(run), Run::Memory(records(&[(2, "b")]))], |a: &u32, b: &u32| b.cmp(a))
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(keys, vec![3, 2, 1]);
+    }
+}
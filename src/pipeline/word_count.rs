@@ -1,5 +1,6 @@
 pub use crate::pipeline::map_reduce::Keyable;
-use std::cell::{Ref};
+use crate::pipeline::map_reduce::{Emitter, ReduceOutput};
+use crate::pipeline::partitioner::Partitioner;
 
 pub struct WordCount {
     word: String,
@@ -29,7 +30,88 @@ pub fn word_count_mapper(raw: Vec<u8>) -> Vec<Box<dyn Keyable<String, i32>>> {
     return word_counts;
 }
 
-pub fn word_count_reducer(key: String, vs: Ref<Vec<i32>>) -> (String, i32) {
-    let sum = vs.iter().sum();
-    return (key, sum);
-}
\ No newline at end of file
+// same as `word_count_mapper`, emitting every token as it is read.
+pub fn word_count_emitter(raw: Vec<u8>, emitter: &mut Emitter<String, i32>) {
+    let full_text = std::str::from_utf8(&raw).unwrap();
+    for token in full_text.to_lowercase().split(" ") {
+        if !token.is_empty() && token.chars().all(|c| c.is_ascii_digit()) {
+            emitter.increment("numbers", 1);
+        }
+        emitter.emit(String::from(token), 1);
+    }
+}
+
+pub fn word_count_reducer(key: String, counts: &mut dyn Iterator<Item = i32>, output: &mut ReduceOutput<String, i32>) {
+    output.emit(key, counts.sum());
+}
+
+// every word length, keyed by the first letter of the word.
+pub fn word_length_emitter(raw: Vec<u8>, emitter: &mut Emitter<String, i32>) {
+    let full_text = std::str::from_utf8(&raw).unwrap();
+    for token in full_text.to_lowercase().split_whitespace() {
+        let first_letter = token.chars().next().unwrap();
+        emitter.emit(first_letter.to_string(), token.chars().count() as i32);
+    }
+}
+
+// the mean word length of the letters starting at least `MIN_WORDS` words, the others are only counted.
+pub fn mean_length_reducer(first_letter: String, lengths: &mut dyn Iterator<Item = i32>, output: &mut ReduceOutput<String, f64>) {
+    const MIN_WORDS: usize = 10;
+    let (n, total) = lengths.fold((0, 0), |(n, total), length| (n + 1, total + length));
+    if n >= MIN_WORDS {
+        output.emit(first_letter, total as f64 / n as f64);
+    } else {
+        output.increment("rare letters", 1);
+    }
+}
+
+// every word, keyed by its first letter.
+pub fn first_letter_emitter(raw: Vec<u8>, emitter: &mut Emitter<String, String>) {
+    let full_text = std::str::from_utf8(&raw).unwrap();
+    for token in full_text.to_lowercase().split_whitespace() {
+        emitter.emit(token.chars().next().unwrap().to_string(), String::from(token));
+    }
+}
+
+// the distinct words starting with a letter, in the order the reducer reads them.
+pub fn word_list_reducer(first_letter: String, words: &mut dyn Iterator<Item = String>, output: &mut ReduceOutput<String, Vec<String>>) {
+    let mut list: Vec<String> = vec![];
+    for word in words {
+        if !list.contains(&word) {
+            list.push(word);
+        }
+    }
+    output.emit(first_letter, list);
+}
+
+// reads the output of word count: every count, keyed by the first letter of its word.
+pub fn letter_count_emitter(counts: Vec<(String, i32)>, emitter: &mut Emitter<String, (i32, String)>) {
+    for (word, count) in counts {
+        if let Some(first_letter) = word.chars().next() {
+            emitter.emit(first_letter.to_string(), (count, word));
+        }
+    }
+}
+
+// the 3 first words of a letter, e.g. its most frequent words when counts come in decreasing order.
+pub fn top_three_reducer(first_letter: String, counts: &mut dyn Iterator<Item = (i32, String)>, output: &mut ReduceOutput<String, Vec<(String, i32)>>) {
+    output.emit(first_letter, counts.take(3).map(|(count, word)| (word, count)).collect());
+}
+
+// shorter words first, then in alphabetical order.
+pub fn by_length(a: &String, b: &String) -> std::cmp::Ordering {
+    return a.len().cmp(&b.len()).then_with(|| a.cmp(b));
+}
+
+// range partitioner: words starting with the same letter go to the same partition, partitions are in
+// alphabetical order. Words not starting with a lowercase ascii letter go to the first partition.
+pub struct AlphabetPartitioner;
+
+impl Partitioner<String> for AlphabetPartitioner {
+    fn partition(&self, key: &String, partitions: usize) -> usize {
+        return match key.bytes().next() {
+            Some(letter @ b'a'..=b'z') => (letter - b'a') as usize * partitions / 26,
+            _ => 0,
+        };
+    }
+}